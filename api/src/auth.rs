@@ -21,31 +21,40 @@ impl Credentials {
             .take(32)
             .map(char::from)
             .collect();
-        let passcode_insecure: Passcode = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(32)
-            .map(char::from)
-            .collect();
-
-        let mut hasher = Sha256::new();
-        hasher.update(&passcode_insecure);
-        let hashed_passcode = format!("{:x}", hasher.finalize());
+        let passcode_insecure = Self::new_passcode();
 
         (
             Self {
                 id,
-                passcode: hashed_passcode,
+                passcode: Self::hash_passcode(&passcode_insecure),
             },
             passcode_insecure,
         )
     }
 
-    pub fn verify_passcode(passcode: &Passcode, hashed_passcode: &Passcode) -> bool {
+    /// a new random passcode, to be hashed before it is stored
+    pub fn new_passcode() -> Passcode {
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect()
+    }
+
+    pub fn hash_passcode(passcode: &str) -> Passcode {
         let mut hasher = Sha256::new();
         hasher.update(passcode);
-        let hashed_passcode_test = format!("{:x}", hasher.finalize());
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// whether a client chosen id is safe to use, as it is also used as a directory name in
+    /// storage mode
+    pub fn valid_id(id: &str) -> bool {
+        (16..=64).contains(&id.len()) && id.chars().all(|c| c.is_ascii_alphanumeric())
+    }
 
-        hashed_passcode == &hashed_passcode_test
+    pub fn verify_passcode(passcode: &Passcode, hashed_passcode: &Passcode) -> bool {
+        hashed_passcode == &Self::hash_passcode(passcode)
     }
 }
 
@@ -54,10 +63,14 @@ pub struct Token {
     pub id: Id,
     pub token: String,
     pub expiry: SystemTime,
+    /// Whether this login requests the additional scopes, for auth keys
+    #[serde(default)]
+    pub incremental: bool,
 }
 
 impl Token {
-    pub fn generate_token(id: &Id) -> Token {
+    /// a new random token for `id`, which expires after `valid_for`
+    pub fn generate_token(id: &Id, valid_for: Duration) -> Token {
         Token {
             id: id.clone(),
             token: rand::thread_rng()
@@ -65,9 +78,8 @@ impl Token {
                 .take(32)
                 .map(char::from)
                 .collect(),
-            expiry: SystemTime::now()
-                .checked_add(Duration::from_secs(60 * 5)) // 5 minutes to complete auth
-                .unwrap(),
+            expiry: SystemTime::now().checked_add(valid_for).unwrap(),
+            incremental: false,
         }
     }
 
@@ -1,10 +1,14 @@
 mod auth;
+#[cfg(test)]
+mod mock_google;
 mod photoscanner;
+mod storage;
 mod webserver;
 
 use auth::Token;
 use photoscanner::PhotoScanner;
 use serde::{Deserialize, Serialize};
+use storage::{ServerMode, StorageProgress};
 use tokio::sync::RwLock;
 use webserver::WebServer;
 
@@ -20,6 +24,13 @@ use std::{
 
 const STORE_PATH: &str = "data/store.json";
 
+/// The pages served during login, embedded so the binary can be deployed on its own
+const TEMPLATES: [(&str, &str); 3] = [
+    ("cookie", include_str!("../www/dynamic/cookie.handlebars")),
+    ("success", include_str!("../www/dynamic/success.handlebars")),
+    ("error", include_str!("../www/dynamic/error.handlebars")),
+];
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct GoogleAuth {
     /// A bearer token used to access the google api
@@ -30,6 +41,19 @@ pub struct GoogleAuth {
     pub refresh_token: String,
 }
 
+/// A completed google login waiting to be claimed by a client
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnclaimedLogin {
+    #[serde(flatten)]
+    pub auth: GoogleAuth,
+    /// The google account id (`sub`) that logged in, if it could be fetched
+    #[serde(default)]
+    pub google_sub: Option<String>,
+    /// The scopes google granted to this login
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
 impl GoogleAuth {
     pub fn is_expired(&self) -> bool {
         SystemTime::now() > self.token_expiry_sec_epoch
@@ -47,13 +71,47 @@ pub struct UserData {
     pub next_token: Option<String>,
     /// The previous token that was used, so the user can repeat a request if required
     pub prev_token: Option<String>,
+    /// How much this user has downloaded in the current quota window
+    #[serde(default)]
+    pub quota: QuotaUsage,
+    /// The google account id (`sub`) this client is linked to
+    #[serde(default)]
+    pub google_sub: Option<String>,
+    /// Set when google rejects the stored refresh token, until the client logs in again
+    #[serde(default)]
+    pub needs_reauth: bool,
+    /// The google scopes granted to the current login, including any granted incrementally
+    #[serde(default)]
+    pub granted_scopes: Vec<String>,
+    /// How far the api has got storing this user's media, when it runs in storage mode
+    #[serde(default)]
+    pub storage: StorageProgress,
+    /// Whether `next_token` and `prev_token` come from a scan including archived media, the tokens
+    /// of one kind of scan can't be used with the other
+    #[serde(default)]
+    pub include_archived: bool,
+}
+
+/// Usage counted against a user's quota, reset at the start of each window
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    /// When the current window started, if any requests have been made
+    pub window_start: Option<SystemTime>,
+    /// The number of download requests made in the current window
+    pub requests: u64,
+    /// The number of response bytes served in the current window
+    pub bytes: u64,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct AppState {
     users: HashMap<String, UserData>,
     auth_keys: HashMap<String, Token>,
-    unclaimed_auth_tokens: HashMap<String, GoogleAuth>,
+    unclaimed_auth_tokens: HashMap<String, UnclaimedLogin>,
+    /// Logins whose success page was reached with an auth key cookie, keyed by that auth key, so
+    /// the client can claim them itself if the page never posts the claim
+    #[serde(default)]
+    pending_claims: HashMap<String, String>,
     psk: String,
 }
 
@@ -119,6 +177,12 @@ async fn main() {
                         true
                     }
                 });
+                let AppState {
+                    auth_keys,
+                    pending_claims,
+                    ..
+                } = &mut *state;
+                pending_claims.retain(|auth_key, _| auth_keys.contains_key(auth_key));
             }
 
             tokio::time::sleep(Duration::from_secs(60)).await;
@@ -129,15 +193,86 @@ async fn main() {
     // This task handles webserver requests
     let webserver_state = state.clone();
     let webserver_handle = tokio::task::spawn(async move {
-        let scanner = PhotoScanner::new();
+        let mut scanner = PhotoScanner::new();
+        if let Ok(user_agent) = env::var("USER_AGENT") {
+            scanner = scanner.user_agent(user_agent);
+        }
+        if let Ok(base_url) = env::var("GOOGLE_PHOTOS_BASE_URL") {
+            scanner = scanner.base_url(base_url.trim_end_matches('/'));
+        }
 
         let mut bars = Handlebars::new();
-        bars.register_template_file("cookie", "./www/dynamic/cookie.handlebars")
-            .expect("valid cookie template");
-        bars.register_template_file("success", "./www/dynamic/success.handlebars")
-            .expect("valid success template");
+        for (name, template) in TEMPLATES {
+            bars.register_template_string(name, template)
+                .unwrap_or_else(|e| panic!("invalid {} template: {}", name, e));
+        }
+
+        let mut builder = WebServer::builder();
+        if let Ok(window) = env::var("QUOTA_WINDOW_SECS") {
+            let window = window.parse().expect("QUOTA_WINDOW_SECS is a number");
+            builder = builder.quota_window(Duration::from_secs(window));
+        }
+        if let Ok(timeout) = env::var("AUTH_TIMEOUT_SECS") {
+            let timeout = timeout.parse().expect("AUTH_TIMEOUT_SECS is a number");
+            builder = builder.auth_timeout(Duration::from_secs(timeout));
+        }
+        if let Ok(max_users) = env::var("MAX_USERS") {
+            builder = builder.max_users(max_users.parse::<usize>().expect("MAX_USERS is a number"));
+        }
+        if let Ok(max) = env::var("MAX_CONCURRENT_REGISTRATIONS") {
+            builder = builder.max_concurrent_registrations(
+                max.parse::<usize>()
+                    .expect("MAX_CONCURRENT_REGISTRATIONS is a number"),
+            );
+        }
+        if let Ok(max_requests) = env::var("QUOTA_MAX_REQUESTS") {
+            builder = builder.quota_max_requests(
+                max_requests
+                    .parse::<u64>()
+                    .expect("QUOTA_MAX_REQUESTS is a number"),
+            );
+        }
+        if let Ok(max_bytes) = env::var("QUOTA_MAX_BYTES") {
+            builder = builder.quota_max_bytes(
+                max_bytes
+                    .parse::<u64>()
+                    .expect("QUOTA_MAX_BYTES is a number"),
+            );
+        }
+        // comma separated, in the order they are requested
+        if let Ok(scopes) = env::var("GOOGLE_SCOPES") {
+            builder = builder.scopes(scopes.split(',').map(|s| s.trim().to_string()));
+        }
+        if let Ok(scopes) = env::var("GOOGLE_ADDITIONAL_SCOPES") {
+            builder = builder.additional_scopes(scopes.split(',').map(|s| s.trim().to_string()));
+        }
+        // either "broker" (the default), where clients store their own media, or "storage", where
+        // the api stores every user's media under STORAGE_ROOT
+        match env::var("SERVER_MODE").as_deref() {
+            Ok("storage") => {
+                let root = env::var("STORAGE_ROOT").expect("STORAGE_ROOT is set in storage mode");
+                builder = builder.mode(ServerMode::Storage(PathBuf::from(root)));
+            }
+            Ok("broker") | Err(_) => {}
+            Ok(mode) => panic!("unknown SERVER_MODE {}", mode),
+        }
+        // for proxies that serve the api under another path without rewriting it, google's
+        // redirect url is built from these so it always matches the routes
+        if let Ok(prefix) = env::var("API_PATH_PREFIX") {
+            builder = builder.path_prefix(prefix);
+        }
+        if let Ok(path) = env::var("CALLBACK_PATH") {
+            builder = builder.callback_path(path);
+        }
+        if let Ok(key) = env::var("REQUEST_SIGNING_KEY") {
+            builder = builder.request_signing_key(key);
+        }
+        if let (Ok(cert_path), Ok(key_path)) = (env::var("TLS_CERT_PATH"), env::var("TLS_KEY_PATH"))
+        {
+            builder = builder.tls(cert_path, key_path);
+        }
 
-        WebServer::builder()
+        builder
             .google_client_id(env::var("GOOGLE_CLIENT_ID").expect("GOOGLE_CLIENT_ID is set"))
             .google_client_secret(
                 env::var("GOOGLE_CLIENT_SECRET").expect("GOOGLE_CLIENT_SECRET is set"),
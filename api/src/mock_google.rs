@@ -0,0 +1,202 @@
+//! A stand in for google's apis, so the scanner and token refresh can be tested without a network
+//! connection or a google account. It serves a small library of canned items a page at a time,
+//! refreshes tokens, and fails in the same way google does for a few special tokens.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use serde_json::json;
+use warp::{http::StatusCode, reply::Response, Filter, Reply};
+
+/// The number of items in the mock library
+pub const LIBRARY_SIZE: usize = 5;
+
+/// The number of archived items, after the rest of the library. Only a search including archived
+/// media returns them
+pub const ARCHIVED_SIZE: usize = 1;
+
+/// An access token the mock rejects, as google does once a login has been revoked
+pub const REJECTED_TOKEN: &str = "rejected";
+
+/// A refresh token the mock rejects with `invalid_grant`
+pub const REVOKED_REFRESH_TOKEN: &str = "revoked";
+
+/// The access token handed out by every refresh
+pub const REFRESHED_TOKEN: &str = "refreshed";
+
+pub struct MockGoogle {
+    address: SocketAddr,
+    /// The number of token refreshes requested so far
+    pub refreshes: Arc<AtomicUsize>,
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl MockGoogle {
+    /// serve the mock on a free local port until it is dropped
+    pub fn start() -> Self {
+        let refreshes = Arc::new(AtomicUsize::new(0));
+
+        let media_items = warp::get()
+            .and(warp::path!("v1" / "mediaItems"))
+            .and(warp::header::<String>("authorization"))
+            .and(warp::query::<HashMap<String, String>>())
+            .map(media_items);
+
+        let search = warp::post()
+            .and(warp::path!("v1" / "mediaItems:search"))
+            .and(warp::header::<String>("authorization"))
+            .and(warp::body::json::<serde_json::Value>())
+            .map(search);
+
+        let counter = refreshes.clone();
+        let token = warp::post()
+            .and(warp::path!("token"))
+            .and(warp::body::form::<HashMap<String, String>>())
+            .map(move |form: HashMap<String, String>| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                token(form)
+            });
+
+        let userinfo = warp::get().and(warp::path!("userinfo")).map(|| {
+            warp::reply::json(&json!({
+                "sub": "mock-sub",
+                "email": "user@example.com",
+                "email_verified": true,
+            }))
+        });
+
+        let (address, server) = warp::serve(media_items.or(search).or(token).or(userinfo))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+
+        MockGoogle {
+            address,
+            refreshes,
+            server: tokio::task::spawn(server),
+        }
+    }
+
+    /// the url of `path` on the mock
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.address, path)
+    }
+}
+
+impl Drop for MockGoogle {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// google's standard error body
+fn error(status: StatusCode, message: &str, google_status: &str) -> Response {
+    let body = json!({
+        "error": {
+            "code": status.as_u16(),
+            "message": message,
+            "status": google_status,
+        }
+    });
+    warp::reply::with_status(warp::reply::json(&body), status).into_response()
+}
+
+/// a page of the library, leaving out archived items as google's list endpoint does
+fn media_items(authorization: String, query: HashMap<String, String>) -> Response {
+    let page_size = query
+        .get("pageSize")
+        .and_then(|size| size.parse::<usize>().ok())
+        .unwrap_or(25);
+    page(
+        &authorization,
+        page_size,
+        query.get("pageToken").map(String::as_str),
+        LIBRARY_SIZE,
+    )
+}
+
+/// a page of search results, including archived items when the filters ask for them
+fn search(authorization: String, body: serde_json::Value) -> Response {
+    let page_size = body["pageSize"].as_u64().unwrap_or(25) as usize;
+    let size = match body["filters"]["includeArchivedMedia"].as_bool() {
+        Some(true) => LIBRARY_SIZE + ARCHIVED_SIZE,
+        _ => LIBRARY_SIZE,
+    };
+    page(&authorization, page_size, body["pageToken"].as_str(), size)
+}
+
+/// a page of the first `size` items of the library, page tokens are the offset of the page's
+/// first item
+fn page(authorization: &str, page_size: usize, token: Option<&str>, size: usize) -> Response {
+    if authorization == format!("Bearer {}", REJECTED_TOKEN) {
+        return error(
+            StatusCode::UNAUTHORIZED,
+            "Request had invalid authentication credentials.",
+            "UNAUTHENTICATED",
+        );
+    }
+
+    let offset = match token.map(|token| token.parse::<usize>()) {
+        None => 0,
+        Some(Ok(offset)) if offset < size => offset,
+        Some(_) => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "Invalid page token.",
+                "INVALID_ARGUMENT",
+            )
+        }
+    };
+
+    let end = (offset + page_size).min(size);
+    let items: Vec<_> = (offset..end)
+        .map(|i| {
+            let id = match i.checked_sub(LIBRARY_SIZE) {
+                Some(archived) => format!("archived{}", archived),
+                None => format!("item{}", i),
+            };
+            json!({
+                "id": id,
+                "productUrl": format!("https://photos.google.com/lr/photo/{}", id),
+                "baseUrl": format!("https://lh3.googleusercontent.com/lr/{}", id),
+                "mimeType": "image/jpeg",
+                "filename": format!("IMG_{:04}.jpg", i),
+            })
+        })
+        .collect();
+
+    let mut body = json!({ "mediaItems": items });
+    if end < size {
+        body["nextPageToken"] = json!(end.to_string());
+    }
+    warp::reply::json(&body).into_response()
+}
+
+/// refresh an access token, rejecting the revoked refresh token as google does
+fn token(form: HashMap<String, String>) -> Response {
+    if form.get("grant_type").map(String::as_str) != Some("refresh_token") {
+        let body = json!({ "error": "unsupported_grant_type" });
+        return warp::reply::with_status(warp::reply::json(&body), StatusCode::BAD_REQUEST)
+            .into_response();
+    }
+
+    if form.get("refresh_token").map(String::as_str) == Some(REVOKED_REFRESH_TOKEN) {
+        let body = json!({
+            "error": "invalid_grant",
+            "error_description": "Token has been expired or revoked.",
+        });
+        return warp::reply::with_status(warp::reply::json(&body), StatusCode::BAD_REQUEST)
+            .into_response();
+    }
+
+    warp::reply::json(&json!({
+        "access_token": REFRESHED_TOKEN,
+        "token_type": "Bearer",
+        "expires_in": 3600,
+    }))
+    .into_response()
+}
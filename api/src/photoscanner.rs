@@ -1,17 +1,58 @@
 #![allow(dead_code)]
 
-use reqwest::Method;
-use shared_libs::json_templates::GetMediaItems;
+use reqwest::{Method, StatusCode};
+use shared_libs::json_templates::{GetMediaItems, GoogleErrorResponse, GoogleProfile, MediaItem};
 use std::time::Duration;
 
 use crate::GoogleAuth;
 
+/// The maximum number of characters of an error body to keep when google returns a failure
+const MAX_ERROR_BODY_LEN: usize = 1024;
+
+/// Where google's photos library api is found, unless replaced for tests
+pub const DEFAULT_BASE_URL: &str = "https://photoslibrary.googleapis.com/v1";
+
+/// Where the profile of a google account is fetched from, unless replaced for tests
+pub const DEFAULT_USERINFO_URL: &str = "https://www.googleapis.com/oauth2/v3/userinfo";
+
+/// The User-Agent used when none is configured, google asks that clients wanting compressed
+/// responses include "gzip" in their User-Agent as well as in Accept-Encoding
+pub const DEFAULT_USER_AGENT: &str =
+    concat!("syncabull-api/", env!("CARGO_PKG_VERSION"), " (gzip)");
+
 #[derive(Debug)]
 pub enum ScanningError {
     NoConnection,
     InvalidGoogleAuth,
     NetworkFailure(reqwest::Error),
     InternalFailure(String),
+    /// Google returned a non-success status, the fields are parsed from google's standard
+    /// `{ error: { code, message, status } }` body when present
+    GoogleApiFailure {
+        http_status: StatusCode,
+        code: Option<u16>,
+        message: Option<String>,
+        status: Option<String>,
+        body: String,
+    },
+}
+
+impl ScanningError {
+    /// Build an error from a failed google response status and its (possibly empty) body
+    pub fn from_google_response(http_status: StatusCode, body: &str) -> Self {
+        let (code, message, status) = match serde_json::from_str::<GoogleErrorResponse>(body) {
+            Ok(r) => (r.error.code, r.error.message, r.error.status),
+            Err(_) => (None, None, None),
+        };
+
+        ScanningError::GoogleApiFailure {
+            http_status,
+            code,
+            message,
+            status,
+            body: body.chars().take(MAX_ERROR_BODY_LEN).collect(),
+        }
+    }
 }
 
 impl std::fmt::Display for ScanningError {
@@ -21,6 +62,25 @@ impl std::fmt::Display for ScanningError {
             ScanningError::InvalidGoogleAuth => write!(f, "Invalid Google Auth"),
             ScanningError::NetworkFailure(ref err) => write!(f, "Network failure: {}", err),
             ScanningError::InternalFailure(ref msg) => write!(f, "Internal failure: {}", msg),
+            ScanningError::GoogleApiFailure {
+                ref http_status,
+                ref message,
+                ref status,
+                ref body,
+                ..
+            } => match (message, status) {
+                (Some(message), Some(status)) => {
+                    write!(
+                        f,
+                        "Google api failure ({}): {}: {}",
+                        http_status, status, message
+                    )
+                }
+                (Some(message), None) => {
+                    write!(f, "Google api failure ({}): {}", http_status, message)
+                }
+                _ => write!(f, "Google api failure ({}): {}", http_status, body),
+            },
         }
     }
 }
@@ -36,35 +96,91 @@ impl From<reqwest::Error> for ScanningError {
 #[derive(Debug)]
 pub struct PhotoScanner {
     timeout_ms: u64,
+    client: reqwest::Client,
+    base_url: String,
+    userinfo_url: String,
 }
 
 impl PhotoScanner {
     pub fn new() -> Self {
-        Self { timeout_ms: 20_000 }
+        Self {
+            timeout_ms: 20_000,
+            client: Self::client(DEFAULT_USER_AGENT),
+            base_url: DEFAULT_BASE_URL.to_string(),
+            userinfo_url: DEFAULT_USERINFO_URL.to_string(),
+        }
     }
 
+    /// Send photos library requests to this url rather than google's, e.g. a mock server in tests
+    pub fn base_url<T: Into<String>>(self, base_url: T) -> Self {
+        Self {
+            base_url: base_url.into(),
+            ..self
+        }
+    }
+
+    /// Fetch account profiles from this url rather than google's
+    pub fn userinfo_url<T: Into<String>>(self, userinfo_url: T) -> Self {
+        Self {
+            userinfo_url: userinfo_url.into(),
+            ..self
+        }
+    }
+
+    /// Replace the User-Agent sent with every request to google
+    pub fn user_agent<T: AsRef<str>>(self, user_agent: T) -> Self {
+        Self {
+            client: Self::client(user_agent.as_ref()),
+            ..self
+        }
+    }
+
+    fn client(user_agent: &str) -> reqwest::Client {
+        reqwest::Client::builder()
+            .user_agent(user_agent)
+            .build()
+            .expect("failed to build http client")
+    }
+
+    /// fetch a page of the library. Archived media is only included through google's search
+    /// endpoint, whose page tokens can't be used with the list endpoint, or the other way around
     pub async fn scan(
         &self,
         auth: &GoogleAuth,
         max_photos: u8,
         token: Option<String>,
+        include_archived: bool,
     ) -> Result<GetMediaItems, ScanningError> {
         if auth.is_expired() {
             return Err(ScanningError::InvalidGoogleAuth);
         }
 
-        let mut query = Vec::with_capacity(2);
-        query.push(("pageSize", max_photos.to_string()));
-        if let Some(page_token) = token {
-            query.push(("pageToken", page_token));
-        }
+        let request = match include_archived {
+            true => {
+                let mut body = serde_json::json!({
+                    "pageSize": max_photos,
+                    "filters": { "includeArchivedMedia": true },
+                });
+                if let Some(page_token) = token {
+                    body["pageToken"] = serde_json::Value::String(page_token);
+                }
+                self.client
+                    .request(Method::POST, format!("{}/mediaItems:search", self.base_url))
+                    .json(&body)
+            }
+            false => {
+                let mut query = Vec::with_capacity(2);
+                query.push(("pageSize", max_photos.to_string()));
+                if let Some(page_token) = token {
+                    query.push(("pageToken", page_token));
+                }
+                self.client
+                    .request(Method::GET, format!("{}/mediaItems", self.base_url))
+                    .query(&query)
+            }
+        };
 
-        let response = reqwest::Client::new()
-            .request(
-                Method::GET,
-                "https://photoslibrary.googleapis.com/v1/mediaItems",
-            )
-            .query(&query)
+        let response = request
             .header("Content-type", "application/json")
             .header("Authorization", format!("Bearer {}", auth.token))
             .timeout(Duration::from_millis(self.timeout_ms))
@@ -72,10 +188,9 @@ impl PhotoScanner {
             .await?;
 
         if !response.status().is_success() {
-            return Err(ScanningError::InternalFailure(format!(
-                "{}",
-                response.status()
-            )));
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(ScanningError::from_google_response(status, &body));
         }
 
         let body_str = response.text().await?;
@@ -92,4 +207,219 @@ impl PhotoScanner {
 
         Ok(body)
     }
+
+    /// fetch the contents of a single media item, forwarding an optional range header to google.
+    /// The response is returned unread so that it can be streamed.
+    pub async fn fetch_media(
+        &self,
+        auth: &GoogleAuth,
+        id: &str,
+        range: Option<&str>,
+    ) -> Result<reqwest::Response, ScanningError> {
+        if auth.is_expired() {
+            return Err(ScanningError::InvalidGoogleAuth);
+        }
+
+        // base urls expire, so always look up a fresh one
+        let response = self
+            .client
+            .get(format!("{}/mediaItems/{}", self.base_url, id))
+            .header("Authorization", format!("Bearer {}", auth.token))
+            .timeout(Duration::from_millis(self.timeout_ms))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(ScanningError::from_google_response(status, &body));
+        }
+
+        let item: MediaItem = response.json().await?;
+        self.fetch_item(&item, range).await
+    }
+
+    /// fetch the contents of an item through the base url it was listed with, forwarding an
+    /// optional range header to google. The response is returned unread so that it can be streamed.
+    pub async fn fetch_item(
+        &self,
+        item: &MediaItem,
+        range: Option<&str>,
+    ) -> Result<reqwest::Response, ScanningError> {
+        let param = match item.mimeType {
+            Some(ref mime_type) if mime_type.contains("video") => "dv",
+            _ => "d",
+        };
+
+        let mut request = self.client.get(format!("{}={}", item.baseUrl, param));
+        if let Some(range) = range {
+            request = request.header(reqwest::header::RANGE, range);
+        }
+        let response = request.send().await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(ScanningError::from_google_response(status, &body));
+        }
+
+        Ok(response)
+    }
+
+    /// fetch the profile of the google account that owns this token
+    pub async fn profile(&self, auth: &GoogleAuth) -> Result<GoogleProfile, ScanningError> {
+        if auth.is_expired() {
+            return Err(ScanningError::InvalidGoogleAuth);
+        }
+
+        let response = self
+            .client
+            .get(&self.userinfo_url)
+            .header("Authorization", format!("Bearer {}", auth.token))
+            .timeout(Duration::from_millis(self.timeout_ms))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(ScanningError::from_google_response(status, &body));
+        }
+
+        Ok(response.json().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use reqwest::StatusCode;
+
+    use super::{PhotoScanner, ScanningError};
+    use crate::{
+        mock_google::{MockGoogle, ARCHIVED_SIZE, LIBRARY_SIZE, REJECTED_TOKEN},
+        GoogleAuth,
+    };
+
+    fn auth(token: &str) -> GoogleAuth {
+        GoogleAuth {
+            token: token.to_string(),
+            token_expiry_sec_epoch: SystemTime::now() + Duration::from_secs(3600),
+            refresh_token: String::from("refresh"),
+        }
+    }
+
+    #[tokio::test]
+    async fn scan_follows_page_tokens() {
+        let google = MockGoogle::start();
+        let scanner = PhotoScanner::new().base_url(google.url("/v1"));
+
+        let mut ids = Vec::new();
+        let mut token = None;
+        let mut pages = 0;
+        loop {
+            let page = scanner.scan(&auth("valid"), 2, token, false).await.unwrap();
+            pages += 1;
+            ids.extend(page.mediaItems.into_iter().map(|item| item.id));
+            match page.nextPageToken {
+                Some(next) => token = Some(next),
+                None => break,
+            }
+        }
+
+        assert_eq!(pages, 3);
+        assert_eq!(
+            ids,
+            (0..LIBRARY_SIZE)
+                .map(|i| format!("item{}", i))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn archived_media_is_only_included_when_asked_for() {
+        let google = MockGoogle::start();
+        let scanner = PhotoScanner::new().base_url(google.url("/v1"));
+
+        for (include_archived, expected) in
+            [(false, LIBRARY_SIZE), (true, LIBRARY_SIZE + ARCHIVED_SIZE)]
+        {
+            let mut ids = Vec::new();
+            let mut token = None;
+            loop {
+                let page = scanner
+                    .scan(&auth("valid"), 2, token, include_archived)
+                    .await
+                    .unwrap();
+                ids.extend(page.mediaItems.into_iter().map(|item| item.id));
+                match page.nextPageToken {
+                    Some(next) => token = Some(next),
+                    None => break,
+                }
+            }
+            assert_eq!(ids.len(), expected);
+            assert_eq!(
+                ids.iter().any(|id| id.starts_with("archived")),
+                include_archived
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn scan_reports_google_errors() {
+        let google = MockGoogle::start();
+        let scanner = PhotoScanner::new().base_url(google.url("/v1"));
+
+        match scanner.scan(&auth(REJECTED_TOKEN), 2, None, false).await {
+            Err(ScanningError::GoogleApiFailure {
+                http_status,
+                code,
+                status,
+                ..
+            }) => {
+                assert_eq!(http_status, StatusCode::UNAUTHORIZED);
+                assert_eq!(code, Some(401));
+                assert_eq!(status.as_deref(), Some("UNAUTHENTICATED"));
+            }
+            other => panic!("expected a google api failure, got {:?}", other.err()),
+        }
+
+        match scanner
+            .scan(&auth("valid"), 2, Some(String::from("not-a-token")), false)
+            .await
+        {
+            Err(ScanningError::GoogleApiFailure {
+                http_status,
+                status,
+                ..
+            }) => {
+                assert_eq!(http_status, StatusCode::BAD_REQUEST);
+                assert_eq!(status.as_deref(), Some("INVALID_ARGUMENT"));
+            }
+            other => panic!("expected a google api failure, got {:?}", other.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn scan_refuses_expired_auth() {
+        let google = MockGoogle::start();
+        let scanner = PhotoScanner::new().base_url(google.url("/v1"));
+
+        let mut expired = auth("valid");
+        expired.token_expiry_sec_epoch = SystemTime::now() - Duration::from_secs(1);
+        assert!(matches!(
+            scanner.scan(&expired, 2, None, false).await,
+            Err(ScanningError::InvalidGoogleAuth)
+        ));
+    }
+
+    #[tokio::test]
+    async fn profile_is_fetched() {
+        let google = MockGoogle::start();
+        let scanner = PhotoScanner::new().userinfo_url(google.url("/userinfo"));
+
+        let profile = scanner.profile(&auth("valid")).await.unwrap();
+        assert_eq!(profile.sub, "mock-sub");
+    }
 }
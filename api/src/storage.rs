@@ -0,0 +1,219 @@
+//! Server side storage, for hosted deployments where the api downloads each user's media itself
+//! rather than leaving it to their client. Every linked user's library is scanned in turn and
+//! stored under `{root}/{user_id}/`, one file per item named after its id, the same layout the
+//! client uses. What happened to each item is recorded, so clients can reconcile against it
+//! through `/stored`.
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use shared_libs::json_templates::{MediaItem, StoredStatus};
+use tokio::io::AsyncWriteExt;
+
+use crate::webserver::WebServer;
+
+/// The number of items requested from google per page
+const PAGE_SIZE: u8 = 50;
+
+/// How long to wait before scanning again once every user's library has been fully scanned
+const IDLE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How long google may go without sending any of an item before it is given up on, so one stalled
+/// response doesn't hold up storage for every user
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Whether the api only brokers access to google for clients, or stores media itself
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ServerMode {
+    /// Clients download and store their own media, the default
+    #[default]
+    Broker,
+    /// The api downloads every linked user's media into this root directory
+    Storage(PathBuf),
+}
+
+/// How far server side storage has got through a user's library
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StorageProgress {
+    /// The token for the next page to store, None to start from the beginning
+    pub next_token: Option<String>,
+    /// Set once the whole library has been stored at least once
+    pub initial_scan_complete: bool,
+    /// The number of items stored so far
+    pub downloaded: u64,
+    /// What happened to each item stored or attempted so far, by id. Ordered so it can be paged
+    /// through by id
+    #[serde(default)]
+    pub items: BTreeMap<String, StoredRecord>,
+}
+
+/// What happened the last time an item was stored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredRecord {
+    pub status: StoredStatus,
+    /// The size of the stored file
+    pub bytes: Option<u64>,
+    /// In seconds since the unix epoch
+    pub updated_at: u64,
+    pub last_error: Option<String>,
+}
+
+/// download a single item into `dir`, skipping it if it is already stored. Returns whether the
+/// item was downloaded, and the size of the stored file.
+async fn store_item(
+    server: &WebServer,
+    dir: &std::path::Path,
+    item: &MediaItem,
+) -> Result<(bool, u64), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let path = dir.join(&item.id);
+    if let Ok(metadata) = tokio::fs::metadata(&path).await {
+        return Ok((false, metadata.len()));
+    }
+
+    let response = tokio::time::timeout(STALL_TIMEOUT, server.scanner.fetch_item(item, None))
+        .await
+        .map_err(|_| format!("google didn't respond within {:?}", STALL_TIMEOUT))??;
+
+    // written alongside and swapped in, so a half written file is never mistaken for a stored one
+    let part = dir.join(format!("{}.part", item.id));
+    match write_part(response, &part).await {
+        Ok(bytes) => {
+            tokio::fs::rename(&part, &path).await?;
+            Ok((true, bytes))
+        }
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&part).await;
+            Err(e)
+        }
+    }
+}
+
+/// write a response's body to `part`, returning its size
+async fn write_part(
+    response: reqwest::Response,
+    part: &std::path::Path,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let mut file = tokio::fs::File::create(part).await?;
+    let mut bytes = 0;
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = tokio::time::timeout(STALL_TIMEOUT, stream.try_next())
+        .await
+        .map_err(|_| format!("google sent nothing for {:?}", STALL_TIMEOUT))??
+    {
+        file.write_all(&chunk).await?;
+        bytes += chunk.len() as u64;
+    }
+    file.flush().await?;
+    Ok(bytes)
+}
+
+/// store the next page of a user's library, returning whether there are more pages to store
+async fn store_page(
+    server: &Arc<WebServer>,
+    root: &std::path::Path,
+    user_id: &str,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (google_token, token) = {
+        let reader = server.state.read().await;
+        match reader.users.get(user_id) {
+            Some(u) => (u.google_auth.clone(), u.storage.next_token.clone()),
+            None => return Ok(false),
+        }
+    };
+
+    let google_token = WebServer::google_token(server, user_id, google_token)
+        .await
+        .map_err(|e| format!("unable to get google token: {:?}", e))?;
+    let res = server
+        .scanner
+        .scan(&google_token, PAGE_SIZE, token, false)
+        .await?;
+
+    let dir = root.join(user_id);
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let mut downloaded = 0;
+    let mut records = Vec::with_capacity(res.mediaItems.len());
+    for item in res.mediaItems.iter() {
+        let updated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let record = match store_item(server, &dir, item).await {
+            Ok((stored, bytes)) => {
+                if stored {
+                    downloaded += 1;
+                }
+                StoredRecord {
+                    status: StoredStatus::Stored,
+                    bytes: Some(bytes),
+                    updated_at,
+                    last_error: None,
+                }
+            }
+            // base urls are fetched fresh with every page, so a failed item is tried again on the
+            // next pass over the library
+            Err(e) => {
+                eprintln!("failed to store {} for {}: {}", item.id, user_id, e);
+                StoredRecord {
+                    status: StoredStatus::Failed,
+                    bytes: None,
+                    updated_at,
+                    last_error: Some(e.to_string()),
+                }
+            }
+        };
+        records.push((item.id.clone(), record));
+    }
+
+    let more = res.nextPageToken.is_some();
+    let mut writer = server.state.write().await;
+    if let Some(user) = writer.users.get_mut(user_id) {
+        user.storage.next_token = res.nextPageToken;
+        user.storage.downloaded += downloaded;
+        user.storage.items.extend(records);
+        if !more {
+            user.storage.initial_scan_complete = true;
+        }
+    }
+
+    Ok(more)
+}
+
+/// Store every linked user's library under `root`, one page per user in turn so a large library
+/// doesn't hold up everyone else. Runs until the api shuts down.
+pub async fn run(server: Arc<WebServer>, root: PathBuf) {
+    println!("storing media under {:?}", root);
+
+    loop {
+        let users: Vec<String> = server
+            .state
+            .read()
+            .await
+            .users
+            .iter()
+            .filter(|(_, u)| u.google_auth.is_some() && !u.needs_reauth)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        let mut more = false;
+        for user_id in users.iter() {
+            match store_page(&server, &root, user_id).await {
+                Ok(m) => more |= m,
+                Err(e) => eprintln!("failed to store media for {}: {}", user_id, e),
+            }
+        }
+
+        // once every library has been stored, the next pass starts from the beginning of each
+        // to pick up new items
+        if !more {
+            tokio::time::sleep(IDLE_INTERVAL).await;
+        }
+    }
+}
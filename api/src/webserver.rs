@@ -1,28 +1,103 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap},
     convert::Infallible,
+    future::Future,
     net::Ipv4Addr,
+    ops::Bound,
+    path::PathBuf,
     sync::Arc,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use handlebars::Handlebars;
 use oauth2::{
-    basic::BasicClient, http::HeaderValue, reqwest::http_client, AuthUrl, AuthorizationCode,
-    ClientId, ClientSecret, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl,
-    RevocationUrl, Scope, TokenResponse, TokenUrl,
+    basic::{BasicClient, BasicErrorResponseType, BasicTokenResponse},
+    http::HeaderValue,
+    reqwest::http_client,
+    AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge,
+    PkceCodeVerifier, RedirectUrl, RequestTokenError, RevocationUrl, Scope, TokenResponse,
+    TokenUrl,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::{header, StatusCode};
+use shared_libs::{
+    json_templates::{
+        AuthUrlParameters, Capabilities, LibraryPage, LibraryParameters, LinkedClient, QueryData,
+        RequestParameters, StoredItem, StoredItemsPage, StoredItemsParameters, TokenStatus,
+        AUTH_EXPIRES_IN_HEADER, LAST_PAGE_HEADER,
+    },
+    signing,
+};
+use tokio::{
+    sync::{Mutex, RwLock, Semaphore},
+    time::error::Elapsed,
+};
+use warp::{
+    filters::BoxedFilter, http::Method, path::FullPath, reject::Reject, Filter, Rejection, Reply,
 };
-use reqwest::StatusCode;
-use shared_libs::json_templates::{QueryData, RequestParameters};
-use tokio::{sync::RwLock, time::error::Elapsed};
-use warp::{reject::Reject, Filter, Rejection, Reply};
 
 use crate::{
     auth::{Credentials, Token},
     photoscanner::PhotoScanner,
-    AppState, GoogleAuth, UserData,
+    storage::{self, ServerMode},
+    AppState, GoogleAuth, QuotaUsage, UnclaimedLogin, UserData,
 };
 
+/// The scopes requested when none are configured
+const DEFAULT_SCOPES: [&str; 3] = [
+    "https://www.googleapis.com/auth/photoslibrary.readonly",
+    "https://www.googleapis.com/auth/plus.me",
+    "https://www.googleapis.com/auth/userinfo.email",
+];
+
+/// The most items google will return in a page
+const MAX_PAGE_SIZE: u8 = 100;
+
+/// The optional features and endpoints supported by every api, reported by `/capabilities`
+const FEATURES: [&str; 14] = [
+    "media",
+    "claim_pending",
+    "rescan",
+    "token_status",
+    "clients",
+    "incremental_auth",
+    "seeded_registration",
+    "psk_check",
+    "library",
+    "page_token",
+    "rotate_passcode",
+    "include_archived",
+    "last_page",
+    "auth_expires_in",
+];
+
+/// The number of items listed by `/stored` when the client doesn't ask for a number
+const DEFAULT_STORED_PAGE_SIZE: u32 = 500;
+
+/// The most items listed by `/stored` in a page
+const MAX_STORED_PAGE_SIZE: u32 = 1000;
+
+/// The header a request's correlation id is read from, and returned in
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// How many times exchanging a login's code is attempted when google can't be reached
+const EXCHANGE_ATTEMPTS: u32 = 3;
+
+/// How long a user has to complete a login, and then claim it, when not configured
+const DEFAULT_AUTH_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How far a signed request's timestamp may be from our clock before it is rejected
+const MAX_SIGNATURE_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// The path every route is served under when not configured
+const DEFAULT_PATH_PREFIX: &str = "api/1";
+
+/// The path google redirects back to after a login, under the path prefix, when not configured
+const DEFAULT_CALLBACK_PATH: &str = "callback";
+
+/// The quota window used when quotas are enabled without setting one
+const DEFAULT_QUOTA_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
 #[derive(Debug)]
 pub struct CustomError(String, StatusCode);
 
@@ -53,6 +128,46 @@ pub struct WebServerBuilder {
     state: Option<Arc<RwLock<AppState>>>,
     handlebars: Option<Arc<Handlebars<'static>>>,
     scanner: Option<Arc<PhotoScanner>>,
+    tls: Option<(PathBuf, PathBuf)>,
+    quota_window: Option<Duration>,
+    quota_max_requests: Option<u64>,
+    quota_max_bytes: Option<u64>,
+    request_signing_key: Option<String>,
+    scopes: Option<Vec<String>>,
+    additional_scopes: Option<Vec<String>>,
+    mode: Option<ServerMode>,
+    auth_timeout: Option<Duration>,
+    max_users: Option<usize>,
+    max_concurrent_registrations: Option<usize>,
+    path_prefix: Option<String>,
+    callback_path: Option<String>,
+}
+
+/// Strip the slashes from either end of a url path, checking every segment of it is non-empty and
+/// only uses characters that don't need escaping, so it means the same in a redirect url as it
+/// does to the routes
+fn normalize_path(name: &str, path: &str) -> String {
+    let path = path.trim_matches('/');
+    for segment in path.split('/') {
+        assert!(
+            !segment.is_empty()
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c)),
+            "{} {:?} must be a url path made of letters, digits, '-', '.', '_' and '~'",
+            name,
+            path
+        );
+    }
+    path.to_string()
+}
+
+/// a filter matching each segment of a normalized path in turn
+fn path_segments(path: &str) -> BoxedFilter<()> {
+    path.split('/')
+        .fold(warp::any().boxed(), |filter, segment| {
+            filter.and(warp::path(segment.to_string())).boxed()
+        })
 }
 
 impl WebServerBuilder {
@@ -116,6 +231,116 @@ impl WebServerBuilder {
         }
     }
 
+    /// serve over https using the given certificate and private key, rather than plain http
+    pub fn tls<C: Into<PathBuf>, K: Into<PathBuf>>(self, cert_path: C, key_path: K) -> Self {
+        WebServerBuilder {
+            tls: Some((cert_path.into(), key_path.into())),
+            ..self
+        }
+    }
+
+    /// the rolling window that per-user quotas are counted over, defaults to one day
+    pub fn quota_window<T: Into<Duration>>(self, quota_window: T) -> Self {
+        WebServerBuilder {
+            quota_window: Some(quota_window.into()),
+            ..self
+        }
+    }
+
+    /// the maximum number of download requests a single user may make per quota window
+    pub fn quota_max_requests<T: Into<u64>>(self, quota_max_requests: T) -> Self {
+        WebServerBuilder {
+            quota_max_requests: Some(quota_max_requests.into()),
+            ..self
+        }
+    }
+
+    /// the maximum number of response bytes a single user may be served per quota window
+    pub fn quota_max_bytes<T: Into<u64>>(self, quota_max_bytes: T) -> Self {
+        WebServerBuilder {
+            quota_max_bytes: Some(quota_max_bytes.into()),
+            ..self
+        }
+    }
+
+    /// require `/register` requests to be signed with this key rather than carry the preshared key
+    pub fn request_signing_key<T: Into<String>>(self, request_signing_key: T) -> Self {
+        WebServerBuilder {
+            request_signing_key: Some(request_signing_key.into()),
+            ..self
+        }
+    }
+
+    /// the google scopes requested when a client first logs in, in the order given
+    pub fn scopes<I: IntoIterator<Item = T>, T: Into<String>>(self, scopes: I) -> Self {
+        WebServerBuilder {
+            scopes: Some(scopes.into_iter().map(Into::into).collect()),
+            ..self
+        }
+    }
+
+    /// scopes that are only requested when a client asks for them, through an incremental login
+    /// that keeps the scopes already granted
+    pub fn additional_scopes<I: IntoIterator<Item = T>, T: Into<String>>(self, scopes: I) -> Self {
+        WebServerBuilder {
+            additional_scopes: Some(scopes.into_iter().map(Into::into).collect()),
+            ..self
+        }
+    }
+
+    /// whether clients store their own media, or the api stores it for them
+    pub fn mode<T: Into<ServerMode>>(self, mode: T) -> Self {
+        WebServerBuilder {
+            mode: Some(mode.into()),
+            ..self
+        }
+    }
+
+    /// how long a login url can be used for, and how long the claim code shown once it succeeds
+    /// can be claimed for, defaults to five minutes
+    pub fn auth_timeout<T: Into<Duration>>(self, auth_timeout: T) -> Self {
+        WebServerBuilder {
+            auth_timeout: Some(auth_timeout.into()),
+            ..self
+        }
+    }
+
+    /// the most users that can be registered, further registrations are refused. Unlimited by
+    /// default
+    pub fn max_users<T: Into<usize>>(self, max_users: T) -> Self {
+        WebServerBuilder {
+            max_users: Some(max_users.into()),
+            ..self
+        }
+    }
+
+    /// the most registrations handled at once, any more are refused until one finishes.
+    /// Unlimited by default
+    pub fn max_concurrent_registrations<T: Into<usize>>(self, max: T) -> Self {
+        WebServerBuilder {
+            max_concurrent_registrations: Some(max.into()),
+            ..self
+        }
+    }
+
+    /// the path every route is served under, `api/1` by default. Set when a proxy in front of the
+    /// api serves it under a different path without rewriting it
+    pub fn path_prefix<T: Into<String>>(self, path_prefix: T) -> Self {
+        WebServerBuilder {
+            path_prefix: Some(path_prefix.into()),
+            ..self
+        }
+    }
+
+    /// the path under the prefix google redirects to after a login, `callback` by default. It
+    /// must match a redirect url registered with google
+    pub fn callback_path<T: Into<String>>(self, callback_path: T) -> Self {
+        WebServerBuilder {
+            callback_path: Some(callback_path.into()),
+            ..self
+        }
+    }
+
     pub fn build(self) -> WebServer {
         let google_client_id = ClientId::new(self.google_client_id.expect("google_client_id set"));
         let google_client_secret =
@@ -124,6 +349,27 @@ impl WebServerBuilder {
         let token_url =
             TokenUrl::new(self.token_url.expect("token_url set")).expect("token_url parse");
 
+        if self.tls.is_some() {
+            assert!(
+                self.domain
+                    .as_ref()
+                    .expect("domain set")
+                    .starts_with("https://"),
+                "domain must use https when tls is enabled"
+            );
+        }
+
+        let path_prefix = normalize_path(
+            "path prefix",
+            self.path_prefix.as_deref().unwrap_or(DEFAULT_PATH_PREFIX),
+        );
+        let callback_path = normalize_path(
+            "callback path",
+            self.callback_path
+                .as_deref()
+                .unwrap_or(DEFAULT_CALLBACK_PATH),
+        );
+
         // Google auth client setup
         let client = BasicClient::new(
             google_client_id,
@@ -133,8 +379,10 @@ impl WebServerBuilder {
         )
         .set_redirect_uri(
             RedirectUrl::new(format!(
-                "{}/api/1/callback",
-                self.domain.as_ref().expect("redirect url set")
+                "{}/{}/{}",
+                self.domain.as_ref().expect("redirect url set"),
+                path_prefix,
+                callback_path
             ))
             .expect("Invalid redirect URL"),
         )
@@ -144,45 +392,200 @@ impl WebServerBuilder {
         );
 
         let (pkce_code_challenge, pkce_code_verifier) = PkceCodeChallenge::new_random_sha256();
-
-        let (authorize_url, csrf_state) = client
-            .authorize_url(CsrfToken::new_random)
-            .add_scope(Scope::new(String::from(
-                "https://www.googleapis.com/auth/photoslibrary.readonly",
-            )))
-            .add_scope(Scope::new(String::from(
-                "https://www.googleapis.com/auth/plus.me",
-            )))
-            .add_scope(Scope::new(String::from(
-                "https://www.googleapis.com/auth/userinfo.email",
-            )))
-            .set_pkce_challenge(pkce_code_challenge)
-            .add_extra_param("prompt", "consent")
-            .add_extra_param("access_type", "offline")
-            .url();
+        let csrf_state = CsrfToken::new_random();
+
+        let scopes = self
+            .scopes
+            .unwrap_or_else(|| DEFAULT_SCOPES.iter().map(|s| s.to_string()).collect());
+        let additional_scopes = self.additional_scopes.unwrap_or_default();
+
+        let authorize_url = |scopes: &[String], incremental: bool| {
+            let mut request = client
+                .authorize_url(|| csrf_state.clone())
+                .add_scopes(scopes.iter().cloned().map(Scope::new))
+                .set_pkce_challenge(pkce_code_challenge.clone())
+                .add_extra_param("prompt", "consent")
+                .add_extra_param("access_type", "offline");
+            // google merges the new scopes with those already granted, so the new tokens cover
+            // everything granted so far
+            if incremental {
+                request = request.add_extra_param("include_granted_scopes", "true");
+            }
+            request.url().0.to_string()
+        };
+        let auth_url = authorize_url(&scopes, false);
+        let incremental_auth_url = authorize_url(
+            &scopes
+                .iter()
+                .chain(additional_scopes.iter())
+                .cloned()
+                .collect::<Vec<_>>(),
+            true,
+        );
 
         WebServer {
             client,
+            scopes,
+            additional_scopes,
             pkce_code_verifier,
             csrf_state,
-            auth_url: authorize_url.to_string(),
+            auth_url,
+            incremental_auth_url,
             domain: self.domain.expect("domain set"),
             state: self.state.expect("state set"),
             handlebars: self.handlebars.expect("handlebars set"),
             scanner: self.scanner.expect("scanner set"),
+            tls: self.tls,
+            quota_window: self.quota_window.unwrap_or(DEFAULT_QUOTA_WINDOW),
+            quota_max_requests: self.quota_max_requests,
+            quota_max_bytes: self.quota_max_bytes,
+            refresh_locks: Mutex::new(HashMap::new()),
+            request_signing_key: self.request_signing_key,
+            seen_signatures: Mutex::new(HashMap::new()),
+            mode: self.mode.unwrap_or_default(),
+            auth_timeout: self.auth_timeout.unwrap_or(DEFAULT_AUTH_TIMEOUT),
+            max_users: self.max_users,
+            registration_permits: self.max_concurrent_registrations.map(Semaphore::new),
+            path_prefix,
+            callback_path,
         }
     }
 }
 
 pub struct WebServer {
     pub client: BasicClient,
+    /// The google scopes requested when a client first logs in
+    pub scopes: Vec<String>,
+    /// The google scopes requested on top of those through an incremental login
+    pub additional_scopes: Vec<String>,
     pub domain: String,
     pub pkce_code_verifier: PkceCodeVerifier,
     pub csrf_state: CsrfToken,
     pub auth_url: String,
+    /// Requests the additional scopes along with those already granted
+    pub incremental_auth_url: String,
     pub state: Arc<RwLock<AppState>>,
     pub handlebars: Arc<Handlebars<'static>>,
     pub scanner: Arc<PhotoScanner>,
+    pub tls: Option<(PathBuf, PathBuf)>,
+    pub quota_window: Duration,
+    pub quota_max_requests: Option<u64>,
+    pub quota_max_bytes: Option<u64>,
+    /// held while a user's google token is being refreshed, so only one refresh runs at a time
+    pub refresh_locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    /// when set, requests guarded by the preshared key must be signed with this key instead
+    pub request_signing_key: Option<String>,
+    /// signatures accepted within the last `MAX_SIGNATURE_AGE`, with their timestamps, so a
+    /// signed request can't be replayed while it is still fresh
+    pub seen_signatures: Mutex<HashMap<String, u64>>,
+    pub mode: ServerMode,
+    /// How long a login url, and the claim code shown once it succeeds, can be used for
+    pub auth_timeout: Duration,
+    /// The most users that can be registered, None for no limit
+    pub max_users: Option<usize>,
+    /// Held by each registration in progress, None for no limit
+    pub registration_permits: Option<Semaphore>,
+    /// The path every route is served under, without leading or trailing slashes
+    pub path_prefix: String,
+    /// The path under the prefix google redirects to after a login
+    pub callback_path: String,
+}
+
+/// The request's correlation id, from its `x-request-id` header if it sent a usable one,
+/// otherwise a new random id
+fn request_id() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::header::headers_cloned().map(|headers: warp::http::HeaderMap| {
+        headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= 64
+                    && id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            })
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| {
+                rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(16)
+                    .map(char::from)
+                    .collect()
+            })
+    })
+}
+
+/// Run a route's filters, handing back their rejection instead of failing the route, so the
+/// handler can log requests its auth filters turn away
+fn settled<T: Send>(
+    filter: impl Filter<Extract = (T,), Error = Rejection> + Clone,
+) -> impl Filter<Extract = (Result<T, Rejection>,), Error = Infallible> + Clone {
+    filter
+        .map(|value: T| Ok(value))
+        .or_else(|rejection| async move { Ok::<_, Infallible>((Err(rejection),)) })
+}
+
+/// the message of a rejection from one of warp's own filters
+fn rejection_message(rejection: &Rejection) -> String {
+    use warp::{filters::body::BodyDeserializeError, reject};
+
+    if let Some(e) = rejection.find::<reject::MissingHeader>() {
+        e.to_string()
+    } else if let Some(e) = rejection.find::<reject::InvalidHeader>() {
+        e.to_string()
+    } else if let Some(e) = rejection.find::<reject::InvalidQuery>() {
+        e.to_string()
+    } else if let Some(e) = rejection.find::<reject::MissingCookie>() {
+        e.to_string()
+    } else if let Some(e) = rejection.find::<BodyDeserializeError>() {
+        e.to_string()
+    } else if let Some(e) = rejection.find::<reject::UnsupportedMediaType>() {
+        e.to_string()
+    } else if let Some(e) = rejection.find::<reject::LengthRequired>() {
+        e.to_string()
+    } else if let Some(e) = rejection.find::<reject::PayloadTooLarge>() {
+        e.to_string()
+    } else {
+        String::from("Invalid request")
+    }
+}
+
+/// Run a handler, logging its outcome under the request's correlation id and returning the id
+/// in the response, whether the handler succeeded or not
+async fn logged<R: Reply>(
+    handler: &'static str,
+    request_id: String,
+    reply: impl Future<Output = Result<R, Rejection>>,
+) -> Result<warp::reply::Response, Rejection> {
+    let start = Instant::now();
+    let mut response = match reply.await {
+        Ok(reply) => reply.into_response(),
+        Err(rejection) => match handle_custom_error(rejection).await {
+            Ok(reply) => reply.into_response(),
+            // warp's own extractors rejected a header, query or cookie the route needs
+            Err(rejection) => {
+                let message = rejection_message(&rejection);
+                eprintln!(
+                    "request_id={} rejecting a request with: {}",
+                    request_id, message
+                );
+                warp::reply::with_status(message, StatusCode::BAD_REQUEST).into_response()
+            }
+        },
+    };
+
+    println!(
+        "request_id={} handler={} status={} duration_ms={}",
+        request_id,
+        handler,
+        response.status().as_u16(),
+        start.elapsed().as_millis()
+    );
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    Ok(response)
 }
 
 fn with<T: Send + Sync>(
@@ -191,6 +594,26 @@ fn with<T: Send + Sync>(
     warp::any().map(move || data.clone())
 }
 
+/// the rejection sent once google has rejected a user's login, clients recognise its status code
+/// and restart the login flow
+fn reauth_required() -> Rejection {
+    warp::reject::custom(CustomError::new(
+        String::from("google login has expired, the client must log in again"),
+        StatusCode::PRECONDITION_REQUIRED,
+    ))
+}
+
+/// the reply sent once a user has used up their quota for the current window
+fn quota_exceeded(retry_after: Duration) -> warp::reply::Response {
+    let reply = warp::reply::with_status(
+        String::from("quota exceeded"),
+        StatusCode::TOO_MANY_REQUESTS,
+    );
+    // round up, so the client never retries before the window has ended
+    let retry_after = retry_after.as_secs() + 1;
+    warp::reply::with_header(reply, "retry-after", retry_after.to_string()).into_response()
+}
+
 pub fn with_auth(
     server: Arc<WebServer>,
 ) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
@@ -200,7 +623,11 @@ pub fn with_auth(
 }
 
 pub fn with_psk(server: Arc<WebServer>) -> impl Filter<Extract = ((),), Error = Rejection> + Clone {
-    warp::header::value("x-psk")
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("x-psk"))
+        .and(warp::header::optional::<u64>(signing::TIMESTAMP_HEADER))
+        .and(warp::header::optional::<String>(signing::SIGNATURE_HEADER))
         .and(with(server))
         .and_then(WebServer::psk)
 }
@@ -210,11 +637,39 @@ impl WebServer {
         WebServerBuilder::default()
     }
 
+    /// the public url of `path` under the path prefix, for links handed to users and clients
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}/{}", self.domain, self.path_prefix, path)
+    }
+
     async fn login(token: HeaderValue, webserver: Arc<WebServer>) -> Result<String, Rejection> {
         let token = token.to_str().map_err(|e| {
             CustomError::new(format!("Invalid token: {}", e), StatusCode::BAD_REQUEST)
         })?;
+        let (username, passcode) = WebServer::parse_basic_auth(token)?;
+
+        let hashed_passcode = match webserver.state.read().await.users.get(&username) {
+            Some(s) => s.hashed_passcode.clone(), //clone requires alloc, but it allows us to drop the rwlock
+            None => {
+                return Err(warp::reject::custom(CustomError::new(
+                    String::from("invalid login"),
+                    StatusCode::UNAUTHORIZED,
+                )))
+            }
+        };
+
+        if !Credentials::verify_passcode(&passcode, &hashed_passcode) {
+            return Err(warp::reject::custom(CustomError::new(
+                String::from("invalid login"),
+                StatusCode::UNAUTHORIZED,
+            )));
+        }
+
+        Ok(username)
+    }
 
+    /// split a basic authorization header into its username and passcode
+    fn parse_basic_auth(token: &str) -> Result<(String, String), Rejection> {
         if token.len() < 5 {
             return Err(warp::reject::custom(CustomError::new(
                 String::from("invalid token"),
@@ -264,30 +719,72 @@ impl WebServer {
             }
         };
 
-        let hashed_passcode = match webserver.state.read().await.users.get(&username) {
-            Some(s) => s.hashed_passcode.clone(), //clone requires alloc, but it allows us to drop the rwlock
-            None => {
+        Ok((username, passcode))
+    }
+
+    /// check a request carries the preshared key, or a valid signature when signing is enabled
+    async fn psk(
+        method: Method,
+        path: FullPath,
+        psk: Option<String>,
+        timestamp: Option<u64>,
+        signature: Option<String>,
+        webserver: Arc<WebServer>,
+    ) -> Result<(), Rejection> {
+        if let Some(key) = &webserver.request_signing_key {
+            let (timestamp, signature) = match (timestamp, signature) {
+                (Some(timestamp), Some(signature)) => (timestamp, signature),
+                _ => {
+                    return Err(warp::reject::custom(CustomError::new(
+                        String::from("request must be signed"),
+                        StatusCode::UNAUTHORIZED,
+                    )))
+                }
+            };
+
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            if now.abs_diff(timestamp) > MAX_SIGNATURE_AGE.as_secs() {
                 return Err(warp::reject::custom(CustomError::new(
-                    String::from("invalid login"),
+                    String::from("signature timestamp is too old or too far in the future"),
                     StatusCode::UNAUTHORIZED,
-                )))
+                )));
             }
-        };
 
-        if !Credentials::verify_passcode(&passcode, &hashed_passcode) {
-            return Err(warp::reject::custom(CustomError::new(
-                String::from("invalid login"),
-                StatusCode::UNAUTHORIZED,
-            )));
-        }
+            // signatures cover the path under the prefix, which is all the client knows of it, as
+            // its webserver address already includes the prefix
+            let prefix = format!("/{}", webserver.path_prefix);
+            let path = path.as_str().strip_prefix(&prefix).unwrap_or(path.as_str());
+            if !signing::verify(key, method.as_str(), path, timestamp, &signature) {
+                return Err(warp::reject::custom(CustomError::new(
+                    String::from("invalid signature"),
+                    StatusCode::UNAUTHORIZED,
+                )));
+            }
 
-        Ok(username)
-    }
+            let mut seen = webserver.seen_signatures.lock().await;
+            seen.retain(|_, seen_at| now.abs_diff(*seen_at) <= MAX_SIGNATURE_AGE.as_secs());
+            if seen.insert(signature, timestamp).is_some() {
+                return Err(warp::reject::custom(CustomError::new(
+                    String::from("signature has already been used"),
+                    StatusCode::UNAUTHORIZED,
+                )));
+            }
 
-    async fn psk(psk: HeaderValue, webserver: Arc<WebServer>) -> Result<(), Rejection> {
-        let psk = psk.to_str().map_err(|e| {
-            CustomError::new(format!("Invalid token: {}", e), StatusCode::BAD_REQUEST)
-        })?;
+            return Ok(());
+        }
+
+        let psk = match psk {
+            Some(psk) => psk,
+            None => {
+                return Err(warp::reject::custom(CustomError::new(
+                    String::from("missing psk"),
+                    StatusCode::UNAUTHORIZED,
+                )))
+            }
+        };
 
         if psk != webserver.state.read().await.psk {
             return Err(warp::reject::custom(CustomError::new(
@@ -299,16 +796,88 @@ impl WebServer {
         Ok(())
     }
 
-    pub async fn register(webserver: Arc<WebServer>, _: ()) -> Result<impl Reply, Infallible> {
+    /// register a new client with random credentials, or with the credentials in a basic
+    /// authorization header if one is sent. Registering again with the same credentials returns
+    /// the existing account, so automated deployments can register on every run.
+    pub async fn register(
+        webserver: Arc<WebServer>,
+        _: (),
+        desired: Option<String>,
+        request_id: String,
+    ) -> Result<impl Reply, Rejection> {
+        let desired = match desired {
+            Some(token) => Some(WebServer::parse_basic_auth(&token)?),
+            None => None,
+        };
+
+        let _permit = match webserver.registration_permits {
+            Some(ref permits) => match permits.try_acquire() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    return Err(warp::reject::custom(CustomError::new(
+                        String::from("too many registrations in progress, try again shortly"),
+                        StatusCode::TOO_MANY_REQUESTS,
+                    )))
+                }
+            },
+            None => None,
+        };
+
         let mut writer = webserver.state.write().await;
 
+        // registering again with existing credentials is still allowed once the cap is reached
+        let at_capacity = webserver
+            .max_users
+            .is_some_and(|max_users| writer.users.len() >= max_users);
+        let full = || {
+            warp::reject::custom(CustomError::new(
+                String::from("this api is not accepting new users"),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ))
+        };
+
         let mut auth: Credentials;
         let mut insecure: String;
-        loop {
-            (auth, insecure) = Credentials::new();
-            if !writer.users.contains_key(&auth.id) {
-                break;
+        match desired {
+            Some((id, passcode)) => {
+                if !Credentials::valid_id(&id) || passcode.len() < 16 {
+                    return Err(warp::reject::custom(CustomError::new(
+                        String::from("ids must be 16 to 64 alphanumeric characters, and passcodes at least 16 characters"),
+                        StatusCode::BAD_REQUEST,
+                    )));
+                }
+
+                if let Some(user) = writer.users.get(&id) {
+                    if !Credentials::verify_passcode(&passcode, &user.hashed_passcode) {
+                        return Err(warp::reject::custom(CustomError::new(
+                            String::from("id is already registered"),
+                            StatusCode::CONFLICT,
+                        )));
+                    }
+                    println!("request_id={} user_id={} registered again", request_id, id);
+                    return Ok(warp::reply::with_status(
+                        warp::reply::json(&Credentials { id, passcode }),
+                        warp::http::StatusCode::OK,
+                    ));
+                }
+
+                if at_capacity {
+                    return Err(full());
+                }
+
+                auth = Credentials {
+                    id,
+                    passcode: Credentials::hash_passcode(&passcode),
+                };
+                insecure = passcode;
             }
+            None if at_capacity => return Err(full()),
+            None => loop {
+                (auth, insecure) = Credentials::new();
+                if !writer.users.contains_key(&auth.id) {
+                    break;
+                }
+            },
         }
 
         writer.users.insert(
@@ -320,9 +889,16 @@ impl WebServer {
                 initial_scan_complete: false,
                 next_token: None,
                 prev_token: None,
+                quota: QuotaUsage::default(),
+                google_sub: None,
+                needs_reauth: false,
+                granted_scopes: Vec::new(),
+                storage: Default::default(),
+                include_archived: false,
             },
         );
 
+        println!("request_id={} user_id={} registered", request_id, auth.id);
         auth.passcode = insecure;
         Ok(warp::reply::with_status(
             warp::reply::json(&auth),
@@ -330,61 +906,129 @@ impl WebServer {
         ))
     }
 
-    pub async fn download(
-        server: Arc<WebServer>,
-        settings: RequestParameters,
-        user_id: String,
-    ) -> Result<impl Reply, Rejection> {
-        let token;
-        let google_token;
-        {
-            let reader = server.state.read().await;
-            match reader.users.get(&user_id) {
-                Some(u) => {
-                    token = match settings.reload {
-                        true => u.prev_token.clone(),
-                        false => u.next_token.clone(),
-                    };
-                    google_token = u.google_auth.clone();
-                }
-                None => {
-                    return Err(warp::reject::custom(CustomError::new(
-                        String::from("invalid user"),
-                        StatusCode::UNAUTHORIZED,
-                    )))
-                }
+    /// count a download request against a user's quota, starting a new window if the last one has
+    /// ended. Returns how long until the window ends if the quota has been used up.
+    fn take_quota(&self, usage: &mut QuotaUsage) -> Result<(), Duration> {
+        let now = SystemTime::now();
+        let window_ended = match usage.window_start {
+            Some(start) => now.duration_since(start).unwrap_or_default() >= self.quota_window,
+            None => true,
+        };
+        if window_ended {
+            *usage = QuotaUsage {
+                window_start: Some(now),
+                requests: 0,
+                bytes: 0,
             };
         }
 
+        let exceeded = self
+            .quota_max_requests
+            .is_some_and(|max| usage.requests >= max)
+            || self.quota_max_bytes.is_some_and(|max| usage.bytes >= max);
+        if exceeded {
+            let elapsed = now
+                .duration_since(usage.window_start.unwrap())
+                .unwrap_or_default();
+            return Err(self.quota_window.saturating_sub(elapsed));
+        }
+
+        usage.requests += 1;
+        Ok(())
+    }
+
+    /// the google token for a user, refreshing it first if it has expired
+    pub(crate) async fn google_token(
+        server: &Arc<WebServer>,
+        user_id: &str,
+        google_token: Option<GoogleAuth>,
+    ) -> Result<GoogleAuth, Rejection> {
         let mut google_token = match google_token {
             Some(t) => t,
             None => {
+                let reader = server.state.read().await;
+                if reader.users.get(user_id).is_some_and(|u| u.needs_reauth) {
+                    return Err(reauth_required());
+                }
                 return Err(warp::reject::custom(CustomError::new(
                     String::from("not google authorised"),
                     StatusCode::UNAUTHORIZED,
-                )))
+                )));
             }
         };
 
         if google_token.is_expired() {
+            // only one refresh runs per user at a time, any other requests wait for it to finish
+            // and then use the token it stored
+            let refresh_lock = server
+                .refresh_locks
+                .lock()
+                .await
+                .entry(user_id.to_string())
+                .or_default()
+                .clone();
+            let _guard = refresh_lock.lock().await;
+
+            let current = server
+                .state
+                .read()
+                .await
+                .users
+                .get(user_id)
+                .and_then(|u| u.google_auth.clone());
+            if let Some(current) = current {
+                if !current.is_expired() {
+                    return Ok(current);
+                }
+            }
+
             //refresh token
             let token_server = server.clone();
             let refresh_token = oauth2::RefreshToken::new(google_token.refresh_token.clone());
-            let new_token = tokio::task::spawn_blocking(move || {
+            let new_token = match tokio::task::spawn_blocking(move || {
                 token_server
                     .client
                     .exchange_refresh_token(&refresh_token)
                     .request(http_client)
             })
             .await
-            .unwrap()
-            .unwrap();
+            {
+                Ok(Ok(new_token)) => new_token,
+                // the refresh token was revoked or has expired, only a new login can fix this, so
+                // drop it and let the client know to log in again
+                Ok(Err(RequestTokenError::ServerResponse(response)))
+                    if *response.error() == BasicErrorResponseType::InvalidGrant =>
+                {
+                    let mut writer = server.state.write().await;
+                    if let Some(user) = writer.users.get_mut(user_id) {
+                        user.google_auth = None;
+                        user.needs_reauth = true;
+                    }
+                    return Err(reauth_required());
+                }
+                Ok(Err(e)) => {
+                    return Err(warp::reject::custom(CustomError::new(
+                        format!("unable to refresh google token: {}", e),
+                        StatusCode::BAD_GATEWAY,
+                    )))
+                }
+                Err(e) => {
+                    return Err(warp::reject::custom(CustomError::new(
+                        format!("unable to refresh google token: {}", e),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    )))
+                }
+            };
 
+            let expires_in = new_token
+                .expires_in()
+                .unwrap_or(Duration::from_secs(3600))
+                .as_secs();
             let new_token = GoogleAuth {
                 token: new_token.access_token().secret().to_string(),
                 token_expiry_sec_epoch: SystemTime::now()
                     .checked_add(Duration::from_secs(
-                        new_token.expires_in().unwrap().as_secs() - 10, //lose 10 seconds, just in case
+                        expires_in.saturating_sub(10), //lose 10 seconds, just in case
                     ))
                     .unwrap(),
                 refresh_token: google_token.refresh_token,
@@ -392,51 +1036,198 @@ impl WebServer {
 
             {
                 let mut writer = server.state.write().await;
-                writer.users.get_mut(&user_id).unwrap().google_auth = Some(new_token.clone());
+                writer.users.get_mut(user_id).unwrap().google_auth = Some(new_token.clone());
                 google_token = new_token;
             }
         }
 
-        let res = match server
-            .scanner
-            .scan(&google_token, settings.max_count, token)
-            .await
-        {
-            Ok(r) => r,
-            Err(e) => {
-                return Err(warp::reject::custom(CustomError::new(
-                    format!("{}", e),
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                )))
-            }
-        };
+        Ok(google_token)
+    }
+
+    pub async fn download(
+        server: Arc<WebServer>,
+        settings: RequestParameters,
+        user_id: String,
+        request_id: String,
+    ) -> Result<warp::reply::Response, Rejection> {
+        println!(
+            "request_id={} user_id={} scanning reload={} max_count={} page_token={:?}",
+            request_id, user_id, settings.reload, settings.max_count, settings.page_token
+        );
 
+        let token;
+        let google_token;
         {
             let mut writer = server.state.write().await;
-            let mut user = writer.users.get_mut(&user_id).unwrap();
-            user.prev_token = user.next_token.clone();
-            user.next_token = res.nextPageToken;
+            match writer.users.get_mut(&user_id) {
+                Some(u) => {
+                    if let Err(retry_after) = server.take_quota(&mut u.quota) {
+                        return Ok(quota_exceeded(retry_after));
+                    }
+
+                    // the saved tokens belong to the other kind of scan, so start from the
+                    // beginning of the library
+                    if u.include_archived != settings.include_archived {
+                        println!(
+                            "request_id={} user_id={} include_archived changed to {}, restarting the scan",
+                            request_id, user_id, settings.include_archived
+                        );
+                        u.include_archived = settings.include_archived;
+                        u.next_token = None;
+                        u.prev_token = None;
+                        u.initial_scan_complete = false;
+                    }
+
+                    token = match (&settings.page_token, settings.reload) {
+                        (Some(page_token), _) => Some(page_token.clone()),
+                        (None, true) => u.prev_token.clone(),
+                        (None, false) => u.next_token.clone(),
+                    };
+                    google_token = u.google_auth.clone();
+                }
+                None => {
+                    return Err(warp::reject::custom(CustomError::new(
+                        String::from("invalid user"),
+                        StatusCode::UNAUTHORIZED,
+                    )))
+                }
+            };
+        }
+
+        let google_token = WebServer::google_token(&server, &user_id, google_token).await?;
+
+        let res = match server
+            .scanner
+            .scan(
+                &google_token,
+                settings.max_count,
+                token.clone(),
+                settings.include_archived,
+            )
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                return Err(warp::reject::custom(CustomError::new(
+                    format!("{}", e),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )))
+            }
+        };
+
+        let last_page = res.nextPageToken.is_none();
+        {
+            let mut writer = server.state.write().await;
+            let mut user = writer.users.get_mut(&user_id).unwrap();
+            // a page fetched from the client's token is the one reloaded next
+            user.prev_token = match settings.page_token {
+                Some(_) => token,
+                None => user.next_token.clone(),
+            };
+            user.next_token = res.nextPageToken;
 
             if user.next_token.is_none() {
                 user.initial_scan_complete = true;
             }
         }
 
-        let reply = warp::reply::with_status(
-            warp::reply::json(&res.mediaItems),
-            warp::http::StatusCode::OK,
-        );
+        let body = serde_json::to_vec(&res.mediaItems).map_err(|e| {
+            CustomError::new(
+                format!("failed to serialize media items: {}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?;
+
+        if let Some(user) = server.state.write().await.users.get_mut(&user_id) {
+            user.quota.bytes += body.len() as u64;
+        }
+
+        let reply = warp::reply::with_header(body, "content-type", "application/json");
+        let reply = warp::reply::with_header(reply, LAST_PAGE_HEADER, last_page.to_string());
+
+        Ok(reply.into_response())
+    }
+
+    /// stream a single media item from google, forwarding any range header so that very large
+    /// files can be fetched in pieces and resumed
+    pub async fn media(
+        item_id: String,
+        server: Arc<WebServer>,
+        range: Option<String>,
+        user_id: String,
+    ) -> Result<warp::reply::Response, Rejection> {
+        let google_token;
+        {
+            let mut writer = server.state.write().await;
+            match writer.users.get_mut(&user_id) {
+                Some(u) => {
+                    if let Err(retry_after) = server.take_quota(&mut u.quota) {
+                        return Ok(quota_exceeded(retry_after));
+                    }
+                    google_token = u.google_auth.clone();
+                }
+                None => {
+                    return Err(warp::reject::custom(CustomError::new(
+                        String::from("invalid user"),
+                        StatusCode::UNAUTHORIZED,
+                    )))
+                }
+            };
+        }
+
+        let google_token = WebServer::google_token(&server, &user_id, google_token).await?;
 
-        Ok(reply)
+        let res = match server
+            .scanner
+            .fetch_media(&google_token, &item_id, range.as_deref())
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                return Err(warp::reject::custom(CustomError::new(
+                    format!("{}", e),
+                    StatusCode::BAD_GATEWAY,
+                )))
+            }
+        };
+
+        if let Some(len) = res.content_length() {
+            if let Some(user) = server.state.write().await.users.get_mut(&user_id) {
+                user.quota.bytes += len;
+            }
+        }
+
+        let mut reply = warp::http::Response::builder().status(res.status());
+        for header in [
+            header::CONTENT_TYPE,
+            header::CONTENT_LENGTH,
+            header::CONTENT_RANGE,
+            header::ACCEPT_RANGES,
+        ] {
+            if let Some(value) = res.headers().get(&header) {
+                reply = reply.header(header, value.clone());
+            }
+        }
+
+        reply
+            .body(warp::hyper::Body::wrap_stream(res.bytes_stream()))
+            .map_err(|e| {
+                warp::reject::custom(CustomError::new(
+                    format!("failed to build media response: {}", e),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ))
+            })
     }
 
     pub async fn get_auth_url(
         server: Arc<WebServer>,
+        parameters: AuthUrlParameters,
         user_id: String,
     ) -> Result<impl Reply, Rejection> {
-        let token = Token::generate_token(&user_id);
+        let mut token = Token::generate_token(&user_id, server.auth_timeout);
+        token.incremental = parameters.incremental;
 
-        let reply = format!("{}/api/1/auth/{}", server.domain, token.token);
+        let reply = server.url(&format!("auth/{}", token.token));
         server
             .state
             .write()
@@ -444,7 +1235,12 @@ impl WebServer {
             .auth_keys
             .insert(token.token.clone(), token);
 
-        Ok(reply)
+        // so the client can tell the user how long they actually have
+        Ok(warp::reply::with_header(
+            reply,
+            AUTH_EXPIRES_IN_HEADER,
+            server.auth_timeout.as_secs().to_string(),
+        ))
     }
 
     pub async fn begin_auth(
@@ -452,24 +1248,25 @@ impl WebServer {
         server: Arc<WebServer>,
     ) -> Result<impl Reply, Rejection> {
         //validate auth_cookie still exists
-        if !server
-            .state
-            .read()
-            .await
-            .auth_keys
-            .contains_key(&auth_cookie)
-        {
-            return Err(warp::reject::custom(CustomError::new(
-                String::from("invalid url"),
-                StatusCode::NOT_FOUND,
-            )));
-        }
+        let incremental = match server.state.read().await.auth_keys.get(&auth_cookie) {
+            Some(token) => token.incremental,
+            None => {
+                return Err(warp::reject::custom(CustomError::new(
+                    String::from("invalid url"),
+                    StatusCode::NOT_FOUND,
+                )))
+            }
+        };
 
-        let callback_url = String::from("/api/1/callback");
+        let callback_url = format!("/{}/{}", server.path_prefix, server.callback_path);
 
         let mut data = BTreeMap::new();
 
-        data.insert("redirect_url", &server.auth_url);
+        let redirect_url = match incremental {
+            true => &server.incremental_auth_url,
+            false => &server.auth_url,
+        };
+        data.insert("redirect_url", redirect_url);
         data.insert("valid_path", &callback_url);
         data.insert("token", &auth_cookie);
 
@@ -478,31 +1275,129 @@ impl WebServer {
         Ok(warp::reply::html(body))
     }
 
-    pub async fn verify(server: Arc<WebServer>, data: QueryData) -> Result<impl Reply, Rejection> {
-        let code = AuthorizationCode::new(data.code);
+    /// Exchange a login's code for google tokens, retrying if google can't be reached. The pkce
+    /// verifier is only ever copied, so a failed exchange leaves it usable for the next login.
+    async fn exchange_code(
+        server: &Arc<WebServer>,
+        code: String,
+    ) -> Result<BasicTokenResponse, (String, StatusCode)> {
+        let mut backoff = Duration::from_secs(1);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let token_server = server.clone();
+            let code = AuthorizationCode::new(code.clone());
+            let result = tokio::task::spawn_blocking(move || {
+                token_server
+                    .client
+                    .exchange_code(code)
+                    .set_pkce_verifier(PkceCodeVerifier::new(
+                        token_server.pkce_code_verifier.secret().to_string(),
+                    ))
+                    .request(http_client)
+            })
+            .await;
+
+            match result {
+                Ok(Ok(token_response)) => return Ok(token_response),
+                // codes can only be used once, so this is usually the callback being refreshed
+                Ok(Err(RequestTokenError::ServerResponse(response)))
+                    if *response.error() == BasicErrorResponseType::InvalidGrant =>
+                {
+                    return Err((
+                        String::from("this login has already been completed or has expired"),
+                        StatusCode::BAD_REQUEST,
+                    ))
+                }
+                Ok(Err(RequestTokenError::Request(e))) if attempt < EXCHANGE_ATTEMPTS => {
+                    eprintln!(
+                        "unable to reach google to complete a login, retrying in {:?}: {}",
+                        backoff, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Ok(Err(e)) => {
+                    return Err((
+                        format!("google did not accept the login: {}", e),
+                        StatusCode::BAD_GATEWAY,
+                    ))
+                }
+                Err(e) => {
+                    return Err((
+                        format!("unable to complete the login: {}", e),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    ))
+                }
+            }
+        }
+    }
+
+    /// the page shown in the browser when a login can't be completed
+    fn login_error(
+        server: &WebServer,
+        request_id: &str,
+        message: &str,
+        status: StatusCode,
+    ) -> warp::reply::Response {
+        eprintln!(
+            "request_id={} failed to complete a login: {}",
+            request_id, message
+        );
+
+        let mut data = BTreeMap::new();
+        data.insert("message", message);
+        let body = server
+            .handlebars
+            .render("error", &data)
+            .unwrap_or_else(|_| format!("Authorisation Failed: {}", message));
+
+        warp::reply::with_status(warp::reply::html(body), status).into_response()
+    }
+
+    pub async fn verify(
+        server: Arc<WebServer>,
+        data: QueryData,
+        auth_cookie: Option<String>,
+        request_id: String,
+    ) -> Result<warp::reply::Response, Rejection> {
         // Exchange the code with a token.
-        let token_server = server.clone();
-        let token_response = tokio::task::spawn_blocking(move || {
-            token_server
-                .client
-                .exchange_code(code)
-                .set_pkce_verifier(PkceCodeVerifier::new(
-                    token_server.pkce_code_verifier.secret().to_string(),
+        let token_response = match WebServer::exchange_code(&server, data.code).await {
+            Ok(token_response) => token_response,
+            Err((message, status)) => {
+                return Ok(WebServer::login_error(
+                    &server,
+                    &request_id,
+                    &message,
+                    status,
                 ))
-                .request(http_client)
-        })
-        .await
-        .unwrap()
-        .unwrap();
+            }
+        };
+
+        let refresh_token = match token_response.refresh_token() {
+            Some(refresh_token) => refresh_token.secret().to_string(),
+            None => {
+                return Ok(WebServer::login_error(
+                    &server,
+                    &request_id,
+                    "google did not grant offline access",
+                    StatusCode::BAD_GATEWAY,
+                ))
+            }
+        };
 
         let google_token = GoogleAuth {
             token: token_response.access_token().secret().to_string(),
             token_expiry_sec_epoch: SystemTime::now()
                 .checked_add(Duration::from_secs(
-                    token_response.expires_in().unwrap().as_secs() - 10, //lose 10 seconds, just in case
+                    token_response
+                        .expires_in()
+                        .unwrap_or(Duration::from_secs(3600))
+                        .as_secs()
+                        .saturating_sub(10), //lose 10 seconds, just in case
                 ))
                 .unwrap(),
-            refresh_token: token_response.refresh_token().unwrap().secret().to_string(),
+            refresh_token,
         };
 
         // we can't know which client this data is associated with, so we need the user to do that for us
@@ -513,38 +1408,62 @@ impl WebServer {
         // the key they were provided with earlier verifiably.
         // But for now this is adequate.
 
+        let scopes = token_response
+            .scopes()
+            .map(|scopes| scopes.iter().map(|scope| scope.to_string()).collect())
+            .unwrap_or_default();
+
+        // remember which google account logged in, so clients sharing an account can be listed
+        let google_sub = match server.scanner.profile(&google_token).await {
+            Ok(profile) => Some(profile.sub),
+            Err(e) => {
+                eprintln!("unable to fetch google profile: {}", e);
+                None
+            }
+        };
+
         //blank id provided, the user should fill this with their token when returning it
-        let token = Token::generate_token(&String::with_capacity(0));
+        let token = Token::generate_token(&String::with_capacity(0), server.auth_timeout);
 
         let mut data = BTreeMap::new();
         data.insert("token", serde_json::to_string(&token).unwrap());
-        data.insert(
-            "post_url",
-            format!("{}/api/1/token_completion", server.domain),
-        );
+        data.insert("claim_code", token.token.clone());
+        data.insert("post_url", server.url("token_completion"));
 
         let body = server.handlebars.render("success", &data).unwrap();
 
-        server
-            .state
-            .write()
-            .await
-            .unclaimed_auth_tokens
-            .insert(token.token, google_token);
+        let mut writer = server.state.write().await;
 
-        Ok(warp::reply::html(body))
-    }
+        // the cookie set by the auth page tells us which client started this login, so remember
+        // it in case the success page is closed before it claims the login
+        if let Some(auth_key) = auth_cookie {
+            if writer.auth_keys.contains_key(&auth_key) {
+                writer.pending_claims.insert(auth_key, token.token.clone());
+            }
+        }
 
-    pub async fn token_completion(
-        server: Arc<WebServer>,
-        data: Token,
-    ) -> Result<impl Reply, Rejection> {
-        // This endpoint is used to validate and finalise a login
-        let mut writer = server.state.write().await;
+        writer.unclaimed_auth_tokens.insert(
+            token.token,
+            UnclaimedLogin {
+                auth: google_token,
+                google_sub,
+                scopes,
+            },
+        );
+
+        println!("request_id={} completed a google login", request_id);
 
+        Ok(warp::reply::html(body).into_response())
+    }
+
+    /// link the unclaimed login `claim_token` to the client that was given `auth_key`
+    fn claim_login(
+        state: &mut AppState,
+        auth_key: &str,
+        claim_token: &str,
+    ) -> Result<(), Rejection> {
         //check that the provided cookie is valid
-        let unauth_user = data.id;
-        let user = match writer.auth_keys.remove(&unauth_user) {
+        let user = match state.auth_keys.remove(auth_key) {
             Some(s) => s,
             None => {
                 return Err(warp::reject::custom(CustomError::new(
@@ -553,10 +1472,10 @@ impl WebServer {
                 )))
             }
         };
+        state.pending_claims.remove(auth_key);
 
         //validate there is an unclaimed login
-        let unclaimed_token = data.token;
-        let unclaimed_login = match writer.unclaimed_auth_tokens.remove(&unclaimed_token) {
+        let unclaimed_login = match state.unclaimed_auth_tokens.remove(claim_token) {
             Some(s) => s,
             None => {
                 return Err(warp::reject::custom(CustomError::new(
@@ -567,8 +1486,13 @@ impl WebServer {
         };
 
         //login this user
-        match writer.users.get_mut(&user.id) {
-            Some(s) => s.google_auth = Some(unclaimed_login),
+        match state.users.get_mut(&user.id) {
+            Some(s) => {
+                s.google_auth = Some(unclaimed_login.auth);
+                s.google_sub = unclaimed_login.google_sub;
+                s.needs_reauth = false;
+                s.granted_scopes = unclaimed_login.scopes;
+            }
             None => {
                 return Err(warp::reject::custom(CustomError::new(
                     String::from("invalid login"),
@@ -577,22 +1501,62 @@ impl WebServer {
             }
         }
 
+        Ok(())
+    }
+
+    pub async fn token_completion(
+        server: Arc<WebServer>,
+        data: Token,
+    ) -> Result<impl Reply, Rejection> {
+        // This endpoint is used to validate and finalise a login
+        let mut writer = server.state.write().await;
+        WebServer::claim_login(&mut writer, &data.id, &data.token)?;
+
         Ok(warp::reply::with_status(
             warp::reply(),
             StatusCode::NO_CONTENT,
         ))
     }
 
-    pub async fn login_check(
-        webserver: Arc<WebServer>,
+    /// claim a login for this client server-side, for when the success page was closed before it
+    /// could claim the login itself
+    pub async fn claim_pending(
+        server: Arc<WebServer>,
         user_id: String,
     ) -> Result<impl Reply, Rejection> {
-        //XXX move timeout to config option?
+        let mut writer = server.state.write().await;
 
-        let timeout_secs = 200;
+        let pending = writer
+            .pending_claims
+            .iter()
+            .find(|(auth_key, _)| {
+                writer
+                    .auth_keys
+                    .get(*auth_key)
+                    .is_some_and(|token| token.id == user_id)
+            })
+            .map(|(auth_key, claim_token)| (auth_key.clone(), claim_token.clone()));
 
+        match pending {
+            Some((auth_key, claim_token)) => {
+                WebServer::claim_login(&mut writer, &auth_key, &claim_token)?;
+                Ok(warp::reply::with_status("", StatusCode::NO_CONTENT))
+            }
+            None => Err(warp::reject::custom(CustomError::new(
+                String::from("no pending login"),
+                StatusCode::NOT_FOUND,
+            ))),
+        }
+    }
+
+    pub async fn login_check(
+        webserver: Arc<WebServer>,
+        user_id: String,
+    ) -> Result<impl Reply, Rejection> {
+        // the login can be completed for as long as its auth url is valid
+        let timeout = webserver.auth_timeout;
         let result: Result<Result<(), Rejection>, Elapsed> =
-            tokio::time::timeout(Duration::from_secs(timeout_secs), async move {
+            tokio::time::timeout(timeout, async move {
                 loop {
                     let reader = webserver.state.read().await;
                     let user = match reader.users.get(&user_id) {
@@ -643,26 +1607,328 @@ impl WebServer {
         Ok(warp::reply::with_status("", StatusCode::NO_CONTENT))
     }
 
-    pub async fn run(self) {
-        let webserver = Arc::new(self);
+    pub async fn rescan(
+        webserver: Arc<WebServer>,
+        user_id: String,
+    ) -> Result<impl Reply, Rejection> {
+        let mut writer = webserver.state.write().await;
+        let user = match writer.users.get_mut(&user_id) {
+            Some(u) => u,
+            None => {
+                return Err(warp::reject::custom(CustomError::new(
+                    String::from("invalid user"),
+                    StatusCode::UNAUTHORIZED,
+                )))
+            }
+        };
+
+        user.next_token = None;
+        user.prev_token = None;
+        user.initial_scan_complete = false;
+        user.storage.next_token = None;
+
+        Ok(warp::reply::with_status("", StatusCode::NO_CONTENT))
+    }
+
+    /// replace the user's passcode with a new one, keeping their google login and scan. The new
+    /// passcode is only ever returned by this request
+    pub async fn rotate_passcode(
+        webserver: Arc<WebServer>,
+        user_id: String,
+    ) -> Result<impl Reply, Rejection> {
+        let mut writer = webserver.state.write().await;
+        let user = match writer.users.get_mut(&user_id) {
+            Some(u) => u,
+            None => {
+                return Err(warp::reject::custom(CustomError::new(
+                    String::from("invalid user"),
+                    StatusCode::UNAUTHORIZED,
+                )))
+            }
+        };
+
+        let passcode = Credentials::new_passcode();
+        user.hashed_passcode = Credentials::hash_passcode(&passcode);
+
+        println!("user_id={} rotated their passcode", user_id);
+        Ok(warp::reply::json(&Credentials {
+            id: user_id,
+            passcode,
+        }))
+    }
+
+    pub async fn token_status(
+        webserver: Arc<WebServer>,
+        user_id: String,
+    ) -> Result<impl Reply, Rejection> {
+        let reader = webserver.state.read().await;
+        let user = match reader.users.get(&user_id) {
+            Some(u) => u,
+            None => {
+                return Err(warp::reject::custom(CustomError::new(
+                    String::from("invalid user"),
+                    StatusCode::UNAUTHORIZED,
+                )))
+            }
+        };
+
+        let status = TokenStatus {
+            linked: user.google_auth.is_some(),
+            needs_reauth: user.needs_reauth,
+            scopes: user.granted_scopes.clone(),
+            expires_in_secs: user.google_auth.as_ref().map(|auth| {
+                auth.token_expiry_sec_epoch
+                    .duration_since(SystemTime::now())
+                    .map(|remaining| remaining.as_secs())
+                    .unwrap_or(0)
+            }),
+        };
+
+        Ok(warp::reply::json(&status))
+    }
 
+    /// list the items the api has stored, or failed to store, for this user in storage mode,
+    /// a page at a time in order of id
+    pub async fn stored_items(
+        webserver: Arc<WebServer>,
+        params: StoredItemsParameters,
+        user_id: String,
+    ) -> Result<impl Reply, Rejection> {
+        if !matches!(webserver.mode, ServerMode::Storage(_)) {
+            return Err(warp::reject::custom(CustomError::new(
+                String::from("this api does not store media"),
+                StatusCode::NOT_FOUND,
+            )));
+        }
+
+        let reader = webserver.state.read().await;
+        let user = match reader.users.get(&user_id) {
+            Some(u) => u,
+            None => {
+                return Err(warp::reject::custom(CustomError::new(
+                    String::from("invalid user"),
+                    StatusCode::UNAUTHORIZED,
+                )))
+            }
+        };
+
+        let limit = params
+            .limit
+            .unwrap_or(DEFAULT_STORED_PAGE_SIZE)
+            .clamp(1, MAX_STORED_PAGE_SIZE) as usize;
+        let start = match params.after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+
+        // one more than the page is taken to find out whether there is another page
+        let mut items: Vec<StoredItem> = user
+            .storage
+            .items
+            .range((start, Bound::Unbounded))
+            .take(limit + 1)
+            .map(|(id, record)| StoredItem {
+                id: id.clone(),
+                status: record.status,
+                bytes: record.bytes,
+                updated_at: record.updated_at,
+                last_error: record.last_error.clone(),
+            })
+            .collect();
+        let next = match items.len() > limit {
+            true => {
+                items.truncate(limit);
+                items.last().map(|item| item.id.clone())
+            }
+            false => None,
+        };
+
+        Ok(warp::reply::json(&StoredItemsPage { items, next }))
+    }
+
+    /// list a page of the ids in this user's google library, from an explicit page token so the
+    /// user's scan through the library is left where it is
+    pub async fn library(
+        server: Arc<WebServer>,
+        params: LibraryParameters,
+        user_id: String,
+    ) -> Result<warp::reply::Response, Rejection> {
+        let google_token;
+        {
+            let mut writer = server.state.write().await;
+            match writer.users.get_mut(&user_id) {
+                Some(u) => {
+                    if let Err(retry_after) = server.take_quota(&mut u.quota) {
+                        return Ok(quota_exceeded(retry_after));
+                    }
+                    google_token = u.google_auth.clone();
+                }
+                None => {
+                    return Err(warp::reject::custom(CustomError::new(
+                        String::from("invalid user"),
+                        StatusCode::UNAUTHORIZED,
+                    )))
+                }
+            };
+        }
+
+        let google_token = WebServer::google_token(&server, &user_id, google_token).await?;
+
+        let max_count = params
+            .max_count
+            .unwrap_or(MAX_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let res = server
+            .scanner
+            .scan(
+                &google_token,
+                max_count,
+                params.page_token,
+                params.include_archived,
+            )
+            .await
+            .map_err(|e| {
+                warp::reject::custom(CustomError::new(
+                    format!("{}", e),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ))
+            })?;
+
+        let page = LibraryPage {
+            ids: res.mediaItems.into_iter().map(|item| item.id).collect(),
+            next: res.nextPageToken,
+        };
+        Ok(warp::reply::json(&page).into_response())
+    }
+
+    /// report the version and optional features of this api, so clients can adapt to it
+    pub async fn capabilities(webserver: Arc<WebServer>) -> Result<impl Reply, Rejection> {
+        let mut features: Vec<String> = FEATURES.iter().map(|f| f.to_string()).collect();
+        if matches!(webserver.mode, ServerMode::Storage(_)) {
+            features.push(String::from("storage"));
+        }
+
+        Ok(warp::reply::json(&Capabilities {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            features,
+            scopes: webserver.scopes.clone(),
+            additional_scopes: webserver.additional_scopes.clone(),
+            max_page_size: MAX_PAGE_SIZE,
+        }))
+    }
+
+    /// the google account a client is linked to, clients must be linked before they can manage
+    /// other clients on the same account
+    fn google_sub(state: &AppState, user_id: &str) -> Result<String, Rejection> {
+        match state.users.get(user_id) {
+            Some(u) => u.google_sub.clone().ok_or_else(|| {
+                warp::reject::custom(CustomError::new(
+                    String::from("no google account is linked to this client"),
+                    StatusCode::FORBIDDEN,
+                ))
+            }),
+            None => Err(warp::reject::custom(CustomError::new(
+                String::from("invalid user"),
+                StatusCode::UNAUTHORIZED,
+            ))),
+        }
+    }
+
+    /// list every client linked to the same google account as this one
+    pub async fn list_clients(
+        webserver: Arc<WebServer>,
+        user_id: String,
+    ) -> Result<impl Reply, Rejection> {
+        let reader = webserver.state.read().await;
+        let google_sub = WebServer::google_sub(&reader, &user_id)?;
+
+        let clients: Vec<LinkedClient> = reader
+            .users
+            .iter()
+            .filter(|(_, u)| u.google_sub.as_ref() == Some(&google_sub))
+            .map(|(id, u)| LinkedClient {
+                id: id.clone(),
+                current: *id == user_id,
+                initial_scan_complete: u.initial_scan_complete,
+            })
+            .collect();
+
+        Ok(warp::reply::json(&clients))
+    }
+
+    /// revoke the credentials of another client linked to the same google account as this one
+    pub async fn revoke_client(
+        client_id: String,
+        webserver: Arc<WebServer>,
+        user_id: String,
+    ) -> Result<impl Reply, Rejection> {
+        let mut writer = webserver.state.write().await;
+        let google_sub = WebServer::google_sub(&writer, &user_id)?;
+
+        match writer.users.get(&client_id) {
+            Some(u) if u.google_sub.as_ref() == Some(&google_sub) => {
+                writer.users.remove(&client_id);
+                Ok(warp::reply::with_status("", StatusCode::NO_CONTENT))
+            }
+            _ => Err(warp::reject::custom(CustomError::new(
+                String::from("no such client on this google account"),
+                StatusCode::NOT_FOUND,
+            ))),
+        }
+    }
+
+    /// every route of the api, under the path prefix
+    fn routes(
+        webserver: Arc<WebServer>,
+    ) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone + Send + Sync + 'static
+    {
         // register this agent with the api
         let register = warp::get()
             .and(warp::path("register"))
             .and(warp::path::end())
-            .and(with(webserver.clone()))
-            .and(with_psk(webserver.clone()))
-            .and_then(WebServer::register)
+            .and(request_id())
+            .and(settled(
+                with(webserver.clone())
+                    .and(with_psk(webserver.clone()))
+                    .and(warp::header::optional::<String>("authorization"))
+                    .map(|webserver, psk, desired| (webserver, psk, desired)),
+            ))
+            .and_then(|request_id: String, authorized: Result<_, Rejection>| {
+                logged("register", request_id.clone(), async move {
+                    let (webserver, psk, desired) = authorized?;
+                    WebServer::register(webserver, psk, desired, request_id).await
+                })
+            })
             .recover(handle_custom_error);
 
         // check for new images to download
         let download = warp::get()
             .and(warp::path("download"))
             .and(warp::path::end())
+            .and(request_id())
+            .and(settled(
+                with(webserver.clone())
+                    .and(warp::query::<RequestParameters>())
+                    .and(with_auth(webserver.clone()))
+                    .map(|server, settings, user_id| (server, settings, user_id)),
+            ))
+            .and_then(|request_id: String, authorized: Result<_, Rejection>| {
+                logged("download", request_id.clone(), async move {
+                    let (server, settings, user_id) = authorized?;
+                    WebServer::download(server, settings, user_id, request_id).await
+                })
+            })
+            .recover(handle_custom_error);
+
+        // stream a single media item, with range support so very large files can be resumed
+        let media = warp::get()
+            .and(warp::path("media"))
+            .and(warp::path::param::<String>())
+            .and(warp::path::end())
             .and(with(webserver.clone()))
-            .and(warp::query::<RequestParameters>())
+            .and(warp::header::optional::<String>("range"))
             .and(with_auth(webserver.clone()))
-            .and_then(WebServer::download)
+            .and_then(WebServer::media)
             .recover(handle_custom_error);
 
         // this endpoint is used to generate a login url for the google auth process
@@ -671,6 +1937,7 @@ impl WebServer {
             .and(warp::path("auth_url"))
             .and(warp::path::end())
             .and(with(webserver.clone()))
+            .and(warp::query::<AuthUrlParameters>())
             .and(with_auth(webserver.clone()))
             .and_then(WebServer::get_auth_url)
             .recover(handle_custom_error);
@@ -692,11 +1959,21 @@ impl WebServer {
         // and if successful, serve the user an page which will validate the token saved earlier
         // this is important so we can associate a user with a token
         let auth_callback = warp::get()
-            .and(warp::path("callback"))
+            .and(path_segments(&webserver.callback_path))
             .and(warp::path::end())
-            .and(with(webserver.clone()))
-            .and(warp::query::<QueryData>())
-            .and_then(WebServer::verify)
+            .and(request_id())
+            .and(settled(
+                with(webserver.clone())
+                    .and(warp::query::<QueryData>())
+                    .and(warp::cookie::optional::<String>("auth_token"))
+                    .map(|server, data, auth_cookie| (server, data, auth_cookie)),
+            ))
+            .and_then(|request_id: String, parsed: Result<_, Rejection>| {
+                logged("verify", request_id.clone(), async move {
+                    let (server, data, auth_cookie) = parsed?;
+                    WebServer::verify(server, data, auth_cookie, request_id).await
+                })
+            })
             .recover(handle_custom_error);
 
         // when a user has autho
@@ -708,6 +1985,15 @@ impl WebServer {
             .and_then(WebServer::token_completion)
             .recover(handle_custom_error);
 
+        // claim a login whose success page never posted to token_completion
+        let claim_pending = warp::post()
+            .and(warp::path("claim_pending"))
+            .and(warp::path::end())
+            .and(with(webserver.clone()))
+            .and(with_auth(webserver.clone()))
+            .and_then(WebServer::claim_pending)
+            .recover(handle_custom_error);
+
         // long poll for user login succeeding
         let login_check = warp::get()
             .and(warp::path("is_logged_in"))
@@ -726,6 +2012,88 @@ impl WebServer {
             .and_then(WebServer::delete_data)
             .recover(handle_custom_error);
 
+        // reset the scan position of this user, so the next download starts from the beginning
+        let rescan = warp::post()
+            .and(warp::path("rescan"))
+            .and(warp::path::end())
+            .and(with(webserver.clone()))
+            .and(with_auth(webserver.clone()))
+            .and_then(WebServer::rescan)
+            .recover(handle_custom_error);
+
+        // replace this user's passcode, returning the new one
+        let rotate_passcode = warp::post()
+            .and(warp::path("rotate_passcode"))
+            .and(warp::path::end())
+            .and(with(webserver.clone()))
+            .and(with_auth(webserver.clone()))
+            .and_then(WebServer::rotate_passcode)
+            .recover(handle_custom_error);
+
+        // report how long the user's google token has left
+        let token_status = warp::get()
+            .and(warp::path("token_status"))
+            .and(warp::path::end())
+            .and(with(webserver.clone()))
+            .and(with_auth(webserver.clone()))
+            .and_then(WebServer::token_status)
+            .recover(handle_custom_error);
+
+        // list the clients linked to the same google account
+        let list_clients = warp::get()
+            .and(warp::path("clients"))
+            .and(warp::path::end())
+            .and(with(webserver.clone()))
+            .and(with_auth(webserver.clone()))
+            .and_then(WebServer::list_clients)
+            .recover(handle_custom_error);
+
+        // revoke a client linked to the same google account
+        let revoke_client = warp::delete()
+            .and(warp::path("clients"))
+            .and(warp::path::param::<String>())
+            .and(warp::path::end())
+            .and(with(webserver.clone()))
+            .and(with_auth(webserver.clone()))
+            .and_then(WebServer::revoke_client)
+            .recover(handle_custom_error);
+
+        // list the items stored for this user, in storage mode
+        let stored_items = warp::get()
+            .and(warp::path("stored"))
+            .and(warp::path::end())
+            .and(with(webserver.clone()))
+            .and(warp::query::<StoredItemsParameters>())
+            .and(with_auth(webserver.clone()))
+            .and_then(WebServer::stored_items)
+            .recover(handle_custom_error);
+
+        // list the ids in this user's library without moving their scan
+        let library = warp::get()
+            .and(warp::path("library"))
+            .and(warp::path::end())
+            .and(with(webserver.clone()))
+            .and(warp::query::<LibraryParameters>())
+            .and(with_auth(webserver.clone()))
+            .and_then(WebServer::library)
+            .recover(handle_custom_error);
+
+        // check the preshared key, or request signature, is accepted without registering
+        let psk_check = warp::get()
+            .and(warp::path("psk_check"))
+            .and(warp::path::end())
+            .and(with_psk(webserver.clone()))
+            .map(|_| warp::reply::with_status("", StatusCode::NO_CONTENT))
+            .recover(handle_custom_error);
+
+        // report what this api supports, before registering
+        let capabilities = warp::get()
+            .and(warp::path("capabilities"))
+            .and(warp::path::end())
+            .and(with(webserver.clone()))
+            .and_then(WebServer::capabilities)
+            .recover(handle_custom_error);
+
         // General catch-all endpoint if a failure occurs
         let catcher = warp::any().and(warp::path::full()).map(|path| {
             warp::reply::with_status(format!("Path {:?} not found", path), StatusCode::NOT_FOUND)
@@ -735,18 +2103,39 @@ impl WebServer {
         // every route needs webserver, so lets do that here
         // routes that require authorisation should be done here - avoids accidentally not authorising a route
         // routes shoudl be authorised and rejected here *not* inside of the functions
-        let api_1 = warp::any().and(warp::path("api")).and(warp::path("1")).and(
+        let api_1 = warp::any().and(path_segments(&webserver.path_prefix)).and(
             register
                 .or(download)
+                .or(media)
                 .or(get_auth_url)
                 .or(auth)
                 .or(auth_callback)
                 .or(auth_token_completion)
+                .or(claim_pending)
                 .or(login_check)
-                .or(delete_data),
+                .or(delete_data)
+                .or(rescan)
+                .or(rotate_passcode)
+                .or(token_status)
+                .or(list_clients)
+                .or(revoke_client)
+                .or(stored_items)
+                .or(library)
+                .or(psk_check)
+                .or(capabilities),
         );
 
-        let routes = warp::any().and(api_1.or(catcher));
+        warp::any().and(api_1.or(catcher))
+    }
+
+    pub async fn run(self) {
+        let webserver = Arc::new(self);
+
+        if let ServerMode::Storage(ref root) = webserver.mode {
+            tokio::task::spawn(storage::run(webserver.clone(), root.clone()));
+        }
+
+        let routes = WebServer::routes(webserver.clone());
 
         println!(
             "binding to : {}:{}",
@@ -754,26 +2143,359 @@ impl WebServer {
             std::env::var("PORT").expect("PORT not set")
         );
 
-        warp::serve(routes)
-            .run((
-                std::env::var("HOST")
-                    .expect("HOST to be set")
-                    .parse::<Ipv4Addr>()
-                    .expect("valid port"),
-                std::env::var("PORT")
-                    .expect("PORT to be set")
-                    .parse()
-                    .expect("valid port"),
-            ))
-            .await;
+        let address = (
+            std::env::var("HOST")
+                .expect("HOST to be set")
+                .parse::<Ipv4Addr>()
+                .expect("valid port"),
+            std::env::var("PORT")
+                .expect("PORT to be set")
+                .parse::<u16>()
+                .expect("valid port"),
+        );
+
+        match webserver.tls {
+            Some((ref cert_path, ref key_path)) => {
+                println!("serving with tls");
+                warp::serve(routes)
+                    .tls()
+                    .cert_path(cert_path)
+                    .key_path(key_path)
+                    .run(address)
+                    .await;
+            }
+            None => warp::serve(routes).run(address).await,
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::net::Ipv4Addr;
+    use std::{
+        net::Ipv4Addr,
+        sync::{atomic::Ordering, Arc},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use handlebars::Handlebars;
+    use shared_libs::{
+        json_templates::{LibraryPage, LibraryParameters},
+        signing,
+    };
+    use tokio::sync::RwLock;
+    use warp::{
+        http::{HeaderMap, StatusCode},
+        Filter, Reply,
+    };
+
+    use super::{path_segments, WebServer, REQUEST_ID_HEADER};
+    use crate::{
+        auth::Credentials,
+        mock_google::{MockGoogle, LIBRARY_SIZE, REFRESHED_TOKEN, REVOKED_REFRESH_TOKEN},
+        photoscanner::PhotoScanner,
+        AppState, GoogleAuth, UserData,
+    };
+
+    /// a webserver talking to the mock rather than google, with one user holding `google_auth`
+    fn server(google: &MockGoogle, google_auth: GoogleAuth) -> Arc<WebServer> {
+        let mut state = AppState::default();
+        state.users.insert(
+            String::from("user"),
+            UserData {
+                google_auth: Some(google_auth),
+                ..Default::default()
+            },
+        );
 
-    use warp::{http::HeaderMap, Filter};
+        Arc::new(
+            WebServer::builder()
+                .google_client_id("client-id")
+                .google_client_secret("client-secret")
+                .auth_url(google.url("/auth"))
+                .token_url(google.url("/token"))
+                .domain("http://localhost")
+                .state(Arc::new(RwLock::new(state)))
+                .handlebars(Handlebars::new())
+                .scanner(
+                    PhotoScanner::new()
+                        .base_url(google.url("/v1"))
+                        .userinfo_url(google.url("/userinfo")),
+                )
+                .build(),
+        )
+    }
+
+    fn google_auth(refresh_token: &str, expires_in: Duration, expired: bool) -> GoogleAuth {
+        let now = SystemTime::now();
+        GoogleAuth {
+            token: String::from("original"),
+            token_expiry_sec_epoch: match expired {
+                true => now - expires_in,
+                false => now + expires_in,
+            },
+            refresh_token: refresh_token.to_string(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn expired_google_token_is_refreshed() {
+        let google = MockGoogle::start();
+        let auth = google_auth("refresh", Duration::from_secs(60), true);
+        let server = server(&google, auth.clone());
+
+        let refreshed = WebServer::google_token(&server, "user", Some(auth))
+            .await
+            .unwrap();
+        assert_eq!(refreshed.token, REFRESHED_TOKEN);
+        assert_eq!(refreshed.refresh_token, "refresh");
+        assert!(!refreshed.is_expired());
+        assert_eq!(google.refreshes.load(Ordering::SeqCst), 1);
+
+        // the refreshed token is stored, so the next request uses it without refreshing again
+        let stored = server.state.read().await.users["user"]
+            .google_auth
+            .clone()
+            .unwrap();
+        assert_eq!(stored.token, REFRESHED_TOKEN);
+        WebServer::google_token(&server, "user", Some(stored))
+            .await
+            .unwrap();
+        assert_eq!(google.refreshes.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn valid_google_token_is_not_refreshed() {
+        let google = MockGoogle::start();
+        let auth = google_auth("refresh", Duration::from_secs(3600), false);
+        let server = server(&google, auth.clone());
+
+        let token = WebServer::google_token(&server, "user", Some(auth))
+            .await
+            .unwrap();
+        assert_eq!(token.token, "original");
+        assert_eq!(google.refreshes.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn revoked_refresh_token_requires_a_new_login() {
+        let google = MockGoogle::start();
+        let auth = google_auth(REVOKED_REFRESH_TOKEN, Duration::from_secs(60), true);
+        let server = server(&google, auth.clone());
+
+        assert!(WebServer::google_token(&server, "user", Some(auth))
+            .await
+            .is_err());
+
+        let state = server.state.read().await;
+        let user = &state.users["user"];
+        assert!(user.needs_reauth);
+        assert!(user.google_auth.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn library_is_listed_without_moving_the_scan() {
+        let google = MockGoogle::start();
+        let auth = google_auth("refresh", Duration::from_secs(3600), false);
+        let server = server(&google, auth);
+
+        let mut ids = Vec::new();
+        let mut page_token = None;
+        loop {
+            let params = LibraryParameters {
+                page_token,
+                max_count: Some(2),
+                include_archived: false,
+            };
+            let res = WebServer::library(server.clone(), params, String::from("user"))
+                .await
+                .unwrap();
+            let body = warp::hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let page: LibraryPage = serde_json::from_slice(&body).unwrap();
+            ids.extend(page.ids);
+            match page.next {
+                Some(next) => page_token = Some(next),
+                None => break,
+            }
+        }
+
+        assert_eq!(ids.len(), LIBRARY_SIZE);
+        assert_eq!(ids[0], "item0");
+        let state = server.state.read().await;
+        assert!(state.users["user"].next_token.is_none());
+        assert!(!state.users["user"].initial_scan_complete);
+    }
+
+    #[tokio::test]
+    async fn rotated_passcode_replaces_the_old_one() {
+        let google = MockGoogle::start();
+        let auth = google_auth("refresh", Duration::from_secs(3600), false);
+        let server = server(&google, auth);
+
+        let res = WebServer::rotate_passcode(server.clone(), String::from("user"))
+            .await
+            .unwrap()
+            .into_response();
+        let body = warp::hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let credentials: Credentials = serde_json::from_slice(&body).unwrap();
+
+        let state = server.state.read().await;
+        let user = &state.users["user"];
+        assert_eq!(credentials.id, "user");
+        assert!(Credentials::verify_passcode(
+            &credentials.passcode,
+            &user.hashed_passcode
+        ));
+        assert!(user.google_auth.is_some());
+    }
+
+    #[tokio::test]
+    async fn path_prefix_is_used_for_redirects_and_routes() {
+        let google = MockGoogle::start();
+        let server = WebServer::builder()
+            .google_client_id("client-id")
+            .google_client_secret("client-secret")
+            .auth_url(google.url("/auth"))
+            .token_url(google.url("/token"))
+            .domain("http://localhost")
+            .path_prefix("/photos/sync/")
+            .callback_path("oauth")
+            .state(Arc::new(RwLock::new(AppState::default())))
+            .handlebars(Handlebars::new())
+            .scanner(PhotoScanner::new())
+            .build();
+
+        assert_eq!(
+            server.client.redirect_url().unwrap().as_str(),
+            "http://localhost/photos/sync/oauth"
+        );
+        assert_eq!(
+            server.url("token_completion"),
+            "http://localhost/photos/sync/token_completion"
+        );
+
+        let route = path_segments(&server.path_prefix)
+            .and(path_segments(&server.callback_path))
+            .and(warp::path::end());
+        assert!(
+            warp::test::request()
+                .path("/photos/sync/oauth")
+                .matches(&route)
+                .await
+        );
+        assert!(
+            !warp::test::request()
+                .path("/api/1/callback")
+                .matches(&route)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn signed_requests_are_accepted_under_the_path_prefix() {
+        let google = MockGoogle::start();
+        let server = Arc::new(
+            WebServer::builder()
+                .google_client_id("client-id")
+                .google_client_secret("client-secret")
+                .auth_url(google.url("/auth"))
+                .token_url(google.url("/token"))
+                .domain("http://localhost")
+                .request_signing_key("signing-key")
+                .state(Arc::new(RwLock::new(AppState::default())))
+                .handlebars(Handlebars::new())
+                .scanner(PhotoScanner::new())
+                .build(),
+        );
+        let (address, serving) =
+            warp::serve(WebServer::routes(server)).bind_ephemeral(([127, 0, 0, 1], 0));
+        let serving = tokio::task::spawn(serving);
+
+        // signed as the client signs them, with the path under the webserver address
+        let client = reqwest::Client::new();
+        let check = |key: &'static str, path: &'static str| {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            client
+                .get(format!("http://{}/api/1/psk_check", address))
+                .header(signing::TIMESTAMP_HEADER, timestamp)
+                .header(
+                    signing::SIGNATURE_HEADER,
+                    signing::sign(key, "GET", path, timestamp),
+                )
+                .send()
+        };
+
+        assert_eq!(
+            check("signing-key", "/psk_check").await.unwrap().status(),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            check("wrong-key", "/psk_check").await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        serving.abort();
+    }
+
+    #[tokio::test]
+    async fn rejected_logins_carry_the_request_id() {
+        let google = MockGoogle::start();
+        let server = Arc::new(
+            WebServer::builder()
+                .google_client_id("client-id")
+                .google_client_secret("client-secret")
+                .auth_url(google.url("/auth"))
+                .token_url(google.url("/token"))
+                .domain("http://localhost")
+                .state(Arc::new(RwLock::new(AppState::default())))
+                .handlebars(Handlebars::new())
+                .scanner(PhotoScanner::new())
+                .build(),
+        );
+
+        let response = warp::test::request()
+            .path("/api/1/download?reload=false&max_count=10")
+            .header(
+                "authorization",
+                format!("Basic {}", base64::encode("nobody:wrong")),
+            )
+            .header(REQUEST_ID_HEADER, "unknown-user")
+            .reply(&WebServer::routes(server))
+            .await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "unknown-user");
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_are_told_what_is_wrong() {
+        let google = MockGoogle::start();
+        let server = Arc::new(
+            WebServer::builder()
+                .google_client_id("client-id")
+                .google_client_secret("client-secret")
+                .auth_url(google.url("/auth"))
+                .token_url(google.url("/token"))
+                .domain("http://localhost")
+                .state(Arc::new(RwLock::new(AppState::default())))
+                .handlebars(Handlebars::new())
+                .scanner(PhotoScanner::new())
+                .build(),
+        );
+
+        let response = warp::test::request()
+            .path("/api/1/download")
+            .header(
+                "authorization",
+                format!("Basic {}", base64::encode("nobody:wrong")),
+            )
+            .reply(&WebServer::routes(server))
+            .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.body(), "Invalid query string");
+    }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn full_test() {
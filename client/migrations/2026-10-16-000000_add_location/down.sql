@@ -0,0 +1,2 @@
+ALTER TABLE media DROP COLUMN latitude;
+ALTER TABLE media DROP COLUMN longitude;
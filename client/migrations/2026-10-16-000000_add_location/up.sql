@@ -0,0 +1,3 @@
+--- location metadata, if google provided it
+ALTER TABLE media ADD COLUMN latitude DOUBLE;
+ALTER TABLE media ADD COLUMN longitude DOUBLE;
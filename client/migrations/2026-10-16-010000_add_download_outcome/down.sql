@@ -0,0 +1,3 @@
+ALTER TABLE media DROP COLUMN file_path;
+ALTER TABLE media DROP COLUMN file_size;
+ALTER TABLE media DROP COLUMN download_duration_ms;
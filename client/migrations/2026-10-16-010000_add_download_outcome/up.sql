@@ -0,0 +1,4 @@
+--- where the downloaded file was stored, and how the download went
+ALTER TABLE media ADD COLUMN file_path TEXT;
+ALTER TABLE media ADD COLUMN file_size BIGINT;
+ALTER TABLE media ADD COLUMN download_duration_ms BIGINT;
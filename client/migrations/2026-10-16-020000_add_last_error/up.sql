@@ -0,0 +1 @@
+ALTER TABLE media ADD COLUMN last_error TEXT;
@@ -0,0 +1 @@
+DROP INDEX media_creation_time;
@@ -0,0 +1,2 @@
+--- lets the stats command filter by when items were taken
+CREATE INDEX media_creation_time ON media (creation_time);
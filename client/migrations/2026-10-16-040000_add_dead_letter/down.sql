@@ -0,0 +1,5 @@
+INSERT OR IGNORE INTO media (id, product_url, base_url, filename, download_attempts, download_success, download_timestamp, creation_time, last_error)
+SELECT id, product_url, '', filename, download_attempts, 0, failed_at, creation_time, reason
+FROM dead_letter;
+
+DROP TABLE dead_letter;
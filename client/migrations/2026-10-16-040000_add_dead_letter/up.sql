@@ -0,0 +1,17 @@
+--- items that failed to download after every attempt, kept apart from the media table so they
+--- are never mistaken for items still waiting to be downloaded
+CREATE TABLE dead_letter (
+    id TEXT PRIMARY KEY NOT NULL,
+    filename TEXT NOT NULL,
+    product_url TEXT NOT NULL,
+    creation_time TEXT,
+    download_attempts INTEGER NOT NULL,
+    reason TEXT,
+    failed_at TEXT NOT NULL
+);
+
+INSERT INTO dead_letter (id, filename, product_url, creation_time, download_attempts, reason, failed_at)
+SELECT id, filename, product_url, creation_time, download_attempts, last_error, download_timestamp
+FROM media WHERE download_success = 0;
+
+DELETE FROM media WHERE download_success = 0;
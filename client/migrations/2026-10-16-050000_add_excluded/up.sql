@@ -0,0 +1,2 @@
+--- items skipped because they matched exclude_patterns or exclude_mime_types
+ALTER TABLE media ADD COLUMN excluded BOOLEAN NOT NULL DEFAULT 0;
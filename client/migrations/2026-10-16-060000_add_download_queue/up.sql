@@ -0,0 +1,10 @@
+--- the download queue, saved so a restarted client can carry on where it left off
+CREATE TABLE download_queue (
+    id TEXT PRIMARY KEY NOT NULL,
+    position INTEGER NOT NULL,
+    item TEXT NOT NULL,
+    download_attempts INTEGER NOT NULL,
+    --- when the page the item came from was loaded, in seconds since the unix epoch, as its base
+    --- url expires some time after that
+    loaded_at BIGINT NOT NULL
+);
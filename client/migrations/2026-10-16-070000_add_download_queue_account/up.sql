@@ -0,0 +1,2 @@
+--- the additional account each queued item belongs to, empty for the primary account
+ALTER TABLE download_queue ADD COLUMN account TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,8 @@
+--- the download attempts made on items that haven't yet been downloaded or given up on, recorded
+--- as each attempt starts so a client that keeps crashing still reaches the attempt limit
+CREATE TABLE attempts (
+    id TEXT PRIMARY KEY NOT NULL,
+    download_attempts INTEGER NOT NULL,
+    --- seconds since the unix epoch
+    last_attempt_at BIGINT NOT NULL
+);
@@ -0,0 +1,3 @@
+--- the sha256 of the downloaded file, recorded when media is stored in the content addressed
+--- layout so each id can be mapped to the file holding its content
+ALTER TABLE media ADD COLUMN sha256 TEXT;
@@ -0,0 +1 @@
+DROP TABLE retry_queue;
@@ -0,0 +1,10 @@
+--- items google rate limited, kept so they are downloaded again once the rate limit has passed,
+--- even by a later run
+CREATE TABLE retry_queue (
+    id TEXT PRIMARY KEY NOT NULL,
+    item TEXT NOT NULL,
+    --- the earliest the item can be tried again, in seconds since the unix epoch
+    retry_at BIGINT NOT NULL,
+    --- the additional account the item belongs to, empty for the primary account
+    account TEXT NOT NULL DEFAULT ''
+);
@@ -0,0 +1,11 @@
+CREATE TABLE download_queue_old (
+    id TEXT PRIMARY KEY NOT NULL,
+    position INTEGER NOT NULL,
+    item TEXT NOT NULL,
+    download_attempts INTEGER NOT NULL,
+    loaded_at BIGINT NOT NULL,
+    account TEXT NOT NULL DEFAULT ''
+);
+INSERT OR IGNORE INTO download_queue_old SELECT id, position, item, download_attempts, loaded_at, account FROM download_queue;
+DROP TABLE download_queue;
+ALTER TABLE download_queue_old RENAME TO download_queue;
@@ -0,0 +1,13 @@
+--- two accounts can have the same item queued, so the queue is keyed by account as well as id
+CREATE TABLE download_queue_new (
+    id TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    item TEXT NOT NULL,
+    download_attempts INTEGER NOT NULL,
+    loaded_at BIGINT NOT NULL,
+    account TEXT NOT NULL DEFAULT '',
+    PRIMARY KEY (account, id)
+);
+INSERT INTO download_queue_new SELECT id, position, item, download_attempts, loaded_at, account FROM download_queue;
+DROP TABLE download_queue;
+ALTER TABLE download_queue_new RENAME TO download_queue;
@@ -0,0 +1,7 @@
+--- the sha256 of each stored file, kept apart from the media table so a file left in the store path
+--- can still be checked against it once the media table has been cleared
+CREATE TABLE file_hashes (
+    id TEXT PRIMARY KEY NOT NULL,
+    sha256 TEXT NOT NULL
+);
+INSERT INTO file_hashes SELECT id, sha256 FROM media WHERE sha256 IS NOT NULL;
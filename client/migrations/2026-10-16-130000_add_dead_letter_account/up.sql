@@ -0,0 +1,3 @@
+--- the additional account a failed item belongs to, empty for the primary account, so retrying it
+--- queues it for the account that failed to download it
+ALTER TABLE dead_letter ADD COLUMN account TEXT NOT NULL DEFAULT '';
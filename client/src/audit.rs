@@ -0,0 +1,76 @@
+use std::{collections::HashSet, error::Error};
+
+use log::{debug, info};
+use reqwest::Client;
+
+use crate::{
+    config::Config,
+    database::{self, DbConnection},
+    media,
+};
+
+/// How the backup compares to the google library
+#[derive(Debug, Default)]
+pub struct AuditReport {
+    /// The number of items in the google library
+    pub library: usize,
+    /// The number of those that have been backed up
+    pub backed_up: usize,
+    /// Items in the library the scan has never reached
+    pub missing: Vec<String>,
+    /// Items in the library that were reached but failed, were given up on or are still waiting to
+    /// download
+    pub not_downloaded: Vec<String>,
+    /// Items in the database that are no longer in the library, deleted from google since they
+    /// were backed up
+    pub removed: Vec<String>,
+}
+
+/// list every item in the google library through the api, a page at a time, without moving the
+/// account's scan
+async fn list_library(
+    config: &Config,
+    agent: &Client,
+) -> Result<HashSet<String>, Box<dyn Error + Send + Sync + 'static>> {
+    let mut ids = HashSet::new();
+    let mut page_token = None;
+    loop {
+        let page = media::library_page(config, agent, page_token).await?;
+        ids.extend(page.ids);
+        debug!("listed {} items", ids.len());
+        match page.next {
+            Some(next) => page_token = Some(next),
+            None => return Ok(ids),
+        }
+    }
+}
+
+/// compare every item google has against the database, reporting what is missing from the backup
+/// and what has been deleted from google since it was backed up
+pub async fn audit(
+    config: &Config,
+    agent: &Client,
+    connection: &mut DbConnection,
+) -> Result<AuditReport, Box<dyn Error + Send + Sync + 'static>> {
+    info!("listing the google library, this can take a while for large libraries");
+    let mut library = list_library(config, agent).await?;
+
+    let mut report = AuditReport {
+        library: library.len(),
+        ..Default::default()
+    };
+    for (id, backed_up) in database::media_ids(connection)? {
+        match (library.remove(&id), backed_up) {
+            (true, true) => report.backed_up += 1,
+            (true, false) => report.not_downloaded.push(id),
+            (false, _) => report.removed.push(id),
+        }
+    }
+    // whatever is left was never seen by the scan
+    report.missing = library.into_iter().collect();
+
+    report.missing.sort();
+    report.not_downloaded.sort();
+    report.removed.sort();
+    Ok(report)
+}
@@ -0,0 +1,171 @@
+use std::{path::PathBuf, time::Duration};
+
+use clap::{Parser, Subcommand};
+
+/// Syncabull is a tool for keeping a local backup of Google Photos
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+pub struct Args {
+    /// Read settings from this TOML file, environment variables take precedence over it
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Write the id of this process to this file while running, refusing to start if another
+    /// running instance already holds it
+    #[arg(long, global = true)]
+    pub pid_file: Option<PathBuf>,
+
+    /// Save only the download status of each item, not its metadata, for faster scans
+    #[arg(long, global = true)]
+    pub no_metadata: bool,
+
+    /// Limit the combined download speed to this many bytes per second for this run, overriding
+    /// max_download_speed. Accepts suffixes such as 500k or 2M
+    #[arg(long, global = true, value_parser = parse_rate)]
+    pub limit_rate: Option<u64>,
+
+    /// Start scanning the primary account from this google page token rather than where its scan
+    /// left off, to skip past or return to a particular page
+    #[arg(long, global = true)]
+    pub start_token: Option<String>,
+
+    /// Shut down gracefully once scanning has run for this long, finishing the current download
+    /// and saving the queue for the next run. Accepts durations such as 90s, 30m, 2h or 1h30m
+    #[arg(long, global = true, value_parser = parse_duration)]
+    pub max_runtime: Option<Duration>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// accept a `YYYY-MM-DD` date, which compares correctly against the stored creation times
+fn parse_date(date: &str) -> Result<String, String> {
+    let valid = date.len() == 10
+        && date.char_indices().all(|(i, c)| match i {
+            4 | 7 => c == '-',
+            _ => c.is_ascii_digit(),
+        });
+    if valid {
+        Ok(date.to_string())
+    } else {
+        Err(format!(
+            "expected a date such as 2020-01-01, got {:?}",
+            date
+        ))
+    }
+}
+
+/// accept a duration made of numbers with `s`, `m`, `h` or `d` suffixes, such as `1h30m`. A number
+/// on its own is seconds
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let invalid = || format!("expected a duration such as 30m or 2h, got {:?}", duration);
+
+    if let Ok(secs) = duration.parse::<u64>() {
+        return Some(secs)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .ok_or_else(invalid);
+    }
+
+    let mut secs: u64 = 0;
+    let mut number = String::new();
+    for c in duration.chars() {
+        let unit = match c {
+            '0'..='9' => {
+                number.push(c);
+                continue;
+            }
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        secs = number
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(unit))
+            .and_then(|n| n.checked_add(secs))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+
+    if !number.is_empty() || secs == 0 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// accept a number of bytes with an optional `k`, `m` or `g` suffix, in powers of 1024 as wget
+/// and curl do
+fn parse_rate(rate: &str) -> Result<u64, String> {
+    let (number, multiplier) = match rate.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&rate[..i], 1024),
+        Some((i, 'm' | 'M')) => (&rate[..i], 1024 * 1024),
+        Some((i, 'g' | 'G')) => (&rate[..i], 1024 * 1024 * 1024),
+        _ => (rate, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("expected a rate such as 500k or 2M, got {:?}", rate))
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Continuously download new media from Google Photos (the default)
+    Run,
+    /// Restart the scan of this account from the beginning, picking up any backfilled media
+    Rescan,
+    /// Replace this client's passcode with a new one from the api, keeping its google login and history
+    RotatePasscode,
+    /// Link the google account again, granting the api's additional scopes alongside those already granted
+    GrantScopes,
+    /// List every item google has and compare it against the backup, reporting what is missing
+    /// locally and what has been deleted from google. Doesn't download anything or move the scan
+    Audit {
+        /// List the id of every missing, undownloaded and removed item, not just the counts
+        #[arg(long)]
+        list: bool,
+    },
+    /// Compact the local database and refresh its statistics, reporting the space reclaimed
+    Maintenance,
+    /// Check that this client is set up correctly, printing a pass/fail checklist
+    Doctor,
+    /// Check this client's config works with the api, printing the api's version and features
+    CheckApi,
+    /// Record the media files in a directory as downloaded, recovering a backup whose database was lost
+    Reindex {
+        /// The directory holding previously downloaded media, normally the store path
+        dir: PathBuf,
+    },
+    /// Report how many items have been downloaded, failed or are pending, and their total size
+    Stats {
+        /// Only count items created on or after this date, e.g. 2020-01-01
+        #[arg(long, value_parser = parse_date)]
+        after: Option<String>,
+        /// Only count items created before this date, e.g. 2021-01-01
+        #[arg(long, value_parser = parse_date)]
+        before: Option<String>,
+    },
+    /// Forget every downloaded item so the next run starts again, keeping this client's registration
+    Purge {
+        /// Also delete the downloaded media from the store path
+        #[arg(long)]
+        delete_files: bool,
+        /// Also forget this client's registration and google login, so the next run registers again
+        #[arg(long)]
+        full: bool,
+        /// Don't ask for confirmation
+        #[arg(long, short)]
+        yes: bool,
+    },
+    /// Write a csv report of every item that permanently failed to download
+    ExportFailed {
+        /// Where to write the report, it is gzip compressed if this ends in `.gz`
+        output: PathBuf,
+    },
+    /// Give every permanently failed item another chance, they are downloaded again on the next run
+    RetryDeadLetter,
+}
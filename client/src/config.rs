@@ -2,13 +2,298 @@ use crate::{
     database::{self, DbConnection},
     media, Id, Passcode,
 };
-use log::{error, info};
-use reqwest::Client;
+use log::{error, info, warn};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::{error::Error, path::PathBuf, process::exit, sync::Mutex};
+use shared_libs::json_templates::MediaItem;
+use std::{
+    error::Error,
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Written to the store path once the initial scan completes, for scripts waiting on the backup
+pub const COMPLETION_MARKER: &str = ".sync-complete";
+
+/// The contents of the completion marker
+#[derive(Debug, Serialize)]
+struct CompletionMarker {
+    /// Seconds since the unix epoch
+    completed_at: u64,
+    /// The number of items downloaded
+    items: u64,
+}
+
+/// The order in which newly scanned items are queued for download
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DownloadOrder {
+    /// Most recently created items are downloaded first
+    NewestFirst,
+    /// Oldest items are downloaded first
+    OldestFirst,
+    /// Items are downloaded in the order the api returns them
+    #[default]
+    ApiOrder,
+}
+
+impl DownloadOrder {
+    /// Sort a page of items according to this ordering, items without a creation time are placed
+    /// at the end of the page
+    pub fn sort(&self, items: &mut [MediaItem]) {
+        // creationTime is an RFC 3339 UTC timestamp, so a lexicographic comparison is sufficient
+        let creation_time = |item: &MediaItem| {
+            item.mediaMetadata
+                .as_ref()
+                .map(|metadata| metadata.creationTime.clone())
+        };
+
+        match self {
+            DownloadOrder::NewestFirst => {
+                items.sort_by(|a, b| match (creation_time(a), creation_time(b)) {
+                    (Some(a), Some(b)) => b.cmp(&a),
+                    (a, b) => b.is_some().cmp(&a.is_some()),
+                })
+            }
+            DownloadOrder::OldestFirst => {
+                items.sort_by(|a, b| match (creation_time(a), creation_time(b)) {
+                    (Some(a), Some(b)) => a.cmp(&b),
+                    (a, b) => b.is_some().cmp(&a.is_some()),
+                })
+            }
+            DownloadOrder::ApiOrder => {}
+        }
+    }
+}
+
+/// What to do once the api has rejected our credentials too many times in a row
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthFailureAction {
+    /// Log an error and keep retrying
+    #[default]
+    Log,
+    /// Post a notification to `auth_failure_webhook` and keep retrying
+    Webhook,
+    /// Stop scanning and wait for the user to re-link their google account
+    Pause,
+}
+
+impl FromStr for AuthFailureAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "log" => Ok(AuthFailureAction::Log),
+            "webhook" => Ok(AuthFailureAction::Webhook),
+            "pause" => Ok(AuthFailureAction::Pause),
+            _ => Err(format!(
+                "invalid auth failure action '{}', expected one of log, webhook, pause",
+                s
+            )),
+        }
+    }
+}
+
+/// Whether an item already in the store path is downloaded again
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SkipIfPresent {
+    /// Always download, replacing the file
+    #[default]
+    Off,
+    /// Skip items whose file has the size google reports
+    Size,
+    /// Skip items whose file has the size google reports and the sha256 recorded when it was
+    /// downloaded, catching files that have rotted or were left half written. Items without a
+    /// recorded hash are downloaded again
+    Hash,
+}
+
+impl FromStr for SkipIfPresent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            // accepted for configs written when this was a plain switch
+            "off" | "false" => Ok(SkipIfPresent::Off),
+            "size" | "true" => Ok(SkipIfPresent::Size),
+            "hash" => Ok(SkipIfPresent::Hash),
+            _ => Err(format!(
+                "invalid skip if present mode '{}', expected one of off, size, hash",
+                s
+            )),
+        }
+    }
+}
+
+/// The HTTP version used to talk to the api and google
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HttpVersion {
+    /// Let the client negotiate, the default
+    #[default]
+    Auto,
+    /// Only use HTTP/1.1, for proxies that don't handle HTTP/2
+    Http1,
+    /// Use HTTP/2 for every connection without negotiating it, so many downloads can share one
+    /// connection. Servers and proxies that don't support HTTP/2 will fail
+    Http2,
+}
+
+impl FromStr for HttpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(HttpVersion::Auto),
+            "http1" | "1.1" => Ok(HttpVersion::Http1),
+            "http2" | "2" => Ok(HttpVersion::Http2),
+            _ => Err(format!(
+                "invalid http version '{}', expected one of auto, http1, http2",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for DownloadOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newest_first" => Ok(DownloadOrder::NewestFirst),
+            "oldest_first" => Ok(DownloadOrder::OldestFirst),
+            "api_order" => Ok(DownloadOrder::ApiOrder),
+            _ => Err(format!(
+                "invalid download order '{}', expected one of newest_first, oldest_first, api_order",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for DownloadOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadOrder::NewestFirst => write!(f, "newest_first"),
+            DownloadOrder::OldestFirst => write!(f, "oldest_first"),
+            DownloadOrder::ApiOrder => write!(f, "api_order"),
+        }
+    }
+}
+
+/// The download parameter rules used when none are configured.
+///
+/// Google reports motion photos as plain images, and `=d` only returns their still frame, the
+/// video part is only served by `=dv`. They are recognised by the names cameras give them: older
+/// Google cameras prefix them with `MVIMG_`, while Pixels add `.MP` before the extension.
+pub const DEFAULT_DOWNLOAD_PARAM_RULES: &str = "MVIMG_*=dv,*.MP.jpg=dv,*.MP.jpeg=dv";
+
+/// Requests items whose filename matches `pattern` with `param`, rather than the parameter
+/// chosen from their mime type. Written as `pattern=param`, where `*` in the pattern matches
+/// any run of characters and matching ignores case.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadParamRule {
+    pub pattern: String,
+    pub param: String,
+}
+
+/// whether `s` matches `pattern`, ignoring case, where `*` matches any run of characters
+pub fn glob_matches(pattern: &str, s: &str) -> bool {
+    let s = s.to_lowercase();
+    let pattern = pattern.to_lowercase();
+    let mut parts = pattern.split('*');
+
+    // the first part is anchored to the start, and the last to the end
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match s.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+impl DownloadParamRule {
+    /// whether `filename` matches this rule's pattern
+    pub fn matches(&self, filename: &str) -> bool {
+        glob_matches(&self.pattern, filename)
+    }
+}
+
+impl FromStr for DownloadParamRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once('=') {
+            Some((pattern, param))
+                if !pattern.is_empty()
+                    && !param.is_empty()
+                    && !param.contains(|c: char| c.is_whitespace() || c == '=') =>
+            {
+                Ok(DownloadParamRule {
+                    pattern: pattern.to_string(),
+                    param: param.to_string(),
+                })
+            }
+            _ => Err(format!(
+                "invalid download param rule '{}', expected pattern=param",
+                s
+            )),
+        }
+    }
+}
+
+/// The number of concurrent api requests allowed when none is configured
+pub const DEFAULT_MAX_API_CONCURRENCY: u32 = 4;
+
+/// Limits how many requests are made to the api at once, so a small self-hosted api isn't
+/// overwhelmed. Held for the whole of each api call, including reading the response, but not by
+/// file downloads, which are limited separately, or by waiting on the long poll for a login.
+#[derive(Debug)]
+pub struct ApiPermits(Semaphore);
+
+impl ApiPermits {
+    pub fn new(permits: u32) -> Self {
+        ApiPermits(Semaphore::new(permits as usize))
+    }
+
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.0
+            .acquire()
+            .await
+            .expect("api permits are never closed")
+    }
+}
+
+impl Default for ApiPermits {
+    fn default() -> Self {
+        ApiPermits::new(DEFAULT_MAX_API_CONCURRENCY)
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
+    /// The additional account this config is for, None for the primary account. Its login is
+    /// stored under `{account}.` keys, and its media in a subfolder of the store path
+    pub account: Option<String>,
+    /// The names of additional google accounts backed up by this client, each with its own login.
+    /// Downloads are recorded by item id alone, so an item shared with several accounts is only
+    /// stored in the folder of the first to download it, and its attempts and failures count for
+    /// all of them. `audit` and `reindex` only cover the primary account
+    pub additional_accounts: Vec<String>,
     /// Temporary location to store media while downloading
     pub temp_path: PathBuf,
     /// The location to store downloaded media
@@ -19,27 +304,182 @@ pub struct Config {
     pub local_id: Option<Id>,
     /// The passcode provided by the remote server
     pub local_passcode: Option<Passcode>,
-    /// The address of the remote server
-    pub webserver_address: String,
+    /// The addresses of the remote servers, tried in order when one is unreachable
+    pub webserver_addresses: Vec<String>,
+    /// The server we registered with, which the authentication flow must be completed against
+    pub registered_address: Option<String>,
     /// The preshared key used to authenticate with the remote server
     pub preshared_key: String,
+    /// When set, registration requests are signed with this key instead of sending `preshared_key`
+    pub request_signing_key: Option<String>,
+    /// When set, this client registers with an id and passcode derived from this secret rather
+    /// than random ones, so a redeployed client with the same seed reuses its existing account
+    pub registration_seed: Option<String>,
     /// Whether we have completed the initial scan for this account yet
     pub initial_scan_complete: Mutex<bool>,
-    /// The maximum number of bytes/sec
+    /// The maximum number of bytes/sec, shared between all downloads of every account, 0 for no
+    /// limit. Only the primary account's setting is used
     pub max_download_speed: u64,
+    /// The order in which each page of new items is queued for download
+    pub download_order: DownloadOrder,
+    /// Download parameters for items needing something other than `d` or `dv`, checked in order
+    pub download_param_rules: Vec<DownloadParamRule>,
+    /// The parameter photos are downloaded with, `d` for the original file with its metadata
+    pub photo_download_param: String,
+    /// The parameter videos are downloaded with, `dv` for the original video
+    pub video_download_param: String,
+    /// Items whose filename matches any of these globs are never downloaded, e.g. `Screenshot_*`
+    pub exclude_patterns: Vec<String>,
+    /// Items whose mime type matches any of these globs are never downloaded, e.g. `image/gif`
+    pub exclude_mime_types: Vec<String>,
+    /// The number of database connections available for concurrently saving media items
+    pub db_writer_threads: u32,
+    /// Download attempts recorded more than this many seconds ago are forgotten when an item is
+    /// queued again, so it gets a fresh set of attempts. 0 to always count them
+    pub attempt_grace_secs: u64,
+    /// Whether to skip downloading items that already exist in the store path, checking their size
+    /// or also their hash
+    pub skip_if_present: SkipIfPresent,
+    /// Whether to move the partial file of a failed download into `failed/` under the store
+    /// path, named after its id, rather than deleting it, to inspect what google sent
+    pub keep_failed_temp: bool,
+    /// Whether to check each downloaded file's EXIF against google's metadata, and write the
+    /// metadata into JPEGs that have no EXIF. Files that are written to no longer match google's
+    /// size or hash, so `skip_if_present` won't recognise them
+    pub embed_exif: bool,
+    /// Whether to store files under `<sha256[0:2]>/<sha256>` in the store path rather than under
+    /// their id, so identical content downloaded for several ids is only stored once. Each item's
+    /// hash is recorded in the database
+    pub content_addressed: bool,
+    /// Whether to download the profile pictures of shared album contributors into
+    /// `contributors/` under the store path, named after each contributor
+    pub download_contributor_avatars: bool,
+    /// How often to rebuild the sprite sheet of recently downloaded images, in seconds, 0 to
+    /// never build one
+    pub sprite_sheet_interval_secs: u64,
+    /// The number of recently downloaded images in the sprite sheet, at most 5100 so the sheet
+    /// fits in a webp image
+    pub sprite_sheet_items: u32,
+    /// How many stored files are hashed again each hour to check they still match the sha256
+    /// recorded when they were downloaded, 0 to never check. Setting it records the hash of every
+    /// download, items downloaded before then are only checked if their hash was already recorded
+    pub integrity_check_rate: u32,
+    /// The url notified of each stored file that fails the integrity check
+    pub integrity_webhook: Option<String>,
+    /// Whether to save only the download status of each item, skipping its metadata, which
+    /// speeds up large scans but leaves `stats --after/--before` with nothing to filter on
+    pub skip_metadata: bool,
+    /// Whether to also back up media archived in google photos, which the scan skips by default.
+    /// The api restarts its scan from the beginning of the library when this changes, so archived
+    /// items the client has already passed are picked up without a `rescan`
+    pub include_archived_media: bool,
+    /// The google page token the first page of this run is fetched from, rather than where the
+    /// api's scan left off. Set by `--start-token`, never saved
+    pub start_token: Option<String>,
+    /// How long scanning runs before the client shuts down gracefully. Set by `--max-runtime`,
+    /// never saved
+    pub max_runtime: Option<Duration>,
+    /// The TOML file settings were read from, if any. Set by `--config`, never saved
+    pub config_file: Option<PathBuf>,
+    /// The number of api responses in a row that can't be parsed before the client gives up, 0 to
+    /// retry forever
+    pub max_parse_failures: u32,
+    /// The number of items requested from the api per page, or None to adapt it to how quickly
+    /// pages are fetched and downloaded
+    pub page_size: Option<u8>,
+    /// The number of consecutive authentication failures before `auth_failure_action` is taken
+    pub auth_failure_threshold: u32,
+    /// What to do once `auth_failure_threshold` consecutive authentication failures occur, taken
+    /// again on each failure after that until authentication succeeds
+    pub auth_failure_action: AuthFailureAction,
+    /// The url notified when `auth_failure_action` is `webhook`
+    pub auth_failure_webhook: Option<String>,
+    /// Downloads pause while the free space on the store path is below this many bytes, 0 to disable
+    pub min_free_bytes: u64,
+    /// How often to check the free space while downloads are paused, in seconds
+    pub free_space_poll_interval_secs: u64,
+    /// The User-Agent sent with every request to the api and google
+    pub user_agent: String,
+    /// The most idle connections kept open to each host for reuse, so many small downloads from
+    /// google don't each open a new connection
+    pub pool_max_idle_per_host: usize,
+    /// How long an idle connection is kept open for reuse, in seconds, 0 to keep it open until the
+    /// other side closes it
+    pub pool_idle_timeout_secs: u64,
+    /// How often TCP keep-alive probes are sent on open connections, in seconds, 0 to disable
+    pub tcp_keepalive_secs: u64,
+    /// The HTTP version used for every request
+    pub http_version: HttpVersion,
+    /// Items larger than this many bytes are downloaded through the api in resumable chunks, in a
+    /// lane of their own so they don't hold up smaller items, 0 to disable
+    pub large_file_threshold: u64,
+    /// How long to wait before scanning again once every item is present, in seconds
+    pub idle_rescan_interval_secs: u64,
+    /// The most pages fetched from the api per `scan_budget_interval_secs`, 0 for no limit. Keeps
+    /// a huge initial scan from using up google's daily quota in one go. Only pages that were
+    /// fetched successfully count against it
+    pub max_scan_pages_per_run: u32,
+    /// How often the scan budget is reset, in seconds
+    pub scan_budget_interval_secs: u64,
+    /// The number of pages fetched before downloading starts, filling the queue up front so the
+    /// downloader isn't left waiting on the api. Items whose base urls expire before they are
+    /// downloaded are fetched through the api by id, apis too old to do so only fetch one page
+    pub prefetch_pages: u32,
+    /// How often queued items are reloaded so their base urls don't expire, in seconds
+    pub baseurl_reload_interval_secs: u64,
+    /// The longest a photo may take to download, in seconds, raised for files too large to finish in time
+    pub photo_download_timeout_secs: u64,
+    /// The longest a video may take to download, in seconds, raised for files too large to finish in time
+    pub video_download_timeout_secs: u64,
+    /// Downloads that receive no data for this many seconds are abandoned
+    pub download_stall_timeout_secs: u64,
+    /// The sqlite journal mode, WAL by default so downloads can be saved while the queue is read
+    pub sqlite_journal_mode: String,
+    /// The sqlite synchronous setting for every connection
+    pub sqlite_synchronous: String,
+    /// How long a connection waits on a locked database before failing, in milliseconds
+    pub sqlite_busy_timeout_ms: u32,
+    /// Where to serve the status endpoints, such as `/queue`, disabled if unset
+    pub status_address: Option<SocketAddr>,
+    /// How long to wait after each download before starting the next, in milliseconds
+    pub inter_download_delay_ms: u64,
+    /// Up to this many milliseconds are randomly added to `inter_download_delay_ms`
+    pub inter_download_jitter_ms: u64,
+    /// A command run after each successful download, given the stored file's path and the item's
+    /// id as arguments
+    pub post_download_command: Option<String>,
+    /// The longest `post_download_command` may run for, in seconds, before it is killed
+    pub post_download_timeout_secs: u64,
+    /// A download lane that neither moves on nor receives any data for this many seconds is
+    /// assumed to be stuck, e.g. waiting on a locked database or a response that never comes, and
+    /// is restarted with its current item put back on the queue, or given up on once it has used
+    /// every attempt. 0 to never restart lanes
+    pub worker_stall_timeout_secs: u64,
+    /// The most requests made to the api at once, not counting file downloads
+    pub max_api_concurrency: u32,
+    #[serde(skip)]
+    pub api_permits: ApiPermits,
 }
 
 impl Config {
+    /// load the config of an additional account, or of the primary account if None, registering
+    /// and linking a google account first if needed
     pub async fn load(
-        agent: &Client,
         connection: &mut DbConnection,
+        account: Option<&str>,
+        config_file: Option<&Path>,
     ) -> Result<Config, Box<dyn Error + Send + Sync + 'static>> {
-        let mut config = database::load_config(connection)?;
+        let mut config = database::load_account_config(connection, account, config_file)?;
+        let agent = &crate::agent(&config);
+
+        if let Some(account) = account {
+            info!("loading additional account {}", account);
+        }
 
         if config.local_id.is_none() {
             info!("client is not registered, registering with api...");
 
-            let (id, passcode) = match media::register(&config, agent).await {
+            let (id, passcode, address) = match media::register(&config, agent).await {
                 Ok(f) => f,
                 Err(e) => {
                     error!("unable to register with api {}", e);
@@ -49,6 +489,7 @@ impl Config {
 
             config.local_id = Some(id);
             config.local_passcode = Some(passcode);
+            config.registered_address = Some(address);
 
             info!("success!");
         }
@@ -57,21 +498,8 @@ impl Config {
         if !config.authenticated {
             info!("client is not authenticated, getting authentication url now.");
 
-            let auth_url = match media::get_auth_url(&config, agent).await {
-                Ok(f) => f,
-                Err(e) => {
-                    error!("unable to get auth url {}", e);
-                    exit(1);
-                }
-            };
-
-            info!(
-                "please visit {} and complete authentication within 120 seconds",
-                auth_url
-            );
-
             // wait for the user to authenticate
-            if let Err(e) = media::await_user_authentication(&config, agent).await {
+            if let Err(e) = media::authenticate(&config, agent, false).await {
                 error!("authentication failed {}", e);
                 exit(1);
             }
@@ -97,10 +525,91 @@ impl Config {
         connection: &mut DbConnection,
     ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
         *self.initial_scan_complete.lock().unwrap() = true;
-        database::save_config(connection, self)
+        database::save_config(connection, self)?;
+
+        // the marker is only a convenience, so failing to write it doesn't fail the scan
+        if let Err(e) = self.write_completion_marker(connection) {
+            warn!("failed to write {}: {}", COMPLETION_MARKER, e);
+        }
+        Ok(())
+    }
+
+    pub fn reset_initial_scan_complete(
+        &self,
+        connection: &mut DbConnection,
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        *self.initial_scan_complete.lock().unwrap() = false;
+        database::save_config(connection, self)?;
+
+        match std::fs::remove_file(self.store_path.join(COMPLETION_MARKER)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    fn write_completion_marker(
+        &self,
+        connection: &mut DbConnection,
+    ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        let marker = CompletionMarker {
+            completed_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs(),
+            items: database::media_stats(connection, None, None)?.downloaded,
+        };
+
+        // written alongside and swapped in, so a watcher never reads a half written marker
+        let path = self.store_path.join(COMPLETION_MARKER);
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&marker)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// The server that the authentication flow should be run against, falling back to the first
+    /// configured server for clients that registered before multiple servers were supported
+    pub fn registered_address(&self) -> &str {
+        self.registered_address
+            .as_deref()
+            .unwrap_or(&self.webserver_addresses[0])
+    }
+
+    /// The pause between downloads, `inter_download_delay_ms` plus a random amount of jitter
+    pub fn inter_download_delay(&self) -> Duration {
+        let jitter = match self.inter_download_jitter_ms {
+            0 => 0,
+            max => rand::thread_rng().gen_range(0..=max),
+        };
+        Duration::from_millis(self.inter_download_delay_ms + jitter)
+    }
+
+    /// whether an item matches `exclude_patterns` or `exclude_mime_types`, and should never be
+    /// downloaded
+    pub fn excluded(&self, item: &MediaItem) -> bool {
+        self.exclude_patterns
+            .iter()
+            .any(|pattern| glob_matches(pattern, &item.filename))
+            || item.mimeType.as_ref().is_some_and(|mime_type| {
+                self.exclude_mime_types
+                    .iter()
+                    .any(|pattern| glob_matches(pattern, mime_type))
+            })
+    }
+
+    /// the name of the account, for logging
+    pub fn account_name(&self) -> &str {
+        self.account.as_deref().unwrap_or("primary")
     }
 
     pub fn initial_scan_complete(&self) -> bool {
         *self.initial_scan_complete.lock().unwrap()
     }
+
+    /// whether each download's sha256 is taken and recorded in the database
+    pub fn records_sha256(&self) -> bool {
+        self.content_addressed
+            || self.skip_if_present == SkipIfPresent::Hash
+            || self.integrity_check_rate > 0
+    }
 }
@@ -1,15 +1,80 @@
-use std::{collections::HashMap, error::Error, path::PathBuf, sync::Mutex};
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
 
-use diesel::{sqlite::Sqlite, Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
+use diesel::{
+    connection::SimpleConnection,
+    r2d2::{ConnectionManager, CustomizeConnection, Pool},
+    sql_types::BigInt,
+    sqlite::Sqlite,
+    Connection, ExpressionMethods, OptionalExtension, QueryDsl, Queryable, QueryableByName,
+    RunQueryDsl,
+};
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use log::warn;
+use serde::Serialize;
 use shared_libs::json_templates::MediaItem;
 
-use crate::config::Config;
+use crate::{
+    config::{
+        ApiPermits, AuthFailureAction, Config, DownloadOrder, DownloadParamRule, HttpVersion,
+        SkipIfPresent, DEFAULT_DOWNLOAD_PARAM_RULES, DEFAULT_MAX_API_CONCURRENCY,
+    },
+    media::DownloadOutcome,
+    page_size::MAX_PAGE_SIZE,
+    sprites::MAX_SPRITE_ITEMS,
+    DEFAULT_USER_AGENT,
+};
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
 pub type DbConnection = diesel::SqliteConnection;
 pub type DB = Sqlite;
+pub type DbPool = Pool<ConnectionManager<DbConnection>>;
+
+/// The busy timeout used until the config has been loaded
+const DEFAULT_BUSY_TIMEOUT_MS: u32 = 5000;
+
+/// How many times to try opening the database while it is locked before giving up
+const CONNECT_ATTEMPTS: u32 = 5;
+
+/// Applied to every connection handed out by the pool, as several pooled connections writing at
+/// once would otherwise immediately fail with `SQLITE_BUSY`
+#[derive(Debug)]
+struct ConnectionOptions {
+    busy_timeout_ms: u32,
+    synchronous: String,
+}
+
+impl CustomizeConnection<DbConnection, diesel::r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, connection: &mut DbConnection) -> Result<(), diesel::r2d2::Error> {
+        connection
+            .batch_execute(&format!(
+                "PRAGMA busy_timeout = {}; PRAGMA synchronous = {};",
+                self.busy_timeout_ms, self.synchronous
+            ))
+            .map_err(diesel::r2d2::Error::QueryError)?;
+        Ok(())
+    }
+}
+
+/// apply the configured journal mode, busy timeout and synchronous setting to a connection. The
+/// journal mode is stored in the database file, so it also applies to every later connection.
+pub fn apply_pragmas(
+    connection: &mut DbConnection,
+    config: &Config,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    connection.batch_execute(&format!(
+        "PRAGMA journal_mode = {}; PRAGMA busy_timeout = {}; PRAGMA synchronous = {};",
+        config.sqlite_journal_mode, config.sqlite_busy_timeout_ms, config.sqlite_synchronous
+    ))?;
+    Ok(())
+}
 
 pub fn run_migrations(
     connection: &mut impl MigrationHarness<DB>,
@@ -18,10 +83,186 @@ pub fn run_migrations(
     Ok(())
 }
 
-pub fn establish_connection(
+fn try_establish_connection(
+    database_url: &str,
+) -> Result<DbConnection, Box<dyn Error + Send + Sync + 'static>> {
+    let mut connection = DbConnection::establish(database_url)?;
+    diesel::sql_query(format!(
+        "PRAGMA busy_timeout = {};",
+        DEFAULT_BUSY_TIMEOUT_MS
+    ))
+    .execute(&mut connection)?;
+    Ok(connection)
+}
+
+/// The database url to use when `DATABASE_URL` is not set, `database.db` in the platform's data
+/// directory, e.g. `~/.local/share/syncabull/` on linux
+pub fn default_database_url() -> Result<String, Box<dyn Error + Send + Sync + 'static>> {
+    let dirs = directories::ProjectDirs::from("com", "syncabull", "syncabull")
+        .ok_or("unable to find a home directory for the database, set DATABASE_URL")?;
+    let path = dirs.data_dir().join("database.db");
+    Ok(path
+        .to_str()
+        .ok_or("the default database path is not valid unicode, set DATABASE_URL")?
+        .to_string())
+}
+
+/// whether a database url is a plain path, rather than an in-memory database or uri filename,
+/// which are left to sqlite
+fn is_path(database_url: &str) -> bool {
+    !database_url.starts_with(':') && !database_url.starts_with("file:")
+}
+
+/// create the directory a database path is in
+fn create_parent_dir(database_url: &str) -> std::io::Result<()> {
+    match Path::new(database_url).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent),
+        _ => Ok(()),
+    }
+}
+
+/// Take an exclusive lock on `{database}.lock`, so that two clients can never use the same
+/// database at once. The lock is held until the returned file is dropped, and is released by the
+/// OS if the client crashes. Returns None for in-memory databases and uri filenames.
+pub fn lock_database(
+    database_url: &str,
+) -> Result<Option<std::fs::File>, Box<dyn Error + Send + Sync + 'static>> {
+    use fs2::FileExt;
+
+    if !is_path(database_url) {
+        return Ok(None);
+    }
+    create_parent_dir(database_url)?;
+
+    let path = format!("{}.lock", database_url);
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)?;
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => Err(format!(
+            "another client is already using the database {}",
+            database_url
+        )
+        .into()),
+        Err(e) => Err(format!("unable to lock {}: {}", path, e).into()),
+    }
+}
+
+/// connect to the database, creating the directory it is in if needed. Opening a database that
+/// another process is setting up can briefly fail as locked, so that is retried with a backoff.
+pub async fn establish_connection(
     database_url: &str,
 ) -> Result<DbConnection, Box<dyn Error + Send + Sync + 'static>> {
-    Ok(DbConnection::establish(database_url)?)
+    if is_path(database_url) {
+        create_parent_dir(database_url)?;
+    }
+
+    let mut backoff = Duration::from_millis(100);
+    let mut attempt = 1;
+    loop {
+        match try_establish_connection(database_url) {
+            Ok(connection) => return Ok(connection),
+            Err(e) if attempt < CONNECT_ATTEMPTS && is_locked(&e.to_string()) => {
+                warn!(
+                    "database is locked, retrying in {:?} (attempt {} of {})",
+                    backoff, attempt, CONNECT_ATTEMPTS
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// whether an error from sqlite is because another connection holds a lock, which passes
+fn is_locked(message: &str) -> bool {
+    message.contains("database is locked") || message.contains("database is busy")
+}
+
+/// create a pool of up to `db_writer_threads` connections, so that database writes can run
+/// concurrently
+pub fn establish_pool(
+    database_url: &str,
+    config: &Config,
+) -> Result<DbPool, Box<dyn Error + Send + Sync + 'static>> {
+    Ok(Pool::builder()
+        .max_size(config.db_writer_threads)
+        .connection_customizer(Box::new(ConnectionOptions {
+            busy_timeout_ms: config.sqlite_busy_timeout_ms,
+            synchronous: config.sqlite_synchronous.clone(),
+        }))
+        .build(ConnectionManager::<DbConnection>::new(database_url))?)
+}
+
+/// run `f` with a connection from the pool, returning an error rather than panicking if the
+/// pool times out waiting for a free connection
+pub fn with_connection<T, F>(
+    pool: &DbPool,
+    f: F,
+) -> Result<T, Box<dyn Error + Send + Sync + 'static>>
+where
+    F: FnOnce(&mut DbConnection) -> Result<T, Box<dyn Error + Send + Sync + 'static>>,
+{
+    let mut connection = pool.get()?;
+    f(&mut connection)
+}
+
+/// `with_connection` on a blocking thread, for async code. Waiting on a busy pool or a locked
+/// database then doesn't hold up the runtime, and the caller can give up on the wait
+pub async fn spawn_with_connection<T, F>(
+    pool: &DbPool,
+    f: F,
+) -> Result<T, Box<dyn Error + Send + Sync + 'static>>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut DbConnection) -> Result<T, Box<dyn Error + Send + Sync + 'static>>
+        + Send
+        + 'static,
+{
+    let pool = pool.clone();
+    tokio::task::spawn_blocking(move || with_connection(&pool, f)).await?
+}
+
+#[derive(QueryableByName)]
+struct DatabaseSize {
+    #[diesel(sql_type = BigInt)]
+    bytes: i64,
+}
+
+/// the current size of the database file, in bytes
+pub fn database_size(
+    connection: &mut DbConnection,
+) -> Result<i64, Box<dyn Error + Send + Sync + 'static>> {
+    let size: DatabaseSize = diesel::sql_query(
+        "SELECT page_count * page_size AS bytes FROM pragma_page_count(), pragma_page_size()",
+    )
+    .get_result(connection)?;
+    Ok(size.bytes)
+}
+
+/// rebuild the database to reclaim unused space and refresh the query planner statistics,
+/// returns the size of the database before and after in bytes
+pub fn run_maintenance(
+    connection: &mut DbConnection,
+) -> Result<(i64, i64), Box<dyn Error + Send + Sync + 'static>> {
+    let before = database_size(connection)?;
+    diesel::sql_query("VACUUM").execute(connection)?;
+    diesel::sql_query("ANALYZE").execute(connection)?;
+    let after = database_size(connection)?;
+    Ok((before, after))
+}
+
+/// a lightweight optimisation of the database, cheap enough to run on every startup
+pub fn optimize(
+    connection: &mut DbConnection,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    diesel::sql_query("PRAGMA optimize").execute(connection)?;
+    Ok(())
 }
 
 // media (id) {
@@ -47,11 +288,20 @@ pub fn establish_connection(
 //     download_attempts -> Integer,
 //     download_success -> Bool,
 //     download_timestamp -> Text,
+//     latitude -> Nullable<Double>,
+//     longitude -> Nullable<Double>,
+//     file_path -> Nullable<Text>,
+//     file_size -> Nullable<BigInt>,
+//     download_duration_ms -> Nullable<BigInt>,
+//     last_error -> Nullable<Text>,
+//     excluded -> Bool,
 // }
 
+/// save a media item, along with the outcome of downloading it if it was downloaded
 pub fn save_media_item(
     connection: &mut DbConnection,
     media_item: &MediaItem,
+    outcome: Option<&DownloadOutcome>,
 ) -> Result<String, Box<dyn Error + Send + Sync + 'static>> {
     use crate::schema::media::dsl::*;
 
@@ -214,6 +464,29 @@ pub fn save_media_item(
                 .as_ref()
                 .map(|contributor_info| &contributor_info.displayName)
         }),
+        latitude.eq({
+            // only present if google provided a location
+            media_item
+                .mediaMetadata
+                .as_ref()
+                .and_then(|media_metadata| media_metadata.location.as_ref())
+                .and_then(|location| location.latlng.as_ref())
+                .map(|latlng| latlng.latitude)
+        }),
+        longitude.eq({
+            // only present if google provided a location
+            media_item
+                .mediaMetadata
+                .as_ref()
+                .and_then(|media_metadata| media_metadata.location.as_ref())
+                .and_then(|location| location.latlng.as_ref())
+                .map(|latlng| latlng.longitude)
+        }),
+        file_path.eq(outcome.map(|outcome| outcome.path.to_string_lossy().to_string())),
+        file_size.eq(outcome.map(|outcome| outcome.bytes as i64)),
+        download_duration_ms.eq(outcome.map(|outcome| outcome.duration.as_millis() as i64)),
+        last_error.eq(&media_item.last_error),
+        sha256.eq(outcome.and_then(|outcome| outcome.sha256.as_ref())),
     );
 
     // insert with each field specified manually
@@ -228,139 +501,1613 @@ pub fn save_media_item(
         .load_iter(connection)?
         .next()
         .unwrap()?;
+    if let Some(hash) = outcome.and_then(|outcome| outcome.sha256.as_deref()) {
+        record_hash(connection, &r, hash)?;
+    }
     Ok(r)
 }
 
-/// check if a media item is present in the database, searching by id
-pub fn in_database(
+/// an item which could not be downloaded
+#[derive(Debug, Queryable)]
+pub struct FailedItem {
+    pub id: String,
+    pub filename: String,
+    pub product_url: String,
+    pub last_error: Option<String>,
+}
+
+/// load every item in the dead letter table
+pub fn failed_media_items(
     connection: &mut DbConnection,
-    search_id: &str,
-) -> Result<bool, Box<dyn Error + Send + Sync + 'static>> {
-    use crate::schema::media::dsl::*;
-    let r: Vec<String> = media.select(id).filter(id.eq(search_id)).load(connection)?;
-    Ok(!r.is_empty())
+) -> Result<Vec<FailedItem>, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::dead_letter::dsl::*;
+    Ok(dead_letter
+        .select((id, filename, product_url, reason))
+        .load::<FailedItem>(connection)?)
 }
 
-pub fn load_config(
+/// move an item an account will never download into the dead letter table, along with why it failed
+pub fn save_dead_letter(
     connection: &mut DbConnection,
-) -> Result<Config, Box<dyn Error + Send + Sync + 'static>> {
-    // load every row from the config table into a hashmap of key-value pairs
-    use crate::schema::config::dsl::*;
-    let r: HashMap<String, String> = config
-        .select((key, value))
-        .load::<(String, String)>(connection)?
-        .into_iter()
-        .collect();
+    item_account: &str,
+    media_item: &MediaItem,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::dead_letter::dsl::*;
 
-    // if a key exists in env, load that over trying to load from the database
-    // otherwise pull it from the database and pass that into the config
-    let store_path = match std::env::var("STORE_PATH") {
-        Ok(s) => PathBuf::from(s),
-        Err(_) => PathBuf::from(r.get("store_path").expect("store_path not found in config")),
-    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .to_string();
 
-    let temp_path = match std::env::var("TEMP_PATH") {
-        Ok(s) => PathBuf::from(s),
-        Err(_) => PathBuf::from(r.get("temp_path").expect("temp_path not found in config")),
-    };
+    let records = (
+        id.eq(&media_item.id),
+        filename.eq(&media_item.filename),
+        product_url.eq(&media_item.productUrl),
+        creation_time.eq(media_item
+            .mediaMetadata
+            .as_ref()
+            .map(|media_metadata| &media_metadata.creationTime)),
+        download_attempts.eq(media_item.download_attempts as i32),
+        reason.eq(&media_item.last_error),
+        failed_at.eq(&now),
+        account.eq(item_account),
+    );
 
-    // if authenticated not present == false
-    let authenticated = match std::env::var("AUTHENTICATED") {
-        Ok(s) => s == "true",
-        Err(_) => r.get("authenticated").unwrap_or(&String::from("false")) == "true",
-    };
+    connection.transaction(|connection| {
+        diesel::insert_into(dead_letter)
+            .values(records)
+            .on_conflict(id)
+            .do_update()
+            .set(records)
+            .execute(connection)?;
+        diesel::delete(crate::schema::media::table.find(&media_item.id)).execute(connection)?;
+        Ok(())
+    })
+}
 
-    let local_id = match std::env::var("LOCAL_ID") {
-        Ok(s) => Some(s),
-        Err(_) => r.get("local_id").map(|s| s.to_string()),
-    };
+/// Move every item in the dead letter table onto the retry queue of its account with its attempts
+/// reset, so the next run downloads it again, returning how many were moved. Their base urls are
+/// long gone, so they are downloaded through the api.
+pub fn retry_dead_letter(
+    connection: &mut DbConnection,
+) -> Result<usize, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::dead_letter::dsl::*;
 
-    let local_passcode = match std::env::var("LOCAL_PASSCODE") {
-        Ok(s) => Some(s),
-        Err(_) => r.get("local_passcode").map(|s| s.to_string()),
-    };
+    connection.transaction(|connection| {
+        let failed = dead_letter
+            .select((id, filename, product_url, account))
+            .load::<(String, String, String, String)>(connection)?;
+        for (item_id, item_filename, item_url, item_account) in failed.iter() {
+            let media_item = MediaItem {
+                id: item_id.clone(),
+                description: None,
+                productUrl: item_url.clone(),
+                baseUrl: String::new(),
+                mimeType: None,
+                mediaMetadata: None,
+                contributorInfo: None,
+                filename: item_filename.clone(),
+                download_attempts: 0,
+                download_success: false,
+                last_error: None,
+            };
+            clear_attempts(connection, item_id)?;
+            save_retry(connection, item_account, &media_item, 0)?;
+        }
+        diesel::delete(dead_letter).execute(connection)?;
+        Ok(failed.len())
+    })
+}
 
-    let webserver_address = match std::env::var("WEBSERVER_ADDRESS") {
-        Ok(s) => s,
-        Err(_) => r.get("webserver_address").unwrap().to_string(),
-    };
+/// record that an attempt at downloading an item has started, and how many have been made
+pub fn record_attempt(
+    connection: &mut DbConnection,
+    item_id: &str,
+    count: u32,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::attempts::dsl::*;
 
-    let preshared_key = match std::env::var("PRESHARED_KEY") {
-        Ok(s) => s,
-        Err(_) => r.get("preshared_key").unwrap().to_string(),
-    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs() as i64;
+    let records = (
+        id.eq(item_id),
+        download_attempts.eq(count as i32),
+        last_attempt_at.eq(now),
+    );
 
-    let initial_scan_complete = match std::env::var("INITIAL_SCAN_COMPLETE") {
-        Ok(s) => s == "true",
-        Err(_) => {
-            r.get("initial_scan_complete")
-                .unwrap_or(&String::from("false"))
-                == "true"
-        }
-    };
-    let initial_scan_complete = Mutex::new(initial_scan_complete);
+    diesel::insert_into(attempts)
+        .values(records)
+        .on_conflict(id)
+        .do_update()
+        .set(records)
+        .execute(connection)?;
+    Ok(())
+}
 
-    let max_download_speed = match std::env::var("MAX_DOWNLOAD_SPEED") {
-        Ok(s) => s.parse::<u64>().unwrap(),
-        Err(_) => r
-            .get("max_download_speed")
-            .unwrap_or(&String::from("0"))
-            .parse::<u64>()
-            .unwrap(),
+/// The attempts recorded for any of these items, by id. Attempts last made more than `max_age`
+/// seconds ago are ignored, unless it is 0.
+pub fn recorded_attempts(
+    connection: &mut DbConnection,
+    item_ids: &[&str],
+    max_age: u64,
+) -> Result<HashMap<String, u32>, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::attempts::dsl::*;
+
+    let since = match max_age {
+        0 => 0,
+        max_age => std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs()
+            .saturating_sub(max_age) as i64,
     };
 
-    Ok(Config {
-        store_path,
-        authenticated,
-        local_id,
-        local_passcode,
-        webserver_address,
-        preshared_key,
-        initial_scan_complete,
-        temp_path,
-        max_download_speed,
-    })
+    Ok(attempts
+        .filter(id.eq_any(item_ids))
+        .filter(last_attempt_at.ge(since))
+        .select((id, download_attempts))
+        .load::<(String, i32)>(connection)?
+        .into_iter()
+        .map(|(item_id, count)| (item_id, count as u32))
+        .collect())
 }
 
-pub fn save_config(
+/// forget the attempts made on an item, once it has been downloaded or given up on
+pub fn clear_attempts(
     connection: &mut DbConnection,
-    save_config: &Config,
+    item_id: &str,
 ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
-    use crate::schema::config::dsl::*;
+    use crate::schema::attempts::dsl::*;
+    diesel::delete(attempts.filter(id.eq(item_id))).execute(connection)?;
+    Ok(())
+}
 
-    // convert the config struct into a hashmap of key-value pairs
-    let authenticated = save_config.authenticated.to_string();
-    let initial_scan_complete = save_config
-        .initial_scan_complete
-        .lock()
+/// save only what is needed to track a media item's download, skipping the metadata columns.
+/// Used when `skip_metadata` is set, any metadata already saved for the item is left as it was.
+pub fn save_media_item_minimal(
+    connection: &mut DbConnection,
+    media_item: &MediaItem,
+    outcome: Option<&DownloadOutcome>,
+) -> Result<String, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::media::dsl::*;
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
+        .as_secs()
         .to_string();
-    let mut r = vec![
-        ("store_path", save_config.store_path.to_str().unwrap()),
-        ("authenticated", &authenticated),
-        ("webserver_address", &save_config.webserver_address),
-        ("preshared_key", &save_config.preshared_key),
-        ("initial_scan_complete", &initial_scan_complete),
-    ];
 
-    if let Some(local_id) = &save_config.local_id {
-        r.push(("local_id", local_id));
-    }
+    let records = (
+        id.eq(&media_item.id),
+        product_url.eq(&media_item.productUrl),
+        base_url.eq(&media_item.baseUrl),
+        filename.eq(&media_item.filename),
+        download_attempts.eq(media_item.download_attempts as i32),
+        download_success.eq(&media_item.download_success),
+        download_timestamp.eq(&now),
+        file_path.eq(outcome.map(|outcome| outcome.path.to_string_lossy().to_string())),
+        file_size.eq(outcome.map(|outcome| outcome.bytes as i64)),
+        download_duration_ms.eq(outcome.map(|outcome| outcome.duration.as_millis() as i64)),
+        last_error.eq(&media_item.last_error),
+        sha256.eq(outcome.and_then(|outcome| outcome.sha256.as_ref())),
+    );
 
-    if let Some(local_passcode) = &save_config.local_passcode {
-        r.push(("local_passcode", local_passcode));
+    diesel::insert_into(media)
+        .values(records.clone())
+        .on_conflict(id)
+        .do_update()
+        .set(records)
+        .execute(connection)?;
+    if let Some(hash) = outcome.and_then(|outcome| outcome.sha256.as_deref()) {
+        record_hash(connection, &media_item.id, hash)?;
     }
+    Ok(media_item.id.clone())
+}
 
-    // insert with each field specified manually
-    for (d_key, d_value) in r.into_iter() {
-        diesel::insert_into(config)
-            .values((key.eq(d_key), value.eq(d_value)))
-            // on conflict, replace all fields
-            .on_conflict(key)
-            .do_update()
-            .set(value.eq(d_value))
-            .execute(connection)?;
-    }
+/// remember the sha256 of an item's stored file. It outlives the item's media row, so a file left
+/// in the store path can be checked against it after the media table has been cleared
+fn record_hash(
+    connection: &mut DbConnection,
+    item_id: &str,
+    hash: &str,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::file_hashes::dsl::*;
+
+    let record = (id.eq(item_id), sha256.eq(hash));
+    diesel::insert_into(file_hashes)
+        .values(record)
+        .on_conflict(id)
+        .do_update()
+        .set(record)
+        .execute(connection)?;
+    Ok(())
+}
+
+/// the sha256 recorded when an item's file was last stored, if any
+pub fn recorded_hash(
+    connection: &mut DbConnection,
+    item_id: &str,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::file_hashes::dsl::*;
+    Ok(file_hashes
+        .select(sha256)
+        .filter(id.eq(item_id))
+        .first(connection)
+        .optional()?)
+}
+
+/// forget every recorded file hash, once the files they were recorded for have been deleted
+pub fn clear_hashes(
+    connection: &mut DbConnection,
+) -> Result<usize, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::file_hashes::dsl::*;
+    Ok(diesel::delete(file_hashes).execute(connection)?)
+}
+
+/// record an item as intentionally excluded, so it is never downloaded. Items that are already
+/// recorded are left as they are.
+pub fn save_excluded(
+    connection: &mut DbConnection,
+    media_item: &MediaItem,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::media::dsl::*;
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .to_string();
 
+    diesel::insert_into(media)
+        .values((
+            id.eq(&media_item.id),
+            product_url.eq(&media_item.productUrl),
+            base_url.eq(&media_item.baseUrl),
+            mime_type.eq(&media_item.mimeType),
+            filename.eq(&media_item.filename),
+            download_attempts.eq(0),
+            download_success.eq(false),
+            download_timestamp.eq(&now),
+            creation_time.eq(media_item
+                .mediaMetadata
+                .as_ref()
+                .map(|media_metadata| &media_metadata.creationTime)),
+            excluded.eq(true),
+        ))
+        .on_conflict_do_nothing()
+        .execute(connection)?;
     Ok(())
 }
+
+/// the ids and file paths of the `limit` most recently downloaded images
+pub fn recent_images(
+    connection: &mut DbConnection,
+    limit: u32,
+) -> Result<Vec<(String, String)>, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::media::dsl::*;
+    use diesel::{NullableExpressionMethods, TextExpressionMethods};
+    Ok(media
+        .select((id, file_path.assume_not_null()))
+        .filter(download_success.eq(true))
+        .filter(mime_type.like("image/%"))
+        .filter(file_path.is_not_null())
+        .order((download_timestamp.desc(), id))
+        .limit(limit as i64)
+        .load::<(String, String)>(connection)?)
+}
+
+/// replace an account's saved download queue with `items`, in the order they will be downloaded,
+/// from a page loaded at `loaded` seconds since the unix epoch. The primary account is `""`
+pub fn save_queue(
+    connection: &mut DbConnection,
+    queue_account: &str,
+    items: &[MediaItem],
+    loaded: u64,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::download_queue::dsl::*;
+
+    let rows = items
+        .iter()
+        .enumerate()
+        .map(|(i, media_item)| {
+            Ok((
+                id.eq(&media_item.id),
+                position.eq(i as i32),
+                item.eq(serde_json::to_string(media_item)?),
+                download_attempts.eq(media_item.download_attempts as i32),
+                loaded_at.eq(loaded as i64),
+                account.eq(queue_account),
+            ))
+        })
+        .collect::<Result<Vec<_>, serde_json::Error>>()?;
+
+    connection.transaction(|connection| {
+        diesel::delete(download_queue.filter(account.eq(queue_account))).execute(connection)?;
+        diesel::insert_into(download_queue)
+            .values(rows)
+            .execute(connection)?;
+        Ok(())
+    })
+}
+
+/// load an account's saved download queue in order, along with when the page it came from was
+/// loaded, in seconds since the unix epoch
+pub fn load_queue(
+    connection: &mut DbConnection,
+    queue_account: &str,
+) -> Result<Vec<(MediaItem, u64)>, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::download_queue::dsl::*;
+
+    let rows = download_queue
+        .filter(account.eq(queue_account))
+        .select((item, loaded_at))
+        .order(position)
+        .load::<(String, i64)>(connection)?;
+
+    rows.into_iter()
+        .map(|(saved, loaded)| Ok((serde_json::from_str(&saved)?, loaded as u64)))
+        .collect()
+}
+
+/// remember an item google rate limited, so it is downloaded again once `at`, in seconds since
+/// the unix epoch, has passed, even if that is by a later run. The rate limited attempt doesn't
+/// count, so the item's recorded attempts are set back to its `download_attempts`
+pub fn save_retry(
+    connection: &mut DbConnection,
+    retry_account: &str,
+    media_item: &MediaItem,
+    at: u64,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::retry_queue::dsl::*;
+
+    let saved = serde_json::to_string(media_item)?;
+    let record = (
+        id.eq(&media_item.id),
+        item.eq(&saved),
+        retry_at.eq(at as i64),
+        account.eq(retry_account),
+    );
+    connection.transaction(|connection| {
+        diesel::insert_into(retry_queue)
+            .values(record)
+            .on_conflict(id)
+            .do_update()
+            .set(record)
+            .execute(connection)?;
+        record_attempt(connection, &media_item.id, media_item.download_attempts)
+    })
+}
+
+/// the items in an account's retry queue that can be tried again at `now`, in seconds since the
+/// unix epoch, longest waiting first
+pub fn due_retries(
+    connection: &mut DbConnection,
+    retry_account: &str,
+    now: u64,
+) -> Result<Vec<MediaItem>, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::retry_queue::dsl::*;
+
+    let rows = retry_queue
+        .filter(account.eq(retry_account))
+        .filter(retry_at.le(now as i64))
+        .select(item)
+        .order(retry_at)
+        .load::<String>(connection)?;
+
+    rows.iter()
+        .map(|saved| Ok(serde_json::from_str(saved)?))
+        .collect()
+}
+
+/// take an item off the retry queue, once it has been downloaded or given up on
+pub fn remove_retry(
+    connection: &mut DbConnection,
+    item_id: &str,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::retry_queue::dsl::*;
+    diesel::delete(retry_queue.filter(id.eq(item_id))).execute(connection)?;
+    Ok(())
+}
+
+/// counts and sizes of the items in the database
+#[derive(Debug, Default)]
+pub struct MediaStats {
+    pub items: u64,
+    pub downloaded: u64,
+    /// Items in the dead letter table, which failed to download after every attempt
+    pub failed: u64,
+    /// Items still waiting to be downloaded, including those that will be retried
+    pub pending: u64,
+    /// Items skipped because they matched the configured exclusions
+    pub excluded: u64,
+    /// The total size of every downloaded item
+    pub bytes: u64,
+}
+
+/// summarise the items in the database, optionally only those created at or after `after` and
+/// before `before`. These are compared against google's RFC 3339 creation times, so a date such
+/// as `2020-01-01` can be used. Items without a creation time are excluded when filtering.
+pub fn media_stats(
+    connection: &mut DbConnection,
+    after: Option<&str>,
+    before: Option<&str>,
+) -> Result<MediaStats, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::media::dsl::*;
+
+    let mut query = media
+        .select((download_success, excluded, file_size))
+        .into_boxed();
+    if let Some(after) = after {
+        query = query.filter(creation_time.ge(after));
+    }
+    if let Some(before) = before {
+        query = query.filter(creation_time.lt(before));
+    }
+
+    let mut stats = MediaStats::default();
+    for (success, is_excluded, size) in query.load::<(bool, bool, Option<i64>)>(connection)? {
+        stats.items += 1;
+        if success {
+            stats.downloaded += 1;
+            stats.bytes += size.unwrap_or_default() as u64;
+        } else if is_excluded {
+            stats.excluded += 1;
+        } else {
+            stats.pending += 1;
+        }
+    }
+
+    let mut failed = crate::schema::dead_letter::table
+        .select(diesel::dsl::count_star())
+        .into_boxed();
+    if let Some(after) = after {
+        failed = failed.filter(crate::schema::dead_letter::creation_time.ge(after));
+    }
+    if let Some(before) = before {
+        failed = failed.filter(crate::schema::dead_letter::creation_time.lt(before));
+    }
+    stats.failed = failed.first::<i64>(connection)? as u64;
+    stats.items += stats.failed;
+
+    Ok(stats)
+}
+
+/// every column of a row in the media table
+#[derive(Debug, Queryable, Serialize)]
+pub struct MediaRow {
+    pub id: String,
+    pub description: Option<String>,
+    pub product_url: String,
+    pub base_url: String,
+    pub mime_type: Option<String>,
+    pub filename: String,
+    pub download_attempts: i32,
+    pub download_success: bool,
+    pub download_timestamp: String,
+    pub creation_time: Option<String>,
+    pub width: Option<String>,
+    pub height: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub focal_length: Option<f32>,
+    pub aperture: Option<f32>,
+    pub iso_equivalent: Option<i32>,
+    pub exposure_time: Option<String>,
+    pub fps: Option<f32>,
+    pub processing_status: Option<String>,
+    pub profile_picture_url: Option<String>,
+    pub display_name: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub file_path: Option<String>,
+    pub file_size: Option<i64>,
+    pub download_duration_ms: Option<i64>,
+    pub last_error: Option<String>,
+    pub excluded: bool,
+    pub sha256: Option<String>,
+}
+
+/// load the stored row for a media item, if there is one
+pub fn media_row(
+    connection: &mut DbConnection,
+    search_id: &str,
+) -> Result<Option<MediaRow>, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::media::dsl::*;
+    Ok(media
+        .find(search_id)
+        .first::<MediaRow>(connection)
+        .optional()?)
+}
+
+/// the id of every item in the database, and whether it has been backed up, either downloaded or
+/// deliberately excluded. Items that failed to download are included as not backed up
+pub fn media_ids(
+    connection: &mut DbConnection,
+) -> Result<Vec<(String, bool)>, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::{dead_letter, media::dsl::*};
+    use diesel::BoolExpressionMethods;
+    let mut ids = media
+        .select((id, download_success.or(excluded)))
+        .load::<(String, bool)>(connection)?;
+    let failed = dead_letter::table
+        .select(dead_letter::id)
+        .filter(dead_letter::id.ne_all(media.select(id)))
+        .load::<String>(connection)?;
+    ids.extend(failed.into_iter().map(|failed_id| (failed_id, false)));
+    Ok(ids)
+}
+
+/// the number of downloaded items with a recorded sha256
+pub fn hashed_count(
+    connection: &mut DbConnection,
+) -> Result<i64, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::media::dsl::*;
+    Ok(media
+        .filter(download_success.eq(true))
+        .filter(sha256.is_not_null())
+        .filter(file_path.is_not_null())
+        .count()
+        .get_result(connection)?)
+}
+
+/// A downloaded item's id, where it is stored and the sha256 recorded when it was downloaded
+pub type HashedItem = (String, String, String);
+
+/// the next downloaded item with a recorded sha256 in id order, after `after` and skipping `skip`
+/// more
+pub fn next_hashed_item(
+    connection: &mut DbConnection,
+    after: Option<&str>,
+    skip: i64,
+) -> Result<Option<HashedItem>, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::media::dsl::*;
+
+    let mut query = media
+        .filter(download_success.eq(true))
+        .filter(sha256.is_not_null())
+        .filter(file_path.is_not_null())
+        .select((id, file_path, sha256))
+        .order(id)
+        .offset(skip)
+        .limit(1)
+        .into_boxed();
+    if let Some(after) = after {
+        query = query.filter(id.gt(after));
+    }
+    Ok(query
+        .load::<(String, Option<String>, Option<String>)>(connection)?
+        .pop()
+        .and_then(|(item_id, path, hash)| Some((item_id, path?, hash?))))
+}
+
+/// check if a media item is present in the database, searching by id. Items aren't recorded per
+/// account, so this is true for every account once any of them has it
+pub fn in_database(
+    connection: &mut DbConnection,
+    search_id: &str,
+) -> Result<bool, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::{dead_letter, media::dsl::*};
+    let r: Vec<String> = media.select(id).filter(id.eq(search_id)).load(connection)?;
+    if !r.is_empty() {
+        return Ok(true);
+    }
+
+    // items that will never be downloaded count as present, so they aren't tried again
+    let r: Vec<String> = dead_letter::table
+        .select(dead_letter::id)
+        .filter(dead_letter::id.eq(search_id))
+        .load(connection)?;
+    Ok(!r.is_empty())
+}
+
+/// whether an item is recorded as downloaded or deliberately excluded, unlike `in_database` items
+/// that failed or were dead lettered don't count
+pub fn backed_up(
+    connection: &mut DbConnection,
+    search_id: &str,
+) -> Result<bool, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::media::dsl::*;
+    use diesel::BoolExpressionMethods;
+    let r: Vec<String> = media
+        .select(id)
+        .filter(id.eq(search_id))
+        .filter(download_success.or(excluded))
+        .load(connection)?;
+    Ok(!r.is_empty())
+}
+
+/// record a file found in the store path as an item's download. Metadata already saved for the
+/// item is kept, and the item is taken off the dead letter table and retry queue so it is not
+/// downloaded again
+pub fn record_found_file(
+    connection: &mut DbConnection,
+    media_item: &MediaItem,
+    outcome: &DownloadOutcome,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::media::dsl::*;
+
+    connection.transaction(|connection| {
+        let updated = diesel::update(media.find(&media_item.id))
+            .set((
+                download_success.eq(true),
+                file_path.eq(outcome.path.to_string_lossy().to_string()),
+                file_size.eq(outcome.bytes as i64),
+                last_error.eq(None::<String>),
+            ))
+            .execute(connection)?;
+        if updated == 0 {
+            save_media_item(connection, media_item, Some(outcome))?;
+        }
+        diesel::delete(crate::schema::dead_letter::table.find(&media_item.id))
+            .execute(connection)?;
+        remove_retry(connection, &media_item.id)?;
+        clear_attempts(connection, &media_item.id)?;
+        Ok(())
+    })
+}
+
+/// Read a TOML config file into the same key-value pairs as the config table, so its values are
+/// parsed and validated exactly as if they had been stored in the database. Keys are the lowercase
+/// names of the matching environment variables, lists may be written as arrays.
+fn read_config_file(
+    path: &Path,
+) -> Result<HashMap<String, String>, Box<dyn Error + Send + Sync + 'static>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("unable to read config file {:?}: {}", path, e))?;
+    let table: HashMap<String, toml::Value> =
+        toml::from_str(&contents).map_err(|e| format!("invalid config file {:?}: {}", path, e))?;
+
+    let value_to_string = |name: &str, value: toml::Value| match value {
+        toml::Value::String(s) => Ok(s),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        _ => Err(format!("unsupported value for {} in {:?}", name, path)),
+    };
+
+    let mut values = HashMap::with_capacity(table.len());
+    for (name, value) in table {
+        let value = match value {
+            toml::Value::Array(items) => items
+                .into_iter()
+                .map(|item| value_to_string(&name, item))
+                .collect::<Result<Vec<_>, _>>()?
+                .join(","),
+            value => value_to_string(&name, value)?,
+        };
+        values.insert(name, value);
+    }
+    Ok(values)
+}
+
+/// whether the config file at `path` sets `name`
+pub fn config_file_sets(
+    path: &Path,
+    name: &str,
+) -> Result<bool, Box<dyn Error + Send + Sync + 'static>> {
+    Ok(read_config_file(path)?.contains_key(name))
+}
+
+/// The config keys each account keeps for itself, additional accounts store them as
+/// `{account}.{key}`
+const ACCOUNT_KEYS: [&str; 5] = [
+    "authenticated",
+    "local_id",
+    "local_passcode",
+    "registered_address",
+    "initial_scan_complete",
+];
+
+/// load the config of the primary account, reading `config_file` over the database if given
+pub fn load_config(
+    connection: &mut DbConnection,
+    config_file: Option<&Path>,
+) -> Result<Config, Box<dyn Error + Send + Sync + 'static>> {
+    load_account_config(connection, None, config_file)
+}
+
+/// Load the config of an additional account, or of the primary account if None. Additional
+/// accounts share every setting but their own login, and store their media in a subfolder of the
+/// store path named after them.
+pub fn load_account_config(
+    connection: &mut DbConnection,
+    account: Option<&str>,
+    config_file: Option<&Path>,
+) -> Result<Config, Box<dyn Error + Send + Sync + 'static>> {
+    // load every row from the config table into a hashmap of key-value pairs
+    use crate::schema::config::dsl::*;
+    let mut r: HashMap<String, String> = config
+        .select((key, value))
+        .load::<(String, String)>(connection)?
+        .into_iter()
+        .collect();
+
+    // a config file overrides the database, but is itself overridden by the environment
+    if let Some(path) = config_file {
+        r.extend(read_config_file(path)?);
+    }
+
+    // an additional account's login is only ever its own, never the primary account's from the
+    // environment
+    if let Some(account) = account {
+        for name in ACCOUNT_KEYS {
+            match r.remove(&format!("{}.{}", account, name)) {
+                Some(v) => r.insert(name.to_string(), v),
+                None => r.remove(name),
+            };
+        }
+    }
+    let account_var = |name: &str| match account {
+        Some(_) => Err(std::env::VarError::NotPresent),
+        None => std::env::var(name),
+    };
+
+    // if a key exists in env, load that over trying to load from the database
+    // otherwise pull it from the database and pass that into the config
+    let mut store_path = match std::env::var("STORE_PATH") {
+        Ok(s) => PathBuf::from(s),
+        Err(_) => PathBuf::from(r.get("store_path").expect("store_path not found in config")),
+    };
+
+    let mut temp_path = match std::env::var("TEMP_PATH") {
+        Ok(s) => PathBuf::from(s),
+        Err(_) => PathBuf::from(r.get("temp_path").expect("temp_path not found in config")),
+    };
+
+    if let Some(account) = account {
+        store_path.push(account);
+        temp_path.push(account);
+    }
+
+    // if authenticated not present == false
+    let authenticated = match account_var("AUTHENTICATED") {
+        Ok(s) => s == "true",
+        Err(_) => r.get("authenticated").unwrap_or(&String::from("false")) == "true",
+    };
+
+    let local_id = match account_var("LOCAL_ID") {
+        Ok(s) => Some(s),
+        Err(_) => r.get("local_id").map(|s| s.to_string()),
+    };
+
+    let local_passcode = match account_var("LOCAL_PASSCODE") {
+        Ok(s) => Some(s),
+        Err(_) => r.get("local_passcode").map(|s| s.to_string()),
+    };
+
+    // a comma separated list of servers, tried in order
+    let webserver_addresses: Vec<String> = match std::env::var("WEBSERVER_ADDRESS") {
+        Ok(s) => s,
+        Err(_) => r.get("webserver_address").unwrap().to_string(),
+    }
+    .split(',')
+    .map(|s| s.trim().to_string())
+    .filter(|s| !s.is_empty())
+    .collect();
+    if webserver_addresses.is_empty() {
+        return Err("at least one webserver_address must be set".into());
+    }
+
+    let registered_address = match account_var("REGISTERED_ADDRESS") {
+        Ok(s) => Some(s),
+        Err(_) => r.get("registered_address").map(|s| s.to_string()),
+    };
+
+    let preshared_key = match std::env::var("PRESHARED_KEY") {
+        Ok(s) => s,
+        Err(_) => r.get("preshared_key").unwrap().to_string(),
+    };
+
+    let request_signing_key = match std::env::var("REQUEST_SIGNING_KEY") {
+        Ok(s) => Some(s),
+        Err(_) => r.get("request_signing_key").map(|s| s.to_string()),
+    };
+
+    let registration_seed = match std::env::var("REGISTRATION_SEED") {
+        Ok(s) => Some(s),
+        Err(_) => r.get("registration_seed").map(|s| s.to_string()),
+    };
+    if registration_seed
+        .as_ref()
+        .is_some_and(|seed| seed.is_empty())
+    {
+        return Err("registration_seed must not be empty".into());
+    }
+    // each account registers its own login, so each derives its own from the seed
+    let registration_seed = match account {
+        Some(account) => registration_seed.map(|seed| format!("{}\n{}", seed, account)),
+        None => registration_seed,
+    };
+
+    // a comma separated list of names for additional google accounts backed up by this client
+    let additional_accounts: Vec<String> = match account {
+        Some(_) => Vec::new(),
+        None => match std::env::var("ADDITIONAL_ACCOUNTS") {
+            Ok(s) => s,
+            Err(_) => r
+                .get("additional_accounts")
+                .map(|s| s.to_string())
+                .unwrap_or_default(),
+        }
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect(),
+    };
+    for (i, name) in additional_accounts.iter().enumerate() {
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "additional account {:?} may only contain letters, numbers, - and _",
+                name
+            )
+            .into());
+        }
+        if additional_accounts[..i].contains(name) {
+            return Err(format!("additional account {:?} is listed twice", name).into());
+        }
+    }
+
+    let initial_scan_complete = match account_var("INITIAL_SCAN_COMPLETE") {
+        Ok(s) => s == "true",
+        Err(_) => {
+            r.get("initial_scan_complete")
+                .unwrap_or(&String::from("false"))
+                == "true"
+        }
+    };
+    let initial_scan_complete = Mutex::new(initial_scan_complete);
+
+    let max_download_speed = match std::env::var("MAX_DOWNLOAD_SPEED") {
+        Ok(s) => s.parse::<u64>().unwrap(),
+        Err(_) => r
+            .get("max_download_speed")
+            .unwrap_or(&String::from("0"))
+            .parse::<u64>()
+            .unwrap(),
+    };
+
+    let download_order = match std::env::var("DOWNLOAD_ORDER") {
+        Ok(s) => s.parse::<DownloadOrder>()?,
+        Err(_) => match r.get("download_order") {
+            Some(s) => s.parse::<DownloadOrder>()?,
+            None => DownloadOrder::default(),
+        },
+    };
+
+    let exclude_patterns = match std::env::var("EXCLUDE_PATTERNS") {
+        Ok(s) => s,
+        Err(_) => r.get("exclude_patterns").cloned().unwrap_or_default(),
+    }
+    .split(',')
+    .map(|s| s.trim().to_string())
+    .filter(|s| !s.is_empty())
+    .collect::<Vec<_>>();
+
+    let exclude_mime_types = match std::env::var("EXCLUDE_MIME_TYPES") {
+        Ok(s) => s,
+        Err(_) => r.get("exclude_mime_types").cloned().unwrap_or_default(),
+    }
+    .split(',')
+    .map(|s| s.trim().to_string())
+    .filter(|s| !s.is_empty())
+    .collect::<Vec<_>>();
+
+    // a comma separated list of pattern=param rules, checked in order
+    let download_param_rules = match std::env::var("DOWNLOAD_PARAM_RULES") {
+        Ok(s) => s,
+        Err(_) => r
+            .get("download_param_rules")
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| DEFAULT_DOWNLOAD_PARAM_RULES.to_string()),
+    }
+    .split(',')
+    .filter(|s| !s.trim().is_empty())
+    .map(|s| s.parse::<DownloadParamRule>())
+    .collect::<Result<Vec<_>, _>>()?;
+
+    // used verbatim after the base url's `=`, so google's undocumented parameters can be tried
+    let photo_download_param = match std::env::var("PHOTO_DOWNLOAD_PARAM") {
+        Ok(s) => s,
+        Err(_) => r
+            .get("photo_download_param")
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| String::from("d")),
+    };
+    let video_download_param = match std::env::var("VIDEO_DOWNLOAD_PARAM") {
+        Ok(s) => s,
+        Err(_) => r
+            .get("video_download_param")
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| String::from("dv")),
+    };
+    for (name, param) in [
+        ("photo_download_param", &photo_download_param),
+        ("video_download_param", &video_download_param),
+    ] {
+        if param.is_empty() {
+            return Err(format!("{} must not be empty", name).into());
+        }
+        if param.contains(|c: char| c.is_whitespace() || "=?#/".contains(c)) {
+            return Err(format!("{} must not contain whitespace, =, ?, # or /", name).into());
+        }
+    }
+
+    let db_writer_threads = match std::env::var("DB_WRITER_THREADS") {
+        Ok(s) => s.parse::<u32>()?,
+        Err(_) => r
+            .get("db_writer_threads")
+            .unwrap_or(&String::from("4"))
+            .parse::<u32>()?,
+    };
+    if db_writer_threads == 0 {
+        return Err("db_writer_threads must be at least 1".into());
+    }
+
+    let attempt_grace_secs = match std::env::var("ATTEMPT_GRACE_SECS") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("attempt_grace_secs")
+            .unwrap_or(&String::from("0"))
+            .parse::<u64>()?,
+    };
+
+    let skip_if_present = match std::env::var("SKIP_IF_PRESENT") {
+        Ok(s) => s.parse::<SkipIfPresent>()?,
+        Err(_) => match r.get("skip_if_present") {
+            Some(s) => s.parse::<SkipIfPresent>()?,
+            None => SkipIfPresent::default(),
+        },
+    };
+
+    let keep_failed_temp = match std::env::var("KEEP_FAILED_TEMP") {
+        Ok(s) => s == "true",
+        Err(_) => r.get("keep_failed_temp").unwrap_or(&String::from("false")) == "true",
+    };
+
+    let max_parse_failures = match std::env::var("MAX_PARSE_FAILURES") {
+        Ok(s) => s.parse::<u32>()?,
+        Err(_) => r
+            .get("max_parse_failures")
+            .unwrap_or(&String::from("10"))
+            .parse::<u32>()?,
+    };
+
+    let page_size = match std::env::var("PAGE_SIZE") {
+        Ok(s) => Some(s.parse::<u8>()?),
+        Err(_) => match r.get("page_size") {
+            Some(s) => Some(s.parse::<u8>()?),
+            None => None,
+        },
+    };
+    if page_size.is_some_and(|size| size == 0 || size > MAX_PAGE_SIZE) {
+        return Err(format!("page_size must be between 1 and {}", MAX_PAGE_SIZE).into());
+    }
+
+    let download_contributor_avatars = match std::env::var("DOWNLOAD_CONTRIBUTOR_AVATARS") {
+        Ok(s) => s == "true",
+        Err(_) => {
+            r.get("download_contributor_avatars")
+                .unwrap_or(&String::from("false"))
+                == "true"
+        }
+    };
+
+    let sprite_sheet_interval_secs = match std::env::var("SPRITE_SHEET_INTERVAL_SECS") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("sprite_sheet_interval_secs")
+            .unwrap_or(&String::from("0"))
+            .parse::<u64>()?,
+    };
+
+    let integrity_check_rate = match std::env::var("INTEGRITY_CHECK_RATE") {
+        Ok(s) => s.parse::<u32>()?,
+        Err(_) => r
+            .get("integrity_check_rate")
+            .unwrap_or(&String::from("0"))
+            .parse::<u32>()?,
+    };
+
+    let integrity_webhook = match std::env::var("INTEGRITY_WEBHOOK") {
+        Ok(s) => Some(s),
+        Err(_) => r.get("integrity_webhook").map(|s| s.to_string()),
+    };
+
+    let sprite_sheet_items = match std::env::var("SPRITE_SHEET_ITEMS") {
+        Ok(s) => s.parse::<u32>()?,
+        Err(_) => r
+            .get("sprite_sheet_items")
+            .unwrap_or(&String::from("400"))
+            .parse::<u32>()?,
+    };
+    if sprite_sheet_items == 0 || sprite_sheet_items > MAX_SPRITE_ITEMS {
+        return Err(format!(
+            "sprite_sheet_items must be between 1 and {}",
+            MAX_SPRITE_ITEMS
+        )
+        .into());
+    }
+
+    let embed_exif = match std::env::var("EMBED_EXIF") {
+        Ok(s) => s == "true",
+        Err(_) => r.get("embed_exif").unwrap_or(&String::from("false")) == "true",
+    };
+
+    let content_addressed = match std::env::var("CONTENT_ADDRESSED") {
+        Ok(s) => s == "true",
+        Err(_) => r.get("content_addressed").unwrap_or(&String::from("false")) == "true",
+    };
+    // writing EXIF into a file changes its content, so it would no longer match its hash
+    if content_addressed && embed_exif {
+        return Err("content_addressed can't be used with embed_exif".into());
+    }
+
+    // files are written to after they are hashed, so they would never match
+    if integrity_check_rate > 0 && embed_exif {
+        return Err("integrity_check_rate can't be used with embed_exif".into());
+    }
+    // embedding exif changes the file after its hash is taken
+    if skip_if_present == SkipIfPresent::Hash && embed_exif {
+        return Err("skip_if_present can't be hash with embed_exif".into());
+    }
+
+    let skip_metadata = match std::env::var("SKIP_METADATA") {
+        Ok(s) => s == "true",
+        Err(_) => r.get("skip_metadata").unwrap_or(&String::from("false")) == "true",
+    };
+
+    let include_archived_media = match std::env::var("INCLUDE_ARCHIVED_MEDIA") {
+        Ok(s) => s == "true",
+        Err(_) => {
+            r.get("include_archived_media")
+                .unwrap_or(&String::from("false"))
+                == "true"
+        }
+    };
+
+    let auth_failure_threshold = match std::env::var("AUTH_FAILURE_THRESHOLD") {
+        Ok(s) => s.parse::<u32>()?,
+        Err(_) => r
+            .get("auth_failure_threshold")
+            .unwrap_or(&String::from("5"))
+            .parse::<u32>()?,
+    };
+    if auth_failure_threshold == 0 {
+        return Err("auth_failure_threshold must be at least 1".into());
+    }
+
+    let auth_failure_action = match std::env::var("AUTH_FAILURE_ACTION") {
+        Ok(s) => s.parse::<AuthFailureAction>()?,
+        Err(_) => match r.get("auth_failure_action") {
+            Some(s) => s.parse::<AuthFailureAction>()?,
+            None => AuthFailureAction::default(),
+        },
+    };
+
+    let auth_failure_webhook = match std::env::var("AUTH_FAILURE_WEBHOOK") {
+        Ok(s) => Some(s),
+        Err(_) => r.get("auth_failure_webhook").map(|s| s.to_string()),
+    };
+    if auth_failure_action == AuthFailureAction::Webhook && auth_failure_webhook.is_none() {
+        return Err("auth_failure_webhook must be set when auth_failure_action is webhook".into());
+    }
+
+    let min_free_bytes = match std::env::var("MIN_FREE_BYTES") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("min_free_bytes")
+            .unwrap_or(&String::from("0"))
+            .parse::<u64>()?,
+    };
+
+    let free_space_poll_interval_secs = match std::env::var("FREE_SPACE_POLL_INTERVAL_SECS") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("free_space_poll_interval_secs")
+            .unwrap_or(&String::from("60"))
+            .parse::<u64>()?,
+    };
+    if free_space_poll_interval_secs == 0 {
+        return Err("free_space_poll_interval_secs must be at least 1".into());
+    }
+
+    let large_file_threshold = match std::env::var("LARGE_FILE_THRESHOLD") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("large_file_threshold")
+            .unwrap_or(&String::from("0"))
+            .parse::<u64>()?,
+    };
+
+    let idle_rescan_interval_secs = match std::env::var("IDLE_RESCAN_INTERVAL_SECS") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("idle_rescan_interval_secs")
+            .unwrap_or(&String::from("1800"))
+            .parse::<u64>()?,
+    };
+    if idle_rescan_interval_secs == 0 {
+        return Err("idle_rescan_interval_secs must be at least 1".into());
+    }
+
+    let max_scan_pages_per_run = match std::env::var("MAX_SCAN_PAGES_PER_RUN") {
+        Ok(s) => s.parse::<u32>()?,
+        Err(_) => r
+            .get("max_scan_pages_per_run")
+            .unwrap_or(&String::from("0"))
+            .parse::<u32>()?,
+    };
+
+    let scan_budget_interval_secs = match std::env::var("SCAN_BUDGET_INTERVAL_SECS") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("scan_budget_interval_secs")
+            .unwrap_or(&String::from("86400"))
+            .parse::<u64>()?,
+    };
+    if scan_budget_interval_secs == 0 {
+        return Err("scan_budget_interval_secs must be at least 1".into());
+    }
+
+    let prefetch_pages = match std::env::var("PREFETCH_PAGES") {
+        Ok(s) => s.parse::<u32>()?,
+        Err(_) => r
+            .get("prefetch_pages")
+            .unwrap_or(&String::from("1"))
+            .parse::<u32>()?,
+    };
+    if prefetch_pages == 0 {
+        return Err("prefetch_pages must be at least 1".into());
+    }
+
+    let baseurl_reload_interval_secs = match std::env::var("BASEURL_RELOAD_INTERVAL_SECS") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("baseurl_reload_interval_secs")
+            .unwrap_or(&String::from("3300"))
+            .parse::<u64>()?,
+    };
+    if baseurl_reload_interval_secs == 0 {
+        return Err("baseurl_reload_interval_secs must be at least 1".into());
+    }
+
+    let photo_download_timeout_secs = match std::env::var("PHOTO_DOWNLOAD_TIMEOUT_SECS") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("photo_download_timeout_secs")
+            .unwrap_or(&String::from("600"))
+            .parse::<u64>()?,
+    };
+    if photo_download_timeout_secs == 0 {
+        return Err("photo_download_timeout_secs must be at least 1".into());
+    }
+
+    let video_download_timeout_secs = match std::env::var("VIDEO_DOWNLOAD_TIMEOUT_SECS") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("video_download_timeout_secs")
+            .unwrap_or(&String::from("7200"))
+            .parse::<u64>()?,
+    };
+    if video_download_timeout_secs == 0 {
+        return Err("video_download_timeout_secs must be at least 1".into());
+    }
+
+    let download_stall_timeout_secs = match std::env::var("DOWNLOAD_STALL_TIMEOUT_SECS") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("download_stall_timeout_secs")
+            .unwrap_or(&String::from("60"))
+            .parse::<u64>()?,
+    };
+    if download_stall_timeout_secs == 0 {
+        return Err("download_stall_timeout_secs must be at least 1".into());
+    }
+
+    let sqlite_journal_mode = match std::env::var("SQLITE_JOURNAL_MODE") {
+        Ok(s) => s,
+        Err(_) => r
+            .get("sqlite_journal_mode")
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| String::from("WAL")),
+    }
+    .to_uppercase();
+    if !["DELETE", "TRUNCATE", "PERSIST", "MEMORY", "WAL", "OFF"]
+        .contains(&sqlite_journal_mode.as_str())
+    {
+        return Err(format!(
+            "invalid sqlite_journal_mode '{}', expected one of DELETE, TRUNCATE, PERSIST, MEMORY, WAL, OFF",
+            sqlite_journal_mode
+        )
+        .into());
+    }
+
+    let sqlite_synchronous = match std::env::var("SQLITE_SYNCHRONOUS") {
+        Ok(s) => s,
+        Err(_) => r
+            .get("sqlite_synchronous")
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| String::from("NORMAL")),
+    }
+    .to_uppercase();
+    if !["OFF", "NORMAL", "FULL", "EXTRA"].contains(&sqlite_synchronous.as_str()) {
+        return Err(format!(
+            "invalid sqlite_synchronous '{}', expected one of OFF, NORMAL, FULL, EXTRA",
+            sqlite_synchronous
+        )
+        .into());
+    }
+
+    let sqlite_busy_timeout_ms = match std::env::var("SQLITE_BUSY_TIMEOUT_MS") {
+        Ok(s) => s.parse::<u32>()?,
+        Err(_) => r
+            .get("sqlite_busy_timeout_ms")
+            .unwrap_or(&DEFAULT_BUSY_TIMEOUT_MS.to_string())
+            .parse::<u32>()?,
+    };
+
+    let inter_download_delay_ms = match std::env::var("INTER_DOWNLOAD_DELAY_MS") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("inter_download_delay_ms")
+            .unwrap_or(&String::from("0"))
+            .parse::<u64>()?,
+    };
+
+    let inter_download_jitter_ms = match std::env::var("INTER_DOWNLOAD_JITTER_MS") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("inter_download_jitter_ms")
+            .unwrap_or(&String::from("0"))
+            .parse::<u64>()?,
+    };
+
+    let post_download_command = match std::env::var("POST_DOWNLOAD_COMMAND") {
+        Ok(s) => Some(s),
+        Err(_) => r.get("post_download_command").map(|s| s.to_string()),
+    }
+    .filter(|command| !command.trim().is_empty());
+
+    let post_download_timeout_secs = match std::env::var("POST_DOWNLOAD_TIMEOUT_SECS") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("post_download_timeout_secs")
+            .unwrap_or(&String::from("60"))
+            .parse::<u64>()?,
+    };
+    if post_download_timeout_secs == 0 {
+        return Err("post_download_timeout_secs must be at least 1".into());
+    }
+
+    let worker_stall_timeout_secs = match std::env::var("WORKER_STALL_TIMEOUT_SECS") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("worker_stall_timeout_secs")
+            .unwrap_or(&String::from("1800"))
+            .parse::<u64>()?,
+    };
+    // the post download command runs without the lane reporting, so it must be allowed to finish
+    if worker_stall_timeout_secs != 0 && worker_stall_timeout_secs <= post_download_timeout_secs {
+        return Err(
+            "worker_stall_timeout_secs must be longer than post_download_timeout_secs".into(),
+        );
+    }
+
+    let max_api_concurrency = match std::env::var("MAX_API_CONCURRENCY") {
+        Ok(s) => s.parse::<u32>()?,
+        Err(_) => match r.get("max_api_concurrency") {
+            Some(s) => s.parse::<u32>()?,
+            None => DEFAULT_MAX_API_CONCURRENCY,
+        },
+    };
+    if max_api_concurrency == 0 {
+        return Err("max_api_concurrency must be at least 1".into());
+    }
+
+    // only the primary account serves the status endpoints, they would conflict otherwise
+    let status_address = match std::env::var("STATUS_ADDRESS") {
+        Ok(s) => Some(s.parse::<SocketAddr>()?),
+        Err(_) => match r.get("status_address") {
+            Some(s) => Some(s.parse::<SocketAddr>()?),
+            None => None,
+        },
+    }
+    .filter(|_| account.is_none());
+
+    let user_agent = match std::env::var("USER_AGENT") {
+        Ok(s) => s,
+        Err(_) => r
+            .get("user_agent")
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| DEFAULT_USER_AGENT.to_string()),
+    };
+    if user_agent.is_empty() {
+        return Err("user_agent must not be empty".into());
+    }
+
+    let pool_max_idle_per_host = match std::env::var("POOL_MAX_IDLE_PER_HOST") {
+        Ok(s) => s.parse::<usize>()?,
+        Err(_) => r
+            .get("pool_max_idle_per_host")
+            .unwrap_or(&String::from("32"))
+            .parse::<usize>()?,
+    };
+
+    let pool_idle_timeout_secs = match std::env::var("POOL_IDLE_TIMEOUT_SECS") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("pool_idle_timeout_secs")
+            .unwrap_or(&String::from("90"))
+            .parse::<u64>()?,
+    };
+
+    let tcp_keepalive_secs = match std::env::var("TCP_KEEPALIVE_SECS") {
+        Ok(s) => s.parse::<u64>()?,
+        Err(_) => r
+            .get("tcp_keepalive_secs")
+            .unwrap_or(&String::from("60"))
+            .parse::<u64>()?,
+    };
+
+    let http_version = match std::env::var("HTTP_VERSION") {
+        Ok(s) => s.parse::<HttpVersion>()?,
+        Err(_) => match r.get("http_version") {
+            Some(s) => s.parse::<HttpVersion>()?,
+            None => HttpVersion::default(),
+        },
+    };
+
+    Ok(Config {
+        account: account.map(|s| s.to_string()),
+        additional_accounts,
+        store_path,
+        authenticated,
+        local_id,
+        local_passcode,
+        webserver_addresses,
+        registered_address,
+        preshared_key,
+        request_signing_key,
+        registration_seed,
+        initial_scan_complete,
+        temp_path,
+        max_download_speed,
+        download_order,
+        download_param_rules,
+        photo_download_param,
+        video_download_param,
+        exclude_patterns,
+        exclude_mime_types,
+        db_writer_threads,
+        attempt_grace_secs,
+        skip_if_present,
+        keep_failed_temp,
+        embed_exif,
+        content_addressed,
+        download_contributor_avatars,
+        sprite_sheet_interval_secs,
+        sprite_sheet_items,
+        integrity_check_rate,
+        integrity_webhook,
+        skip_metadata,
+        include_archived_media,
+        start_token: None,
+        max_runtime: None,
+        config_file: config_file.map(Path::to_path_buf),
+        max_parse_failures,
+        page_size,
+        auth_failure_threshold,
+        auth_failure_action,
+        auth_failure_webhook,
+        min_free_bytes,
+        free_space_poll_interval_secs,
+        user_agent,
+        pool_max_idle_per_host,
+        pool_idle_timeout_secs,
+        tcp_keepalive_secs,
+        http_version,
+        large_file_threshold,
+        idle_rescan_interval_secs,
+        max_scan_pages_per_run,
+        scan_budget_interval_secs,
+        prefetch_pages,
+        baseurl_reload_interval_secs,
+        photo_download_timeout_secs,
+        video_download_timeout_secs,
+        download_stall_timeout_secs,
+        sqlite_journal_mode,
+        sqlite_synchronous,
+        sqlite_busy_timeout_ms,
+        status_address,
+        inter_download_delay_ms,
+        inter_download_jitter_ms,
+        post_download_command,
+        post_download_timeout_secs,
+        worker_stall_timeout_secs,
+        max_api_concurrency,
+        api_permits: ApiPermits::new(max_api_concurrency),
+    })
+}
+
+/// delete every row from the media and dead letter tables, returning how many were deleted, and
+/// forget the saved download queue and attempts
+pub fn clear_media(
+    connection: &mut DbConnection,
+) -> Result<usize, Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::{attempts, dead_letter, download_queue, media::dsl::*, retry_queue};
+    diesel::delete(download_queue::table).execute(connection)?;
+    diesel::delete(retry_queue::table).execute(connection)?;
+    diesel::delete(attempts::table).execute(connection)?;
+    Ok(diesel::delete(media).execute(connection)?
+        + diesel::delete(dead_letter::table).execute(connection)?)
+}
+
+/// mark the initial scan as incomplete, and if `credentials` is set forget this client's
+/// registration and google login so the next run registers again
+pub fn reset_local_state(
+    connection: &mut DbConnection,
+    credentials: bool,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::config::dsl::*;
+    use diesel::{BoolExpressionMethods, TextExpressionMethods};
+
+    // additional accounts keep their own copies of these keys, prefixed with the account name
+    let with_accounts = |name: &str| key.eq(name.to_string()).or(key.like(format!("%.{}", name)));
+
+    diesel::update(config.filter(with_accounts("initial_scan_complete")))
+        .set(value.eq("false"))
+        .execute(connection)?;
+
+    if credentials {
+        for name in [
+            "authenticated",
+            "local_id",
+            "local_passcode",
+            "registered_address",
+        ] {
+            diesel::delete(config.filter(with_accounts(name))).execute(connection)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn save_config(
+    connection: &mut DbConnection,
+    save_config: &Config,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    use crate::schema::config::dsl::*;
+
+    // convert the config struct into a hashmap of key-value pairs
+    let authenticated = save_config.authenticated.to_string();
+    let initial_scan_complete = save_config
+        .initial_scan_complete
+        .lock()
+        .unwrap()
+        .to_string();
+    let webserver_address = save_config.webserver_addresses.join(",");
+    let mut r = vec![
+        ("authenticated", authenticated.as_str()),
+        ("initial_scan_complete", &initial_scan_complete),
+    ];
+
+    // additional accounts only save their own login, the rest is shared with the primary account
+    if save_config.account.is_none() {
+        r.push(("store_path", save_config.store_path.to_str().unwrap()));
+        r.push(("webserver_address", &webserver_address));
+        r.push(("preshared_key", &save_config.preshared_key));
+    }
+
+    if let Some(local_id) = &save_config.local_id {
+        r.push(("local_id", local_id));
+    }
+
+    if let Some(local_passcode) = &save_config.local_passcode {
+        r.push(("local_passcode", local_passcode));
+    }
+
+    if let Some(registered_address) = &save_config.registered_address {
+        r.push(("registered_address", registered_address));
+    }
+
+    // insert with each field specified manually
+    for (d_key, d_value) in r.into_iter() {
+        let d_key = match &save_config.account {
+            Some(account) => format!("{}.{}", account, d_key),
+            None => d_key.to_string(),
+        };
+        diesel::insert_into(config)
+            .values((key.eq(d_key), value.eq(d_value)))
+            // on conflict, replace all fields
+            .on_conflict(key)
+            .do_update()
+            .set(value.eq(d_value))
+            .execute(connection)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        due_retries, failed_media_items, load_queue, media_ids, record_attempt, recorded_attempts,
+        retry_dead_letter, save_dead_letter, save_queue, save_retry,
+    };
+    use crate::test_utils::{connection, item};
+
+    #[test]
+    fn test_rate_limited_attempt_is_not_recorded() {
+        let mut connection = connection();
+        record_attempt(&mut connection, "limited", 2).unwrap();
+
+        // the attempt that was rate limited is taken back before the item is saved for a retry
+        let limited = item("limited", 1);
+        save_retry(&mut connection, "", &limited, 0).unwrap();
+
+        let recorded = recorded_attempts(&mut connection, &["limited"], 0).unwrap();
+        assert_eq!(recorded.get("limited"), Some(&1));
+    }
+
+    #[test]
+    fn test_retried_dead_letter_is_queued_with_its_attempts_reset() {
+        let mut connection = connection();
+        let failed = item("failed", 5);
+        record_attempt(&mut connection, "failed", 5).unwrap();
+        save_dead_letter(&mut connection, "work", &failed).unwrap();
+
+        assert_eq!(retry_dead_letter(&mut connection).unwrap(), 1);
+        assert!(failed_media_items(&mut connection).unwrap().is_empty());
+        assert!(due_retries(&mut connection, "", 0).unwrap().is_empty());
+        let due = due_retries(&mut connection, "work", 0).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, "failed");
+        assert_eq!(due[0].download_attempts, 0);
+        let recorded = recorded_attempts(&mut connection, &["failed"], 0).unwrap();
+        assert_eq!(recorded.get("failed").copied().unwrap_or_default(), 0);
+    }
+
+    #[test]
+    fn test_dead_lettered_item_is_not_backed_up() {
+        let mut connection = connection();
+        save_dead_letter(&mut connection, "", &item("failed", 5)).unwrap();
+
+        assert_eq!(
+            media_ids(&mut connection).unwrap(),
+            vec![(String::from("failed"), false)]
+        );
+    }
+
+    #[test]
+    fn test_reindexed_dead_lettered_item_is_backed_up() {
+        let mut connection = connection();
+        let id = "a".repeat(40);
+        save_dead_letter(&mut connection, "", &item(&id, 5)).unwrap();
+
+        let store = tempfile::tempdir().unwrap();
+        std::fs::write(store.path().join(&id), b"image").unwrap();
+        let summary = crate::reindex::reindex(&mut connection, store.path()).unwrap();
+
+        assert_eq!(summary.added, 1);
+        assert!(failed_media_items(&mut connection).unwrap().is_empty());
+        assert_eq!(media_ids(&mut connection).unwrap(), vec![(id, true)]);
+    }
+
+    #[test]
+    fn test_accounts_save_separate_queues() {
+        let mut connection = connection();
+        save_queue(&mut connection, "", &[item("shared", 0)], 1).unwrap();
+        save_queue(&mut connection, "work", &[item("shared", 0)], 2).unwrap();
+
+        for (account, loaded) in [("", 1), ("work", 2)] {
+            let queue = load_queue(&mut connection, account).unwrap();
+            assert_eq!(queue.len(), 1);
+            assert_eq!(queue[0].0.id, "shared");
+            assert_eq!(queue[0].1, loaded);
+        }
+    }
+}
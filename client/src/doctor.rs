@@ -0,0 +1,304 @@
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::Client;
+
+use crate::{
+    config::Config,
+    database::{self, DbConnection},
+    media,
+    page_size::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE},
+};
+
+/// How long to wait on each network check before it is considered failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Collects the result of each check, printing them as they complete
+#[derive(Default)]
+struct Checklist {
+    failed: bool,
+}
+
+impl Checklist {
+    fn pass(&mut self, check: &str) {
+        println!("[PASS] {}", check);
+    }
+
+    fn fail<E: std::fmt::Display>(&mut self, check: &str, reason: E) {
+        self.failed = true;
+        println!("[FAIL] {}: {}", check, reason);
+    }
+
+    fn skip(&mut self, check: &str, reason: &str) {
+        println!("[SKIP] {}: {}", check, reason);
+    }
+
+    fn record<T, E: std::fmt::Display>(&mut self, check: &str, result: Result<T, E>) -> bool {
+        match result {
+            Ok(_) => {
+                self.pass(check);
+                true
+            }
+            Err(e) => {
+                self.fail(check, e);
+                false
+            }
+        }
+    }
+}
+
+/// check that a directory exists (creating it if needed) and that we can write a file into it
+fn check_writable(path: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(path)?;
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let probe = path.join(format!(".syncabull-doctor-{}", nanos));
+    std::fs::write(&probe, b"syncabull")?;
+    std::fs::remove_file(&probe)
+}
+
+/// check that the server answers http requests at all, any status is accepted
+async fn check_reachable(
+    agent: &Client,
+    address: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    agent
+        .get(format!("{}/is_logged_in", address))
+        .timeout(CHECK_TIMEOUT)
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Run every diagnostic check, printing a pass/fail checklist. Returns false if any check failed.
+///
+/// The only change this makes is registering the client if it has not been registered yet,
+/// exactly as a normal run would, as that is the only way to check the preshared key.
+pub async fn run(connection: &mut DbConnection, config_file: Option<&Path>) -> bool {
+    let mut checklist = Checklist::default();
+
+    let mut config: Config = match database::load_config(connection, config_file) {
+        Ok(config) => {
+            checklist.pass("config loads and is valid");
+            config
+        }
+        Err(e) => {
+            checklist.fail("config loads and is valid", e);
+            return false;
+        }
+    };
+    let agent = crate::agent(&config);
+
+    let result = check_writable(&config.store_path);
+    checklist.record(
+        &format!("store path {:?} is writable", config.store_path),
+        result,
+    );
+    let result = check_writable(&config.temp_path);
+    checklist.record(
+        &format!("temp path {:?} is writable", config.temp_path),
+        result,
+    );
+
+    let mut reachable = false;
+    for address in &config.webserver_addresses {
+        let result = check_reachable(&agent, address).await;
+        reachable |= checklist.record(&format!("api {} is reachable", address), result);
+    }
+    if !reachable {
+        checklist.skip("preshared key is accepted", "no api is reachable");
+        checklist.skip("credentials authenticate", "no api is reachable");
+        checklist.skip("a page of media can be fetched", "no api is reachable");
+        return false;
+    }
+
+    if config.local_id.is_none() {
+        match media::register(&config, &agent).await {
+            Ok((id, passcode, address)) => {
+                checklist.pass("preshared key is accepted");
+                config.local_id = Some(id);
+                config.local_passcode = Some(passcode);
+                config.registered_address = Some(address);
+                if let Err(e) = config.save(connection) {
+                    checklist.fail("saving the new registration", e);
+                }
+            }
+            Err(e) => {
+                checklist.fail("preshared key is accepted", e);
+                checklist.skip("credentials authenticate", "client is not registered");
+                checklist.skip("a page of media can be fetched", "client is not registered");
+                return false;
+            }
+        }
+    } else {
+        checklist.skip(
+            "preshared key is accepted",
+            "client is already registered, the key is only used when registering",
+        );
+    }
+
+    if !config.authenticated {
+        checklist.fail(
+            "credentials authenticate",
+            "no google account is linked yet, run the client to link one",
+        );
+        checklist.skip(
+            "a page of media can be fetched",
+            "client is not authenticated",
+        );
+        return false;
+    }
+
+    // the api holds this request open until a google login exists, so a timeout means the api
+    // accepted our credentials but has no google account for us
+    let result = match tokio::time::timeout(
+        CHECK_TIMEOUT,
+        media::await_user_authentication(&config, &agent),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(_) => Err("the api has no google account linked to this client".into()),
+    };
+    if !checklist.record("credentials authenticate", result) {
+        checklist.skip(
+            "a page of media can be fetched",
+            "credentials were rejected",
+        );
+        return false;
+    }
+
+    match media::token_status(&config, &agent).await {
+        Ok(status) if status.needs_reauth => checklist.fail(
+            "google account is linked",
+            "google rejected the login, run the client to link it again",
+        ),
+        Ok(status) if status.linked => checklist.pass(&format!(
+            "google account is linked (token expires in {} seconds)",
+            status.expires_in_secs.unwrap_or_default()
+        )),
+        Ok(_) => checklist.fail("google account is linked", "the api has no google login"),
+        Err(e) => checklist.fail("google account is linked", e),
+    }
+
+    // reload the previous page rather than requesting the next one, so the scan position is
+    // left where it was
+    let page_size = config.page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+    let result = media::get_media_items(&config, &agent, true, page_size, None).await;
+    checklist.record("a page of media can be fetched", result);
+
+    !checklist.failed
+}
+
+/// Check that this client's config works with the api it talks to, printing the api's version,
+/// features and the page size that will be used. Returns false on any incompatibility.
+///
+/// Unlike `doctor` this never registers the client, so it changes nothing on either side.
+pub async fn check_api(connection: &mut DbConnection, config_file: Option<&Path>) -> bool {
+    let mut checklist = Checklist::default();
+
+    let config: Config = match database::load_config(connection, config_file) {
+        Ok(config) => {
+            checklist.pass("config loads and is valid");
+            config
+        }
+        Err(e) => {
+            checklist.fail("config loads and is valid", e);
+            return false;
+        }
+    };
+    let agent = crate::agent(&config);
+
+    let capabilities = match media::capabilities(&config, &agent).await {
+        Ok(Some(capabilities)) => {
+            checklist.pass("api reports its capabilities");
+            capabilities
+        }
+        Ok(None) => {
+            checklist.fail(
+                "api reports its capabilities",
+                "the api is too old to support /capabilities",
+            );
+            return false;
+        }
+        Err(e) => {
+            checklist.fail("api reports its capabilities", e);
+            return false;
+        }
+    };
+    println!("api version: {}", capabilities.version);
+    println!("api features: {}", capabilities.features.join(", "));
+
+    match config.page_size {
+        Some(size) if size > capabilities.max_page_size => checklist.fail(
+            "page size is supported",
+            format!(
+                "page_size is {} but the api returns at most {} items a page",
+                size, capabilities.max_page_size
+            ),
+        ),
+        Some(size) => checklist.pass(&format!("page size is supported (fixed at {})", size)),
+        None => checklist.pass(&format!(
+            "page size is supported (adapts from {}, up to {})",
+            DEFAULT_PAGE_SIZE.min(capabilities.max_page_size),
+            MAX_PAGE_SIZE.min(capabilities.max_page_size)
+        )),
+    }
+
+    // features only some configs rely on
+    let required = [
+        (
+            "media",
+            config.large_file_threshold > 0,
+            "large_file_threshold is set",
+        ),
+        (
+            "seeded_registration",
+            config.registration_seed.is_some(),
+            "registration_seed is set",
+        ),
+        (
+            "last_page",
+            !config.initial_scan_complete(),
+            "the initial scan only completes once the api reports the last page",
+        ),
+    ];
+    for (feature, _, reason) in required.iter().filter(|(_, required, _)| *required) {
+        let check = format!("api supports {}, as {}", feature, reason);
+        match capabilities.features.iter().any(|f| f == feature) {
+            true => checklist.pass(&check),
+            false => checklist.fail(&check, "the api doesn't support it"),
+        }
+    }
+
+    if capabilities.features.iter().any(|f| f == "psk_check") {
+        let result = media::check_psk(&config, &agent).await;
+        checklist.record("preshared key is accepted", result);
+    } else {
+        checklist.skip(
+            "preshared key is accepted",
+            "the api can only check the key by registering",
+        );
+    }
+
+    if config.local_id.is_none() {
+        checklist.skip("credentials authenticate", "client is not registered yet");
+    } else {
+        match media::token_status(&config, &agent).await {
+            Ok(status) => {
+                checklist.pass("credentials authenticate");
+                if !status.linked || status.needs_reauth {
+                    println!("no usable google account is linked, run the client to link one");
+                }
+            }
+            Err(e) => checklist.fail("credentials authenticate", e),
+        }
+    }
+
+    !checklist.failed
+}
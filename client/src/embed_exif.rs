@@ -0,0 +1,212 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, Cursor},
+    path::Path,
+};
+
+use exif::{experimental::Writer, DateTime, Field, In, Reader, Tag, Value};
+use log::{debug, warn};
+use shared_libs::json_templates::{MediaItem, MediaMetadata};
+
+/// EXIF times are local to where the photo was taken while google's are UTC, so they may differ
+/// by up to the largest timezone offset
+const MAX_TIMEZONE_OFFSET_SECS: i64 = 14 * 60 * 60;
+
+/// The identifier at the start of a JPEG APP1 segment holding EXIF data
+const EXIF_ID: &[u8] = b"Exif\0\0";
+
+/// the first value of an ascii field, without padding
+fn ascii(field: &Field) -> Option<String> {
+    match field.value {
+        Value::Ascii(ref values) => values
+            .first()
+            .map(|value| String::from_utf8_lossy(value).trim().to_string()),
+        _ => None,
+    }
+}
+
+/// rewrite google's RFC 3339 creation time, e.g. `2020-05-03T12:34:56.123Z`, into the EXIF
+/// format, `2020:05:03 12:34:56`
+fn exif_time(creation_time: &str) -> Option<Vec<u8>> {
+    let mut data = creation_time.as_bytes().get(..19)?.to_vec();
+    data[4] = b':';
+    data[7] = b':';
+    data[10] = b' ';
+    DateTime::from_ascii(&data).ok()?;
+    Some(data)
+}
+
+/// seconds since the unix epoch, ignoring any timezone
+fn epoch_secs(time: &DateTime) -> i64 {
+    // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let (month, day) = (time.month as i64, time.day as i64);
+    let year = time.year as i64 - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    days * 86400 + time.hour as i64 * 3600 + time.minute as i64 * 60 + time.second as i64
+}
+
+fn camera(metadata: &MediaMetadata) -> (Option<&String>, Option<&String>) {
+    match (&metadata.photo, &metadata.video) {
+        (Some(photo), _) => (photo.cameraMake.as_ref(), photo.cameraModel.as_ref()),
+        (None, Some(video)) => (video.cameraMake.as_ref(), video.cameraModel.as_ref()),
+        (None, None) => (None, None),
+    }
+}
+
+/// warn about any key fields in a file's EXIF that disagree with google's metadata
+fn compare(item: &MediaItem, metadata: &MediaMetadata, exif: &exif::Exif) {
+    let (make, model) = camera(metadata);
+    for (tag, expected) in [(Tag::Make, make), (Tag::Model, model)] {
+        let actual = exif.get_field(tag, In::PRIMARY).and_then(ascii);
+        if let (Some(expected), Some(actual)) = (expected, actual) {
+            if !expected.eq_ignore_ascii_case(&actual) {
+                warn!(
+                    "{} EXIF {} is {:?} but google reports {:?}",
+                    item.id, tag, actual, expected
+                );
+            }
+        }
+    }
+
+    let taken = exif
+        .get_field(Tag::DateTimeOriginal, In::PRIMARY)
+        .and_then(|field| match field.value {
+            Value::Ascii(ref values) => values.first(),
+            _ => None,
+        })
+        .and_then(|value| DateTime::from_ascii(value).ok());
+    if let (Some(taken), Some(created)) = (
+        taken,
+        exif_time(&metadata.creationTime).and_then(|created| DateTime::from_ascii(&created).ok()),
+    ) {
+        if (epoch_secs(&taken) - epoch_secs(&created)).abs() > MAX_TIMEZONE_OFFSET_SECS {
+            warn!(
+                "{} EXIF was taken at {} but google reports {}",
+                item.id, taken, metadata.creationTime
+            );
+        }
+    }
+}
+
+/// build a JPEG APP1 segment holding google's metadata as EXIF, None if there is nothing to add
+fn exif_segment(metadata: &MediaMetadata) -> Result<Option<Vec<u8>>, exif::Error> {
+    let field = |tag, value: Vec<u8>| Field {
+        tag,
+        ifd_num: In::PRIMARY,
+        value: Value::Ascii(vec![value]),
+    };
+
+    let mut fields = Vec::new();
+    let (make, model) = camera(metadata);
+    if let Some(make) = make {
+        fields.push(field(Tag::Make, make.clone().into_bytes()));
+    }
+    if let Some(model) = model {
+        fields.push(field(Tag::Model, model.clone().into_bytes()));
+    }
+    if let Some(created) = exif_time(&metadata.creationTime) {
+        fields.push(field(Tag::DateTimeOriginal, created));
+        fields.push(field(Tag::OffsetTimeOriginal, b"+00:00".to_vec()));
+    }
+    if fields.is_empty() {
+        return Ok(None);
+    }
+
+    let mut writer = Writer::new();
+    for field in fields.iter() {
+        writer.push_field(field);
+    }
+    let mut tiff = Cursor::new(Vec::new());
+    writer.write(&mut tiff, false)?;
+    let tiff = tiff.into_inner();
+
+    let len = 2 + EXIF_ID.len() + tiff.len();
+    let len = u16::try_from(len).map_err(|_| exif::Error::TooBig("EXIF segment is too large"))?;
+    let mut segment = vec![0xff, 0xe1];
+    segment.extend_from_slice(&len.to_be_bytes());
+    segment.extend_from_slice(EXIF_ID);
+    segment.extend_from_slice(&tiff);
+    Ok(Some(segment))
+}
+
+/// write google's metadata into a JPEG without EXIF, returning false if the file is not a JPEG
+fn embed(path: &Path, metadata: &MediaMetadata) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let data = std::fs::read(path)?;
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return Ok(false);
+    }
+    let segment = match exif_segment(metadata)? {
+        Some(segment) => segment,
+        None => return Ok(false),
+    };
+
+    // EXIF goes straight after the start of image marker, or after the JFIF header if present
+    let mut insert_at = 2;
+    if data.get(2..4) == Some(&[0xff, 0xe0]) {
+        if let Some(len) = data.get(4..6) {
+            insert_at += 2 + u16::from_be_bytes([len[0], len[1]]) as usize;
+        }
+    }
+    if insert_at > data.len() {
+        return Err("malformed JPEG header".into());
+    }
+
+    let mut embedded = Vec::with_capacity(data.len() + segment.len());
+    embedded.extend_from_slice(&data[..insert_at]);
+    embedded.extend_from_slice(&segment);
+    embedded.extend_from_slice(&data[insert_at..]);
+
+    // write alongside the file and swap it in, so a crash never leaves a half written file
+    let tmp = path.with_extension("exif.tmp");
+    std::fs::write(&tmp, embedded)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(true)
+}
+
+/// Check the EXIF of a downloaded file against google's metadata, warning about any
+/// disagreement. If the file has no EXIF at all, google's metadata is written into it so the
+/// archive doesn't depend on the database. Only JPEGs can be written to, other formats, and
+/// formats without EXIF support, are left as they are.
+///
+/// Returns whether the file was modified.
+pub fn verify_or_embed(
+    path: &Path,
+    item: &MediaItem,
+) -> Result<bool, Box<dyn Error + Send + Sync + 'static>> {
+    let metadata = match item.mediaMetadata {
+        Some(ref metadata) => metadata,
+        None => return Ok(false),
+    };
+
+    let mut reader = BufReader::new(File::open(path)?);
+    match Reader::new().read_from_container(&mut reader) {
+        Ok(exif) => {
+            compare(item, metadata, &exif);
+            Ok(false)
+        }
+        Err(exif::Error::NotFound(_)) => {
+            let embedded = embed(path, metadata)?;
+            if embedded {
+                debug!("wrote google's metadata into the EXIF of {}", item.id);
+            } else {
+                debug!(
+                    "{} has no EXIF and is not a JPEG, leaving it as is",
+                    item.id
+                );
+            }
+            Ok(embedded)
+        }
+        Err(exif::Error::Io(e)) => Err(Box::new(e)),
+        // videos and other formats without EXIF support, or EXIF too damaged to read
+        Err(e) => {
+            debug!("unable to read EXIF of {}: {}", item.id, e);
+            Ok(false)
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::{process::Stdio, time::Duration};
+
+use log::{error, info, warn};
+use shared_libs::json_templates::MediaItem;
+
+use crate::{config::Config, media::DownloadOutcome};
+
+/// run `post_download_command` for a downloaded item, logging its output. The command is split on
+/// whitespace and given the stored file's path and the item's id as its last two arguments, which
+/// are also set in `SYNCABULL_PATH` and `SYNCABULL_ID` alongside `SYNCABULL_FILENAME` and
+/// `SYNCABULL_BYTES`. A command that fails or times out is logged, the download still counts.
+pub async fn post_download(config: &Config, item: &MediaItem, outcome: &DownloadOutcome) {
+    let mut parts = match config.post_download_command {
+        Some(ref command) => command.split_whitespace(),
+        None => return,
+    };
+    let program = match parts.next() {
+        Some(program) => program,
+        None => return,
+    };
+
+    let mut command = tokio::process::Command::new(program);
+    command
+        .args(parts)
+        .arg(&outcome.path)
+        .arg(&item.id)
+        .env("SYNCABULL_ID", &item.id)
+        .env("SYNCABULL_PATH", &outcome.path)
+        .env("SYNCABULL_FILENAME", &item.filename)
+        .env("SYNCABULL_BYTES", outcome.bytes.to_string())
+        .stdin(Stdio::null())
+        // so a command that times out doesn't keep running in the background
+        .kill_on_drop(true);
+
+    let timeout = Duration::from_secs(config.post_download_timeout_secs);
+    let output = match tokio::time::timeout(timeout, command.output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => {
+            error!(
+                "failed to run the post download command for {}: {}",
+                item.id, e
+            );
+            return;
+        }
+        Err(_) => {
+            error!(
+                "the post download command for {} timed out after {} seconds",
+                item.id, config.post_download_timeout_secs
+            );
+            return;
+        }
+    };
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        info!("post download command: {}", line);
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        warn!("post download command: {}", line);
+    }
+    if !output.status.success() {
+        error!(
+            "the post download command for {} failed: {}",
+            item.id, output.status
+        );
+    }
+}
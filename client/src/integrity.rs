@@ -0,0 +1,124 @@
+use std::{path::PathBuf, time::Duration};
+
+use log::{debug, error, info, warn};
+use rand::Rng;
+use reqwest::Client;
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::Config,
+    database::{self, spawn_with_connection, DbPool, HashedItem},
+    media, sleep_until_shutdown,
+};
+
+/// posted to `integrity_webhook` for each stored file that fails the integrity check
+#[derive(Debug, Serialize)]
+struct IntegrityFailureNotification<'a> {
+    event: &'a str,
+    client_id: Option<&'a str>,
+    item_id: &'a str,
+    path: &'a str,
+    expected_sha256: &'a str,
+    /// None if the file is missing or couldn't be read
+    actual_sha256: Option<&'a str>,
+}
+
+/// the item after `after` to check, wrapping around to the first once the last has been checked
+async fn next_item(
+    pool: &DbPool,
+    after: &mut Option<String>,
+) -> Result<Option<HashedItem>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let last = after.clone();
+    let item = spawn_with_connection(pool, move |conn| {
+        match database::next_hashed_item(conn, last.as_deref(), 0)? {
+            None if last.is_some() => database::next_hashed_item(conn, None, 0),
+            item => Ok(item),
+        }
+    })
+    .await?;
+    *after = item.as_ref().map(|(id, _, _)| id.clone());
+    Ok(item)
+}
+
+/// the id of a random item to start checking after, so a client that restarts often still checks
+/// the whole store over time rather than the same first few files
+async fn random_start(pool: &DbPool) -> Option<String> {
+    let result = spawn_with_connection(pool, |conn| match database::hashed_count(conn)? {
+        0 => Ok(None),
+        count => database::next_hashed_item(conn, None, rand::thread_rng().gen_range(0..count)),
+    })
+    .await;
+    match result {
+        Ok(item) => item.map(|(id, _, _)| id),
+        Err(e) => {
+            error!("failed to pick where to start the integrity check: {}", e);
+            None
+        }
+    }
+}
+
+/// let `integrity_webhook` know about a file that failed the check, failures are only logged
+async fn notify(agent: &Client, webhook: &str, notification: &IntegrityFailureNotification<'_>) {
+    match agent.post(webhook).json(notification).send().await {
+        Ok(res) if res.status().is_success() => {}
+        Ok(res) => error!("integrity webhook responded with {}", res.status()),
+        Err(e) => error!("failed to notify the integrity webhook: {}", e),
+    }
+}
+
+/// hash stored files again, `integrity_check_rate` an hour, reporting any that no longer match the
+/// sha256 recorded when they were downloaded, an early sign of failing storage. Runs until the
+/// client shuts down.
+pub async fn run(config: &Config, agent: &Client, pool: &DbPool, shutdown: &CancellationToken) {
+    let interval = Duration::from_secs(60 * 60) / config.integrity_check_rate;
+    info!(
+        "checking the integrity of {} stored files an hour",
+        config.integrity_check_rate
+    );
+
+    let mut after = random_start(pool).await;
+    while !shutdown.is_cancelled() {
+        sleep_until_shutdown(interval, shutdown).await;
+        if shutdown.is_cancelled() {
+            break;
+        }
+
+        let (id, path, expected) = match next_item(pool, &mut after).await {
+            Ok(Some(item)) => item,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("failed to load the next item to check: {}", e);
+                continue;
+            }
+        };
+
+        let actual = match media::hash_file(PathBuf::from(&path)).await {
+            Ok(actual) if actual == expected => {
+                debug!("{} matches its recorded hash", path);
+                continue;
+            }
+            Ok(actual) => Some(actual),
+            Err(e) => {
+                warn!("unable to hash {}: {}", path, e);
+                None
+            }
+        };
+
+        error!(
+            "{} ({}) no longer matches the sha256 recorded when it was downloaded, the storage may be failing",
+            path, id
+        );
+        if let Some(ref webhook) = config.integrity_webhook {
+            let notification = IntegrityFailureNotification {
+                event: "integrity_failure",
+                client_id: config.local_id.as_deref(),
+                item_id: &id,
+                path: &path,
+                expected_sha256: &expected,
+                actual_sha256: actual.as_deref(),
+            };
+            notify(agent, webhook, &notification).await;
+        }
+    }
+}
@@ -1,58 +1,556 @@
+pub mod audit;
+pub mod cli;
 pub mod config;
 pub mod database;
+pub mod doctor;
+pub mod embed_exif;
+pub mod hook;
+pub mod integrity;
 pub mod media;
+pub mod page_size;
+pub mod pidfile;
+pub mod purge;
+pub mod ratelimit;
+pub mod reindex;
+pub mod report;
 pub mod schema;
+pub mod sprites;
+pub mod status;
+#[cfg(test)]
+mod test_utils;
+pub mod throughput;
 
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use database::{establish_connection, run_migrations, DbConnection};
-use log::{debug, error, info};
+use clap::Parser;
+use cli::{Args, Command};
+use database::{
+    establish_connection, establish_pool, run_migrations, spawn_with_connection, with_connection,
+    DbConnection, DbPool,
+};
+use log::{debug, error, info, warn};
 use reqwest::Client;
 use shared_libs::json_templates::MediaItem;
 use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
 
-use crate::config::Config;
+use crate::{
+    config::{AuthFailureAction, Config, HttpVersion, SkipIfPresent},
+    media::{label, MediaError},
+    page_size::PageSize,
+    ratelimit::RateLimiter,
+    throughput::Throughput,
+};
 
 type Id = String;
 type Passcode = String;
 
-pub fn agent() -> Client {
-    Client::new()
+/// The number of times an item is attempted before it is recorded as failed
+pub const MAX_DOWNLOAD_ATTEMPTS: u32 = 4;
+
+/// The User-Agent used when none is configured, google asks that clients wanting compressed
+/// responses include "gzip" in their User-Agent as well as in Accept-Encoding
+pub const DEFAULT_USER_AGENT: &str = concat!("syncabull/", env!("CARGO_PKG_VERSION"), " (gzip)");
+
+pub fn agent(config: &Config) -> Client {
+    let builder = match config.http_version {
+        HttpVersion::Auto => Client::builder(),
+        HttpVersion::Http1 => Client::builder().http1_only(),
+        HttpVersion::Http2 => Client::builder().http2_prior_knowledge(),
+    };
+    builder
+        .user_agent(&config.user_agent)
+        .pool_max_idle_per_host(config.pool_max_idle_per_host)
+        .pool_idle_timeout(
+            Some(Duration::from_secs(config.pool_idle_timeout_secs))
+                .filter(|timeout| !timeout.is_zero()),
+        )
+        .tcp_keepalive(
+            Some(Duration::from_secs(config.tcp_keepalive_secs))
+                .filter(|keepalive| !keepalive.is_zero()),
+        )
+        .build()
+        .expect("failed to build http client")
+}
+
+/// check a downloaded file's EXIF against google's metadata, writing the metadata in if the file
+/// has none. Failures are only logged, as the download itself succeeded.
+async fn check_exif(outcome: &mut media::DownloadOutcome, item: &MediaItem) {
+    let path = outcome.path.clone();
+    let item = item.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let modified = embed_exif::verify_or_embed(&path, &item)?;
+        Ok::<_, Box<dyn std::error::Error + Send + Sync + 'static>>(match modified {
+            true => Some(std::fs::metadata(&path)?.len()),
+            false => None,
+        })
+    })
+    .await;
+
+    match result {
+        Ok(Ok(Some(bytes))) => outcome.bytes = bytes,
+        Ok(Ok(None)) => {}
+        Ok(Err(e)) => warn!("failed to check EXIF of {:?}: {}", outcome.path, e),
+        Err(e) => warn!("failed to check EXIF of {:?}: {}", outcome.path, e),
+    }
+}
+
+/// download the profile picture of the contributor of a shared album item, if it hasn't been
+/// fetched already. Failures are only logged, the item itself was downloaded.
+async fn save_avatar(config: &Config, agent: &Client, state: &ScanState, item: &MediaItem) {
+    let contributor = match item.contributorInfo {
+        Some(ref contributor) => contributor,
+        None => return,
+    };
+    if !state
+        .avatars
+        .lock()
+        .await
+        .insert(contributor.profilePictureBaseUrl.clone())
+    {
+        return;
+    }
+
+    if let Err(e) = media::download_avatar(config, agent, contributor).await {
+        warn!(
+            "failed to download the profile picture of {}: {}",
+            contributor.displayName, e
+        );
+    }
+}
+
+/// How often the current download speed is logged
+const THROUGHPUT_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the download queue is saved, so a restarted client can pick up where it left off
+const QUEUE_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the retry queue is checked for items that can be tried again
+const RETRY_QUEUE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the watchdog checks each download lane is still making progress
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long to wait before retrying a rate limited item, when google doesn't say
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// Counters for what has happened during this run, reported when the client shuts down
+#[derive(Debug, Default)]
+pub struct SessionStats {
+    /// Items downloaded successfully
+    pub downloaded: AtomicU64,
+    /// Items that were given up on after `MAX_DOWNLOAD_ATTEMPTS` attempts
+    pub failed: AtomicU64,
+    /// Bytes written to the store path
+    pub bytes: AtomicU64,
+}
+
+/// How fetching pages from the api is going, so being backed off from a failing api can be told
+/// apart from being idle
+#[derive(Debug, Default)]
+pub struct ApiHealth {
+    /// When a page was last fetched, in seconds since the unix epoch, 0 if none has been yet
+    pub last_success_at: AtomicU64,
+    /// Fetches that have failed in a row
+    pub consecutive_failures: AtomicU64,
+    /// When the next fetch is attempted while backing off, in seconds since the unix epoch, 0
+    /// when not backing off
+    pub retry_at: AtomicU64,
+}
+
+/// Items are downloaded in two lanes, so a few very large items don't hold up the many small ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    /// Every item is queued here first, and moved to the large lane if it turns out to be over
+    /// `large_file_threshold`
+    Main,
+    /// Items over `large_file_threshold`, downloaded one at a time through the api
+    Large,
+}
+
+/// State shared between the scanning and downloading halves of `download_scan`
+#[derive(Debug, Default)]
+pub struct ScanState {
+    /// Items waiting to be downloaded
+    pub queue: Mutex<VecDeque<MediaItem>>,
+    /// The item currently being downloaded, it is not in the queue while this happens
+    pub downloading: Mutex<Option<MediaItem>>,
+    /// Items over `large_file_threshold` waiting to be downloaded by the large file lane
+    pub large_queue: Mutex<VecDeque<MediaItem>>,
+    /// The item currently being downloaded by the large file lane
+    pub downloading_large: Mutex<Option<MediaItem>>,
+    /// The size of each item moved to the large file lane, by id
+    pub large_sizes: Mutex<HashMap<String, u64>>,
+    /// When the page in the queue was loaded, in seconds since the unix epoch
+    pub page_loaded_at: AtomicU64,
+    /// Whether the queue is currently being downloaded
+    pub processing: AtomicBool,
+    /// Whether we are waiting for new items to appear, or for space to be freed
+    pub waiting: AtomicBool,
+    pub stats: SessionStats,
+    pub api_health: ApiHealth,
+    pub throughput: Throughput,
+    /// The bytes received by the large file lane, which aren't counted in `throughput`
+    pub large_throughput: Throughput,
+    /// When the main lane last reported it was still working, in seconds since the unix epoch.
+    /// It is set ahead while the lane deliberately waits, so the watchdog doesn't mistake that
+    /// for being stuck
+    pub heartbeat: AtomicU64,
+    /// When the large file lane last reported it was still working
+    pub large_heartbeat: AtomicU64,
+    /// Shared by every download of every account, so `max_download_speed` limits their combined
+    /// speed
+    pub limiter: Arc<RateLimiter>,
+    /// Cancelled once the client has been asked to shut down
+    pub shutdown: CancellationToken,
+    /// The contributor profile pictures already fetched this run, by url
+    pub avatars: Mutex<HashSet<String>>,
+    /// The ids of the queued items taken from the retry queue or left in the queue past
+    /// `baseurl_reload_interval_secs`, which are downloaded through the api as their base urls
+    /// have likely expired
+    pub retrying: Mutex<HashSet<String>>,
+    /// Whether the api can stream media, checked the first time it is needed
+    pub api_serves_media: tokio::sync::OnceCell<bool>,
 }
 
-/// Load new items from the server for download :)
+impl ScanState {
+    /// the queue of items waiting for a lane
+    pub fn queue(&self, lane: Lane) -> &Mutex<VecDeque<MediaItem>> {
+        match lane {
+            Lane::Main => &self.queue,
+            Lane::Large => &self.large_queue,
+        }
+    }
+
+    /// the item a lane is downloading
+    pub fn downloading(&self, lane: Lane) -> &Mutex<Option<MediaItem>> {
+        match lane {
+            Lane::Main => &self.downloading,
+            Lane::Large => &self.downloading_large,
+        }
+    }
+
+    /// the bytes received by a lane
+    pub fn throughput(&self, lane: Lane) -> &Throughput {
+        match lane {
+            Lane::Main => &self.throughput,
+            Lane::Large => &self.large_throughput,
+        }
+    }
+
+    /// the average bytes per second received by both lanes together
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.throughput.bytes_per_sec() + self.large_throughput.bytes_per_sec()
+    }
+
+    fn heartbeat(&self, lane: Lane) -> &AtomicU64 {
+        match lane {
+            Lane::Main => &self.heartbeat,
+            Lane::Large => &self.large_heartbeat,
+        }
+    }
+
+    /// note that a lane is still working, and may go quiet for `quiet` before it next reports
+    fn beat(&self, lane: Lane, quiet: Duration) {
+        self.heartbeat(lane)
+            .store(unix_time() + quiet.as_secs(), Ordering::Relaxed);
+    }
+
+    /// whether a lane has gone `timeout` without reporting or receiving any data
+    fn stalled(&self, lane: Lane, timeout: Duration) -> bool {
+        let quiet = unix_time().saturating_sub(self.heartbeat(lane).load(Ordering::Relaxed));
+        quiet >= timeout.as_secs() && !self.throughput(lane).received_within(timeout)
+    }
+
+    /// whether the api can stream media, so retried items can be downloaded through it. An api
+    /// that can't be asked is checked again next time
+    async fn api_serves_media(&self, config: &Config, agent: &Client) -> bool {
+        let check = self
+            .api_serves_media
+            .get_or_try_init(|| async {
+                media::capabilities(config, agent)
+                    .await
+                    .map(|capabilities| {
+                        capabilities.is_some_and(|c| c.features.iter().any(|f| f == "media"))
+                    })
+            })
+            .await;
+        match check {
+            Ok(serves_media) => *serves_media,
+            Err(e) => {
+                warn!("unable to check the api can serve media: {}", e);
+                false
+            }
+        }
+    }
+
+    /// whether every item queued so far has been downloaded or given up on, in both lanes
+    async fn drained(&self) -> bool {
+        self.queue.lock().await.is_empty()
+            && self.downloading.lock().await.is_none()
+            && self.large_queue.lock().await.is_empty()
+            && self.downloading_large.lock().await.is_none()
+    }
+}
+
+/// Take the configured action once the api has rejected our credentials too many times in a row
+async fn handle_auth_failures(config: &Config, agent: &Client, failures: u32) {
+    match config.auth_failure_action {
+        AuthFailureAction::Log => {
+            error!(
+                "authentication with the api has failed {} times in a row, your google account may need to be re-linked",
+                failures
+            );
+        }
+        AuthFailureAction::Webhook => {
+            let webhook = config.auth_failure_webhook.as_ref().unwrap();
+            if let Err(e) = media::notify_auth_failure(config, agent, webhook, failures).await {
+                error!("failed to notify auth failure webhook: {}", e);
+            }
+        }
+        AuthFailureAction::Pause => {
+            error!(
+                "authentication with the api has failed {} times in a row, pausing until the google account is re-linked",
+                failures
+            );
+            loop {
+                match media::get_auth_url(config, agent, false).await {
+                    Ok(auth_url) => {
+                        info!(
+                            "please visit {} to re-link your google account{}",
+                            auth_url.url,
+                            auth_url.time_limit()
+                        );
+                        info!("if the page shows a claim code, paste it here and press enter");
+                        match media::complete_authentication(config, agent, &auth_url.url).await {
+                            Ok(_) => break,
+                            Err(e) => error!("re-authentication failed {}", e),
+                        }
+                    }
+                    Err(e) => {
+                        error!("unable to get auth url {}", e);
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                    }
+                }
+            }
+            info!("user authentication successful, resuming");
+        }
+    }
+}
+
+/// Queue new items for download as the queue empties. This only returns if the api's responses
+/// repeatedly can't be parsed, as retrying against an incompatible api would never succeed.
+///
+/// `scan_turn` is shared by every account, so they fetch pages one at a time, in turn.
 pub async fn load_new_items(
     config: &Config,
     agent: &Client,
-    connection: Arc<Mutex<DbConnection>>,
-    queue: &Mutex<VecDeque<MediaItem>>,
-    processing: &AtomicBool,
-    waiting: &AtomicBool,
-) {
+    connection: DbPool,
+    state: &ScanState,
+    scan_turn: &Mutex<()>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
     let mut e_backoff = 1;
+    let mut db_backoff = 1;
+    let mut auth_failures = 0;
+    let mut parse_failures = 0;
     let mut last_refresh_time = Instant::now();
-    // first request should always try to reload if possible, but only if the initial scan has
-    // been completed
-    let mut reload = config.initial_scan_complete();
+    // the first request reloads the page we were last given, so that a page which was only
+    // partially downloaded before the client stopped is picked up again rather than skipped
+    let mut reload = true;
+    let mut page_size = PageSize::new(config.page_size);
+    // when the last page was queued and how long it took to fetch, to see how fast it drains
+    let mut page_queued: Option<(Instant, Duration)> = None;
+    // pages fetched since the scan budget was last reset, failed fetches don't count
+    let mut budget_start = Instant::now();
+    let mut budget_pages = 0;
+    let mut budget_paused = false;
+    // set once the api has returned the last page of the library. The initial scan is only
+    // complete once everything queued from it has been downloaded, not just queued
+    let mut reached_end = false;
+    // the pages still to fetch before the first download of the initial scan, and the items and
+    // fetch time of those fetched so far
+    let mut prefetch_left = match config.initial_scan_complete() {
+        true => 1,
+        false => config.prefetch_pages,
+    };
+    // only the api's current page can be fetched again once base urls expire, so older apis that
+    // can't serve expired items by id would lose the items of every page before it
+    if prefetch_left > 1 && !state.api_serves_media(config, agent).await {
+        warn!("the api can't serve media, so only one page is fetched at a time");
+        prefetch_left = 1;
+    }
+    let mut prefetched: Vec<MediaItem> = Vec::new();
+    let mut prefetch_started: Option<(Instant, u64)> = None;
+    // fetched in place of the first page, until a page has been fetched from it
+    let mut start_token = config.start_token.clone();
+    // checked straight away, to pick up items rate limited by earlier runs
+    let mut retries_checked: Option<Instant> = None;
+    if start_token.is_some() {
+        info!("starting the scan from the given page token, the saved queue is left behind");
+        reload = false;
+    }
+
+    // pick up the queue saved by the last run. If its base urls have expired it is downloaded
+    // through the api, as it would have been had the client kept running
+    let account = queue_account(config).to_string();
+    match spawn_with_connection(&connection, move |conn| {
+        database::load_queue(conn, &account)
+    })
+    .await
+    {
+        Ok(_) if start_token.is_some() => {}
+        Ok(saved) if !saved.is_empty() => {
+            let loaded_at = saved[0].1;
+            let age = unix_time().saturating_sub(loaded_at);
+            info!("resuming {} items queued by the last run", saved.len());
+            last_refresh_time = Instant::now()
+                .checked_sub(Duration::from_secs(age))
+                .unwrap_or_else(Instant::now);
+            state.page_loaded_at.store(loaded_at, Ordering::Relaxed);
+            let mut items: Vec<MediaItem> = saved.into_iter().map(|(item, _)| item).collect();
+            restore_attempts(config, &connection, &mut items).await;
+            state.queue.lock().await.extend(items);
+            reload = false;
+            prefetch_left = 1;
+            if age >= config.baseurl_reload_interval_secs {
+                expire_queue(config, agent, state, &mut reload).await;
+            }
+        }
+        Ok(_) => {}
+        Err(e) => error!("failed to load the saved download queue: {}", e),
+    }
 
     loop {
-        if !processing.load(Ordering::Relaxed) && queue.lock().await.is_empty() {
-            let items = match media::get_media_items(config, agent, reload).await {
-                Ok(i) => i,
+        if retries_checked.is_none_or(|checked| checked.elapsed() >= RETRY_QUEUE_INTERVAL) {
+            retries_checked = Some(Instant::now());
+            queue_due_retries(config, state, &connection).await;
+        }
+
+        if !state.processing.load(Ordering::Relaxed) && state.queue.lock().await.is_empty() {
+            if let Some((queued, latency)) = page_queued.take() {
+                page_size.drained(latency, queued.elapsed());
+            }
+
+            if reached_end && state.drained().await {
+                reached_end = false;
+                if !config.initial_scan_complete() {
+                    info!("the whole library has been downloaded, initial scan complete");
+                    mark_initial_scan_complete(config, &connection);
+                }
+            }
+
+            let budget_interval = Duration::from_secs(config.scan_budget_interval_secs);
+            if budget_start.elapsed() >= budget_interval {
+                budget_start = Instant::now();
+                budget_pages = 0;
+            }
+            if config.max_scan_pages_per_run > 0 && budget_pages >= config.max_scan_pages_per_run {
+                let remaining = budget_interval.saturating_sub(budget_start.elapsed());
+                if !budget_paused {
+                    info!(
+                        "fetched {} pages, the scan budget for this interval, pausing scanning for {} seconds",
+                        budget_pages,
+                        remaining.as_secs()
+                    );
+                    budget_paused = true;
+                }
+                // wake up in time to queue due retries, they are still downloaded while scanning
+                // is paused
+                state.waiting.store(true, Ordering::Relaxed);
+                tokio::time::sleep(remaining.min(RETRY_QUEUE_INTERVAL)).await;
+                state.waiting.store(false, Ordering::Relaxed);
+                continue;
+            }
+            budget_paused = false;
+
+            let fetch_start = Instant::now();
+            let result = {
+                let _turn = scan_turn.lock().await;
+                media::get_media_items(
+                    config,
+                    agent,
+                    reload,
+                    page_size.get(),
+                    start_token.as_deref(),
+                )
+                .await
+            };
+            let page = match result {
+                Ok(page) => page,
                 Err(e) => {
                     error!(
                         "failed to collect media items for download due to error: {}",
                         e
                     );
-                    error!("retrying in {} seconds", e_backoff);
-                    tokio::time::sleep(Duration::from_secs(e_backoff)).await;
+
+                    if matches!(e, MediaError::Network(ref e) if e.is_timeout()) {
+                        page_size.timed_out();
+                    }
+
+                    if let MediaError::Parse(ref parse_error) = e {
+                        parse_failures += 1;
+                        if parse_failures == config.max_parse_failures {
+                            error!(
+                                "giving up after {} responses in a row could not be parsed, the last response was: {}",
+                                parse_failures, parse_error.body
+                            );
+                            return Err(Box::new(e));
+                        }
+                    } else {
+                        parse_failures = 0;
+                    }
+
+                    if let MediaError::ReauthRequired = e {
+                        warn!("google rejected this client's login, it must be linked again");
+                        match media::authenticate(config, agent, false).await {
+                            Ok(()) => {
+                                info!("user authentication successful");
+                                e_backoff = 1;
+                                continue;
+                            }
+                            Err(e) => error!("authentication failed {}", e),
+                        }
+                    }
+
+                    if let MediaError::Auth(_) = e {
+                        auth_failures += 1;
+                        if auth_failures >= config.auth_failure_threshold {
+                            handle_auth_failures(config, agent, auth_failures).await;
+                            if config.auth_failure_action == AuthFailureAction::Pause {
+                                auth_failures = 0;
+                                e_backoff = 1;
+                                continue;
+                            }
+                        }
+                    }
+
+                    // when the api says how long it is rate limiting us for, there is no point
+                    // retrying any sooner
+                    let retry_in = match e {
+                        MediaError::RateLimited {
+                            retry_after: Some(retry_after),
+                        } => retry_after.as_secs().max(e_backoff),
+                        _ => e_backoff,
+                    };
+                    error!("retrying in {} seconds", retry_in);
+                    state
+                        .api_health
+                        .consecutive_failures
+                        .fetch_add(1, Ordering::Relaxed);
+                    state
+                        .api_health
+                        .retry_at
+                        .store(unix_time() + retry_in, Ordering::Relaxed);
+                    tokio::time::sleep(Duration::from_secs(retry_in)).await;
+                    state.api_health.retry_at.store(0, Ordering::Relaxed);
                     e_backoff *= 2;
                     if e_backoff > 1800 {
                         e_backoff = 1800;
@@ -61,135 +559,676 @@ pub async fn load_new_items(
                 }
             };
 
+            let latency = fetch_start.elapsed();
+            page_size.fetched(latency);
+            start_token = None;
+            budget_pages += 1;
+
             e_backoff = 1;
+            auth_failures = 0;
+            parse_failures = 0;
             last_refresh_time = Instant::now();
+            state
+                .api_health
+                .last_success_at
+                .store(unix_time(), Ordering::Relaxed);
+            state
+                .api_health
+                .consecutive_failures
+                .store(0, Ordering::Relaxed);
+
+            if page.last_page == Some(true) {
+                reached_end = true;
+            }
+            let has_more = page.has_more();
+            let media::MediaPage { items, last_page } = page;
+
+            // pages are held back until all of them have been fetched, so downloading starts with
+            // a full queue rather than waiting on the api between pages. A short page doesn't end
+            // the prefetch early unless it is the last
+            if prefetch_left > 1 && has_more {
+                prefetch_left -= 1;
+                prefetch_started.get_or_insert((fetch_start, unix_time()));
+                prefetched.extend(items);
+                reload = false;
+                continue;
+            }
+            prefetch_left = 1;
+            let items = match prefetched.is_empty() {
+                true => items,
+                false => {
+                    let items: Vec<MediaItem> = prefetched.drain(..).chain(items).collect();
+                    info!("prefetched {} items, starting to download", items.len());
+                    items
+                }
+            };
+            // base urls expire from when the first page was fetched
+            let (page_fetched, page_loaded_at) = prefetch_started
+                .take()
+                .unwrap_or((fetch_start, unix_time()));
+            last_refresh_time = page_fetched;
 
             if items.is_empty() {
                 info!("api returned no new items to download");
                 continue;
             }
 
-            if all_present(&items, &mut *connection.lock().await) {
+            let page = items.clone();
+            let present = match spawn_with_connection(&connection, move |conn| {
+                all_present(&page, conn)
+            })
+            .await
+            {
+                Ok(present) => {
+                    db_backoff = 1;
+                    present
+                }
+                Err(e) => {
+                    // the database may only be busy, so fetch this page again once it has settled
+                    error!(
+                        "failed to check for new items, retrying in {} seconds: {}",
+                        db_backoff, e
+                    );
+                    tokio::time::sleep(Duration::from_secs(db_backoff)).await;
+                    db_backoff = (db_backoff * 2).min(300);
+                    reload = true;
+                    continue;
+                }
+            };
+
+            if present {
                 if !config.initial_scan_complete() {
-                    info!("all items are present in the database, initial scan complete");
-                    config
-                        .set_initial_scan_complete(&mut *connection.lock().await)
-                        .expect("failed to set initial scan complete");
+                    // older apis don't say which page is the last, so the first page that is
+                    // already downloaded is taken to mean the scan has caught up
+                    if last_page.is_none() {
+                        info!("all items are present in the database, initial scan complete");
+                        mark_initial_scan_complete(config, &connection);
+                    }
                 } else {
-                    info!("all items are present in the database, no new items to download - sleeping for 15 minutes");
-                    waiting.store(true, Ordering::Relaxed);
-                    tokio::time::sleep(Duration::from_secs(60 * 30)).await;
+                    info!(
+                        "all items are present in the database, no new items to download - sleeping for {} seconds",
+                        config.idle_rescan_interval_secs
+                    );
+                    state.waiting.store(true, Ordering::Relaxed);
+                    tokio::time::sleep(Duration::from_secs(config.idle_rescan_interval_secs)).await;
                 }
             }
 
-            queue.lock().await.extend(items);
-            waiting.store(false, Ordering::Relaxed);
+            let (excluded, mut items): (Vec<_>, Vec<_>) =
+                items.into_iter().partition(|item| config.excluded(item));
+            if !excluded.is_empty() {
+                debug!("excluding {} items from download", excluded.len());
+                let saved = spawn_with_connection(&connection, move |conn| {
+                    excluded
+                        .iter()
+                        .try_for_each(|item| database::save_excluded(conn, item))
+                })
+                .await;
+                if let Err(e) = saved {
+                    error!("failed to record excluded items: {}", e);
+                }
+            }
+
+            // items already moved to the large file lane stay there, rather than being queued
+            // twice when a page is fetched again
+            {
+                let large_sizes = state.large_sizes.lock().await;
+                items.retain(|item| !large_sizes.contains_key(&item.id));
+            }
+
+            restore_attempts(config, &connection, &mut items).await;
+            config.download_order.sort(&mut items);
+            state.queue.lock().await.extend(items);
+            state
+                .page_loaded_at
+                .store(page_loaded_at, Ordering::Relaxed);
+            page_queued = Some((Instant::now(), latency));
+            state.waiting.store(false, Ordering::Relaxed);
             reload = false;
         }
 
-        // if last refresh > 50 minutes, recollect all media items
-        if last_refresh_time.elapsed().as_secs() > (60 * 55) {
-            info!("last refresh was more than 55 minutes ago, reloading all media items");
+        // base urls expire after an hour, so before they do the queued items are sent through the
+        // api instead, or with older apis dropped and the same page fetched again with fresh urls
+        if last_refresh_time.elapsed().as_secs() > config.baseurl_reload_interval_secs
+            && expire_queue(config, agent, state, &mut reload).await
+        {
+            page_queued = None;
+            page_size.expired();
+        }
 
-            let mut lock = queue.lock().await;
-            if config.initial_scan_complete() {
-                info!("refreshing media items");
-                reload = true;
-            } else {
-                error!(
-                    "initial scan not complete, but 50 minutes have passed, this should not happen"
-                );
-                for item in lock.iter() {
-                    let db_conn = connection.clone();
-                    let db_item = item.clone();
-                    let res = tokio::task::spawn_blocking(move || {
-                        database::save_media_item(&mut *db_conn.blocking_lock(), &db_item)
-                    });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
 
-                    match res.await {
-                        Ok(_) => debug!("saved media item to database"),
-                        Err(e) => error!("failed to save media item to database {}", e),
-                    }
-                }
-                info!("saved all media items to database, initial scan complete");
+/// Deal with the queued items whose base urls have expired, returning whether there were any. They
+/// are downloaded through the api, which looks each one up by id, so the items of every prefetched
+/// page are kept. Older apis can't serve media, so the items are dropped instead and `reload` set
+/// to fetch the api's current page again. The api only moves its scan position forward when a new
+/// page is requested, so a slow initial scan carries on from the page it was on.
+async fn expire_queue(
+    config: &Config,
+    agent: &Client,
+    state: &ScanState,
+    reload: &mut bool,
+) -> bool {
+    // items already going through the api don't need fresh urls
+    let expired = {
+        let retrying = state.retrying.lock().await;
+        let queue = state.queue.lock().await;
+        queue
+            .iter()
+            .filter(|item| !retrying.contains(&item.id))
+            .count()
+    };
+    if expired == 0 {
+        return false;
+    }
+
+    let serves_media = state.api_serves_media(config, agent).await;
+    let mut retrying = state.retrying.lock().await;
+    let mut queue = state.queue.lock().await;
+    if serves_media {
+        info!(
+            "last refresh was more than {} seconds ago, downloading {} queued media items through the api",
+            config.baseurl_reload_interval_secs, expired
+        );
+        retrying.extend(queue.iter().map(|item| item.id.clone()));
+    } else {
+        info!(
+            "last refresh was more than {} seconds ago, reloading {} queued media items",
+            config.baseurl_reload_interval_secs, expired
+        );
+        queue.retain(|item| retrying.contains(&item.id));
+        *reload = true;
+    }
+    true
+}
+
+/// queue the items in the retry queue that can be tried again, dropping any downloaded since
+async fn queue_due_retries(config: &Config, state: &ScanState, connection: &DbPool) {
+    let account = queue_account(config).to_string();
+    let due = match spawn_with_connection(connection, move |conn| {
+        database::due_retries(conn, &account, unix_time())
+    })
+    .await
+    {
+        Ok(due) => due,
+        Err(e) => {
+            error!("failed to load the retry queue: {}", e);
+            return;
+        }
+    };
+
+    let due: Vec<MediaItem> = {
+        let retrying = state.retrying.lock().await;
+        due.into_iter()
+            .filter(|item| !retrying.contains(&item.id))
+            .collect()
+    };
+    // items downloaded since they were rate limited are dropped from the retry queue
+    let result = spawn_with_connection(connection, move |conn| {
+        let mut pending = Vec::with_capacity(due.len());
+        for item in due {
+            if database::in_database(conn, &item.id)? {
+                database::remove_retry(conn, &item.id)?;
+            } else {
+                pending.push(item);
             }
+        }
+        Ok(pending)
+    })
+    .await;
+    let mut due = match result {
+        Ok(due) => due,
+        Err(e) => {
+            error!("failed to check which retried items are downloaded: {}", e);
+            return;
+        }
+    };
+    if due.is_empty() {
+        return;
+    }
 
-            lock.clear();
+    info!("retrying {} items that were rate limited", due.len());
+    restore_attempts(config, connection, &mut due).await;
+    let mut retrying = state.retrying.lock().await;
+    retrying.extend(due.iter().map(|item| item.id.clone()));
+    state.queue.lock().await.extend(due);
+}
+
+/// the sha256 recorded when an item was last downloaded, if any
+async fn recorded_sha256(connection: &DbPool, id: &str) -> Option<String> {
+    let item_id = id.to_string();
+    match spawn_with_connection(connection, move |conn| {
+        database::recorded_hash(conn, &item_id)
+    })
+    .await
+    {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("failed to load the recorded hash of {}: {}", id, e);
+            None
         }
+    }
+}
 
-        tokio::time::sleep(Duration::from_millis(100)).await;
+/// seconds since the unix epoch
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// the account an account's queue is saved under, empty for the primary account
+fn queue_account(config: &Config) -> &str {
+    config.account.as_deref().unwrap_or_default()
+}
+
+/// Save the item being downloaded and the queue behind it, skipping the write if nothing has
+/// changed since `last`, which is updated to what was saved
+async fn save_queue(
+    config: &Config,
+    state: &ScanState,
+    connection: &DbPool,
+    last: &mut Vec<(String, u32)>,
+) {
+    let mut items: Vec<MediaItem> = state.downloading.lock().await.iter().cloned().collect();
+    items.extend(state.queue.lock().await.iter().cloned());
+    // large items are queued in the main lane again when restored, and moved back as before
+    items.extend(state.downloading_large.lock().await.iter().cloned());
+    items.extend(state.large_queue.lock().await.iter().cloned());
+
+    let current: Vec<(String, u32)> = items
+        .iter()
+        .map(|item| (item.id.clone(), item.download_attempts))
+        .collect();
+    if current == *last {
+        return;
+    }
+
+    let loaded_at = state.page_loaded_at.load(Ordering::Relaxed);
+    let result = tokio::task::block_in_place(|| {
+        with_connection(connection, |conn| {
+            database::save_queue(conn, queue_account(config), &items, loaded_at)
+        })
+    });
+    match result {
+        Ok(()) => *last = current,
+        Err(e) => error!("failed to save the download queue: {}", e),
+    }
+}
+
+/// carry over the attempts recorded for items by earlier runs, so restarting doesn't reset their
+/// progress towards `MAX_DOWNLOAD_ATTEMPTS`
+async fn restore_attempts(config: &Config, connection: &DbPool, items: &mut [MediaItem]) {
+    let ids: Vec<String> = items.iter().map(|item| item.id.clone()).collect();
+    let grace_secs = config.attempt_grace_secs;
+    let recorded = match spawn_with_connection(connection, move |conn| {
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        database::recorded_attempts(conn, &ids, grace_secs)
+    })
+    .await
+    {
+        Ok(recorded) => recorded,
+        Err(e) => {
+            error!("failed to load recorded download attempts: {}", e);
+            return;
+        }
+    };
+
+    for item in items.iter_mut() {
+        if let Some(&attempts) = recorded.get(&item.id) {
+            item.download_attempts = item.download_attempts.max(attempts);
+        }
+    }
+}
+
+fn mark_initial_scan_complete(config: &Config, connection: &DbPool) {
+    // the whole config is saved, so it can't be moved to a blocking task
+    let result = tokio::task::block_in_place(|| {
+        with_connection(connection, |conn| config.set_initial_scan_complete(conn))
+    });
+    if let Err(e) = result {
+        error!("failed to set initial scan complete: {}", e);
     }
 }
 
 /// check if all items in this queue have already been downloaded
-pub fn all_present(items: &[MediaItem], connection: &mut DbConnection) -> bool {
+pub fn all_present(
+    items: &[MediaItem],
+    connection: &mut DbConnection,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync + 'static>> {
     for item in items {
-        if !database::in_database(connection, &item.id).unwrap() {
-            return false;
+        if !database::in_database(connection, &item.id)? {
+            return Ok(false);
         }
     }
 
-    true
+    Ok(true)
 }
 
-/// Download items that are in the queue
+/// check whether the free space on the store path has dropped below the configured watermark
+fn below_free_space_watermark(config: &Config) -> bool {
+    if config.min_free_bytes == 0 {
+        return false;
+    }
+
+    match fs2::available_space(&config.store_path) {
+        Ok(available) => available < config.min_free_bytes,
+        Err(e) => {
+            debug!(
+                "unable to check free space on {:?}: {}",
+                config.store_path, e
+            );
+            false
+        }
+    }
+}
+
+/// Download the items queued for a lane. Only the main lane tells the scanner it is busy, so the
+/// next page is fetched while the large lane is still working through its items.
 pub async fn download_items(
     config: &Config,
     agent: &Client,
-    connection: Arc<Mutex<DbConnection>>,
-    queue: &Mutex<VecDeque<MediaItem>>,
-    processing: &AtomicBool,
-    waiting: &AtomicBool,
+    connection: DbPool,
+    state: &ScanState,
+    lane: Lane,
 ) {
-    loop {
+    let mut paused = false;
+    let queue = state.queue(lane);
+    // items are only deferred when there is a lane to take them
+    let defer_large = lane == Lane::Main && config.large_file_threshold > 0;
+
+    // the current download is always finished before stopping, so nothing is left half written
+    while !state.shutdown.is_cancelled() {
+        state.beat(lane, Duration::ZERO);
+
+        if below_free_space_watermark(config) {
+            if !paused {
+                error!(
+                    "free space on {:?} is below {} bytes, pausing downloads until space is freed",
+                    config.store_path, config.min_free_bytes
+                );
+                paused = true;
+            }
+            state.waiting.store(true, Ordering::Relaxed);
+            let poll_interval = Duration::from_secs(config.free_space_poll_interval_secs);
+            state.beat(lane, poll_interval);
+            sleep_until_shutdown(poll_interval, &state.shutdown).await;
+            continue;
+        } else if paused {
+            info!("free space is available again, resuming downloads");
+            paused = false;
+            state.waiting.store(false, Ordering::Relaxed);
+        }
+
         if !queue.lock().await.is_empty() {
-            processing.store(true, Ordering::Relaxed);
+            if lane == Lane::Main {
+                state.processing.store(true, Ordering::Relaxed);
+            }
         } else {
-            processing.store(false, Ordering::Relaxed);
+            if lane == Lane::Main {
+                state.processing.store(false, Ordering::Relaxed);
+            }
 
             // if we are waiting for the download - wait 10 minutes, otherwise 5 seconds
-            if waiting.load(Ordering::Relaxed) {
-                tokio::time::sleep(Duration::from_secs(60 * 10)).await;
+            let idle = if lane == Lane::Main && state.waiting.load(Ordering::Relaxed) {
+                Duration::from_secs(60 * 10)
             } else {
-                tokio::time::sleep(Duration::from_secs(5)).await;
+                Duration::from_secs(5)
+            };
+            state.beat(lane, idle);
+            sleep_until_shutdown(idle, &state.shutdown).await;
+            if state.shutdown.is_cancelled() {
+                break;
             }
         }
 
         {
-            let mut locked = queue.lock().await;
-            if let Some(mut item) = locked.pop_front() {
-                if database::in_database(&mut *connection.lock().await, &item.id).unwrap() {
+            // the queue is only locked while taking an item, so it can still be inspected while
+            // a long download runs. The item is marked as downloading as it is taken, so the
+            // watchdog can put it back wherever the lane gets stuck
+            let next = {
+                let mut downloading = state.downloading(lane).lock().await;
+                *downloading = queue.lock().await.pop_front();
+                downloading.clone()
+            };
+            if let Some(mut item) = next {
+                let id = item.id.clone();
+                match spawn_with_connection(&connection, move |conn| {
+                    database::in_database(conn, &id)
+                })
+                .await
+                {
+                    Ok(true) => {
+                        *state.downloading(lane).lock().await = None;
+                        continue;
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        // put the item back and give the database a moment, it is likely busy
+                        error!("failed to check if {} is downloaded: {}", item.id, e);
+                        *state.downloading(lane).lock().await = None;
+                        queue.lock().await.push_front(item);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                }
+
+                // items already known to be large, such as rate limited ones coming back from
+                // the retry queue, go straight to the large lane without spending an attempt
+                if defer_large && state.large_sizes.lock().await.contains_key(&item.id) {
+                    *state.downloading(lane).lock().await = None;
+                    state.large_queue.lock().await.push_back(item);
                     continue;
                 }
 
-                info!("downloading {}", item.baseUrl);
+                info!("downloading {}", label(&item));
                 item.download_success = false;
                 item.download_attempts += 1;
-                if media::download_item(config, agent, &item).await.is_ok() {
-                    info!("download successful");
-                    item.download_success = true;
+                *state.downloading(lane).lock().await = Some(item.clone());
+                // recorded before downloading, so an attempt that crashes the client still counts
+                let (id, attempts) = (item.id.clone(), item.download_attempts);
+                if let Err(e) = spawn_with_connection(&connection, move |conn| {
+                    database::record_attempt(conn, &id, attempts)
+                })
+                .await
+                {
+                    error!("failed to record download attempt of {}: {}", item.id, e);
                 }
+                let large_size = state.large_sizes.lock().await.get(&item.id).copied();
+                // older apis can't serve media, leaving only the item's saved base url, which may
+                // still be valid if the rate limit was short
+                let retried = state.retrying.lock().await.contains(&item.id)
+                    && state.api_serves_media(config, agent).await;
+                let recorded_sha256 = match config.skip_if_present {
+                    SkipIfPresent::Hash => recorded_sha256(&connection, &item.id).await,
+                    _ => None,
+                };
+                let result = match large_size {
+                    Some(len) if lane == Lane::Large => {
+                        media::download_deferred(
+                            config,
+                            agent,
+                            state.throughput(lane),
+                            &state.limiter,
+                            &item,
+                            len,
+                        )
+                        .await
+                    }
+                    _ if retried => {
+                        media::download_through_api(
+                            config,
+                            agent,
+                            state.throughput(lane),
+                            &state.limiter,
+                            &item,
+                            defer_large,
+                        )
+                        .await
+                    }
+                    _ => {
+                        media::download_item(
+                            config,
+                            agent,
+                            state.throughput(lane),
+                            &state.limiter,
+                            &item,
+                            defer_large,
+                            recorded_sha256.as_deref(),
+                        )
+                        .await
+                    }
+                };
+                *state.downloading(lane).lock().await = None;
+
+                // nothing was downloaded, so the attempt is handed over to the large lane
+                if let Err(MediaError::Deferred { bytes }) = result {
+                    info!(
+                        "{} is {} bytes, moving it to the large file lane",
+                        label(&item),
+                        bytes
+                    );
+                    item.download_attempts -= 1;
+                    state
+                        .large_sizes
+                        .lock()
+                        .await
+                        .insert(item.id.clone(), bytes);
+                    state.large_queue.lock().await.push_back(item);
+                    continue;
+                }
+
+                // rate limits aren't the item's fault, so rather than spend an attempt it is put
+                // on the retry queue, which outlives this run. The attempt was already recorded,
+                // saving the retry records the lower count
+                if let Err(MediaError::RateLimited { retry_after }) = result {
+                    let retry_at =
+                        unix_time() + retry_after.unwrap_or(DEFAULT_RETRY_AFTER).as_secs();
+                    item.download_attempts -= 1;
+                    item.last_error = Some(String::from("rate limited"));
+                    let (account, retried) = (queue_account(config).to_string(), item.clone());
+                    match spawn_with_connection(&connection, move |conn| {
+                        database::save_retry(conn, &account, &retried, retry_at)
+                    })
+                    .await
+                    {
+                        Ok(()) => {
+                            warn!(
+                                "{} was rate limited, retrying it in {} seconds",
+                                label(&item),
+                                retry_at - unix_time().min(retry_at)
+                            );
+                            state.retrying.lock().await.remove(&item.id);
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("failed to add {} to the retry queue: {}", item.id, e);
+                            queue.lock().await.push_back(item);
+                            continue;
+                        }
+                    }
+                }
+
+                let outcome = match result {
+                    Ok(mut outcome) => {
+                        if config.embed_exif {
+                            check_exif(&mut outcome, &item).await;
+                        }
+                        if config.download_contributor_avatars {
+                            save_avatar(config, agent, state, &item).await;
+                        }
+                        hook::post_download(config, &item, &outcome).await;
+                        info!(
+                            "downloaded {}, {} bytes in {:?}",
+                            label(&item),
+                            outcome.bytes,
+                            outcome.duration
+                        );
+                        item.download_success = true;
+                        item.last_error = None;
+                        state.stats.downloaded.fetch_add(1, Ordering::Relaxed);
+                        state
+                            .stats
+                            .bytes
+                            .fetch_add(outcome.bytes, Ordering::Relaxed);
+                        Some(outcome)
+                    }
+                    Err(e) => {
+                        // the url won't change until the page is fetched again, so don't retry it
+                        if let MediaError::InvalidBaseUrl(_) = e {
+                            warn!("skipping {}: {}", label(&item), e);
+                            item.download_attempts = MAX_DOWNLOAD_ATTEMPTS;
+                        } else {
+                            warn!(
+                                "attempt {} to download {} failed: {}",
+                                item.download_attempts,
+                                label(&item),
+                                e
+                            );
+                        }
+                        item.last_error = Some(e.to_string());
+                        None
+                    }
+                };
 
                 match (item.download_success, item.download_attempts) {
-                    (true, _) | (false, 4) => {
+                    (true, _) | (false, MAX_DOWNLOAD_ATTEMPTS..) => {
                         if !item.download_success {
-                            error!("failed to download item {} after 4 attempts", item.id);
+                            state.stats.failed.fetch_add(1, Ordering::Relaxed);
+                            error!(
+                                "failed to download {} after {} attempts",
+                                label(&item),
+                                MAX_DOWNLOAD_ATTEMPTS
+                            );
                         }
 
+                        state.large_sizes.lock().await.remove(&item.id);
+                        state.retrying.lock().await.remove(&item.id);
+                        let item_label = label(&item).to_string();
                         let db_conn = connection.clone();
+                        let skip_metadata = config.skip_metadata;
+                        let account = queue_account(config).to_string();
                         let res = tokio::task::spawn_blocking(move || {
-                            database::save_media_item(&mut *db_conn.blocking_lock(), &item)
+                            let mut db_conn = db_conn.get()?;
+                            if !item.download_success {
+                                database::save_dead_letter(&mut db_conn, &account, &item)?;
+                            } else if skip_metadata {
+                                database::save_media_item_minimal(
+                                    &mut db_conn,
+                                    &item,
+                                    outcome.as_ref(),
+                                )?;
+                            } else {
+                                database::save_media_item(&mut db_conn, &item, outcome.as_ref())?;
+                            }
+                            database::remove_retry(&mut db_conn, &item.id)?;
+                            database::clear_attempts(&mut db_conn, &item.id)
                         });
 
                         match res.await {
-                            Ok(_) => info!("saved media item to database"),
-                            Err(e) => error!("failed to save media item to database {}", e),
+                            Ok(Ok(_)) => info!("saved {} to the database", item_label),
+                            Ok(Err(e)) => {
+                                error!("failed to save {} to the database: {}", item_label, e)
+                            }
+                            Err(e) => {
+                                error!("failed to save {} to the database: {}", item_label, e)
+                            }
                         }
                     }
                     (false, _) => {
-                        locked.push_back(item);
+                        queue.lock().await.push_back(item);
                     }
                 }
+
+                // pace every download the same way, whether it succeeded or will be retried
+                let delay = config.inter_download_delay();
+                if !delay.is_zero() {
+                    state.beat(lane, delay);
+                    sleep_until_shutdown(delay, &state.shutdown).await;
+                }
             }
         }
 
@@ -197,50 +1236,923 @@ pub async fn download_items(
     }
 }
 
-pub async fn download_scan(config: &Config, agent: &Client, database: DbConnection) {
-    let database = Arc::new(Mutex::new(database));
-    let download_queue: Mutex<VecDeque<MediaItem>> = Mutex::new(VecDeque::with_capacity(50));
-    let processing = AtomicBool::new(false);
-    let waiting = AtomicBool::new(false);
+/// Run a download lane, restarting it whenever it goes `worker_stall_timeout_secs` without making
+/// progress, e.g. stuck waiting on the database, a lock or a response that never comes. The lane
+/// only uses the database from blocking threads, so a wait on it can be abandoned like any other
+async fn supervise(
+    config: &Config,
+    agent: &Client,
+    connection: DbPool,
+    state: &ScanState,
+    lane: Lane,
+) {
+    let timeout = Duration::from_secs(config.worker_stall_timeout_secs);
+    if timeout.is_zero() {
+        return download_items(config, agent, connection, state, lane).await;
+    }
+
+    let worker = || download_items(config, agent, connection.clone(), state, lane);
+    while !watch(state, lane, timeout, worker()).await {
+        error!(
+            "the {} lane made no progress for {} seconds, restarting it",
+            match lane {
+                Lane::Main => "download",
+                Lane::Large => "large file",
+            },
+            timeout.as_secs()
+        );
+        requeue_stalled(&connection, queue_account(config), state, lane, timeout).await;
+    }
+}
+
+/// run a lane's worker until it finishes, returning true, or until the lane goes `timeout`
+/// without making progress, returning false
+async fn watch(
+    state: &ScanState,
+    lane: Lane,
+    timeout: Duration,
+    worker: impl Future<Output = ()>,
+) -> bool {
+    state.beat(lane, Duration::ZERO);
+    let watchdog = async {
+        while !state.stalled(lane, timeout) {
+            tokio::time::sleep(WATCHDOG_INTERVAL.min(timeout / 2)).await;
+        }
+    };
+    tokio::select! {
+        _ = worker => true,
+        _ = watchdog => false,
+    }
+}
+
+/// Put the item a stalled lane was downloading back on the front of its queue. The stuck attempt
+/// counts, so an item that keeps hanging is given up on once it has used every attempt, rather
+/// than jamming the lane. `account` is the account the lane downloads for.
+async fn requeue_stalled(
+    connection: &DbPool,
+    account: &str,
+    state: &ScanState,
+    lane: Lane,
+    timeout: Duration,
+) {
+    // the stuck worker has been dropped, releasing anything it held
+    let mut item = match state.downloading(lane).lock().await.take() {
+        Some(item) => item,
+        None => return,
+    };
+    item.last_error = Some(format!(
+        "made no progress for {} seconds",
+        timeout.as_secs()
+    ));
+
+    if item.download_attempts < MAX_DOWNLOAD_ATTEMPTS {
+        warn!("{} got stuck, it will be tried again", label(&item));
+        state.queue(lane).lock().await.push_front(item);
+        return;
+    }
+
+    state.stats.failed.fetch_add(1, Ordering::Relaxed);
+    error!(
+        "failed to download {} after {} attempts",
+        label(&item),
+        MAX_DOWNLOAD_ATTEMPTS
+    );
+    state.large_sizes.lock().await.remove(&item.id);
+    state.retrying.lock().await.remove(&item.id);
+    let item_label = label(&item).to_string();
+    let account = account.to_string();
+    if let Err(e) = spawn_with_connection(connection, move |conn| {
+        database::save_dead_letter(conn, &account, &item)?;
+        database::remove_retry(conn, &item.id)?;
+        database::clear_attempts(conn, &item.id)
+    })
+    .await
+    {
+        error!("failed to save {} to the database: {}", item_label, e);
+    }
+}
+
+/// sleep for the given duration, waking early if a shutdown is requested
+async fn sleep_until_shutdown(duration: Duration, shutdown: &CancellationToken) {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => {}
+        _ = shutdown.cancelled() => {}
+    }
+}
+
+/// wait for ctrl-c, or SIGTERM on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("failed to listen for ctrl-c: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Download new media until the client is asked to shut down, or scanning fails in a way that
+/// retrying can't fix. `limiter` is shared by every account scanned at once
+pub async fn download_scan(
+    config: &Config,
+    agent: &Client,
+    database: DbPool,
+    scan_turn: &Mutex<()>,
+    limiter: Arc<RateLimiter>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let state = Arc::new(ScanState {
+        limiter,
+        ..Default::default()
+    });
+    let fatal = std::sync::Mutex::new(None);
 
     tokio_scoped::scope(|scope| {
-        // load new items
-        scope.spawn(load_new_items(
+        if let Some(address) = config.status_address {
+            scope.spawn(status::serve(address, state.clone(), database.clone()));
+        }
+
+        scope.spawn(async {
+            shutdown_signal().await;
+            info!("shutdown requested, finishing the current download");
+            state.shutdown.cancel();
+        });
+
+        // stop once the run has used up its time, as though asked to shut down
+        if let Some(max_runtime) = config.max_runtime {
+            let state = &state;
+            scope.spawn(async move {
+                sleep_until_shutdown(max_runtime, &state.shutdown).await;
+                if !state.shutdown.is_cancelled() {
+                    info!(
+                        "reached the maximum runtime of {} seconds, finishing the current download",
+                        max_runtime.as_secs()
+                    );
+                    state.shutdown.cancel();
+                }
+            });
+        }
+
+        // periodically log the download speed while downloading
+        scope.spawn(async {
+            while !state.shutdown.is_cancelled() {
+                sleep_until_shutdown(THROUGHPUT_LOG_INTERVAL, &state.shutdown).await;
+                let rate = state.bytes_per_sec();
+                if rate > 0 {
+                    info!("downloading at {} bytes/sec (30 second average)", rate);
+                }
+            }
+        });
+
+        // periodically rebuild the sprite sheet of recent downloads
+        if config.sprite_sheet_interval_secs > 0 {
+            scope.spawn(async {
+                let interval = Duration::from_secs(config.sprite_sheet_interval_secs);
+                while !state.shutdown.is_cancelled() {
+                    sleep_until_shutdown(interval, &state.shutdown).await;
+                    if state.shutdown.is_cancelled() {
+                        break;
+                    }
+                    // decoding images is slow, so let the runtime move other tasks off this thread
+                    let result =
+                        tokio::task::block_in_place(|| sprites::generate(config, &database));
+                    match result {
+                        Ok(items) => debug!("rebuilt the sprite sheet with {} items", items),
+                        Err(e) => error!("failed to build the sprite sheet: {}", e),
+                    }
+                }
+            });
+        }
+
+        // every account shares the database, so only the primary account checks the stored files
+        if config.integrity_check_rate > 0 && config.account.is_none() {
+            scope.spawn(integrity::run(config, agent, &database, &state.shutdown));
+        }
+
+        // periodically save the queue, items are cleared from it as they complete
+        scope.spawn(async {
+            let mut last = Vec::new();
+            while !state.shutdown.is_cancelled() {
+                sleep_until_shutdown(QUEUE_SAVE_INTERVAL, &state.shutdown).await;
+                save_queue(config, &state, &database, &mut last).await;
+            }
+        });
+
+        // load new items, this only fetches metadata so it can stop immediately
+        scope.spawn(async {
+            tokio::select! {
+                result = load_new_items(config, agent, database.clone(), &state, scan_turn) => {
+                    if let Err(e) = result {
+                        info!("stopping, finishing the current download");
+                        *fatal.lock().unwrap() = Some(e);
+                        state.shutdown.cancel();
+                    }
+                }
+                _ = state.shutdown.cancelled() => {}
+            }
+        });
+
+        // download items, restarting lanes that get stuck
+        scope.spawn(supervise(
             config,
             agent,
             database.clone(),
-            &download_queue,
-            &processing,
-            &waiting,
+            &state,
+            Lane::Main,
         ));
+        if config.large_file_threshold > 0 {
+            scope.spawn(supervise(
+                config,
+                agent,
+                database.clone(),
+                &state,
+                Lane::Large,
+            ));
+        }
+    });
 
-        // download items
-        scope.spawn(download_items(
-            config,
-            agent,
-            database,
-            &download_queue,
-            &processing,
-            &waiting,
-        ));
-    })
+    // the current download has finished by now, so this leaves only what is still to do
+    save_queue(config, &state, &database, &mut Vec::new()).await;
+
+    let remaining = state.queue.lock().await.len() + state.large_queue.lock().await.len();
+    info!(
+        "session complete for the {} account: {} items downloaded ({} bytes), {} items failed, {}",
+        config.account_name(),
+        state.stats.downloaded.load(Ordering::Relaxed),
+        state.stats.bytes.load(Ordering::Relaxed),
+        state.stats.failed.load(Ordering::Relaxed),
+        match remaining {
+            0 => String::from("queue fully drained"),
+            n => format!("{} queued items saved for the next run", n),
+        }
+    );
+
+    match fatal.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Restart the scan of this account from the beginning, both on the api and locally
+pub async fn rescan(
+    config: &Config,
+    agent: &Client,
+    connection: &mut DbConnection,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    media::rescan(config, agent).await?;
+    config.reset_initial_scan_complete(connection)
+}
+
+/// warn if an account's google login will need to be linked again before it can be scanned
+async fn check_token_status(config: &Config, agent: &Client) {
+    match media::token_status(config, agent).await {
+        Ok(status) if status.needs_reauth => warn!(
+            "google rejected the {} account's login, you will be asked to link it again",
+            config.account_name()
+        ),
+        Ok(status) if !status.linked => warn!(
+            "the api has no google account linked to the {} account, downloads will fail until it is re-linked",
+            config.account_name()
+        ),
+        Ok(status) => debug!(
+            "google token expires in {} seconds",
+            status.expires_in_secs.unwrap_or_default()
+        ),
+        Err(e) => debug!("unable to get google token status: {}", e),
+    }
 }
 
 #[tokio::main]
 pub async fn run() {
-    //XXX: adjustable scan times
     //XXX: Testing
 
+    let args = Args::parse();
+
     pretty_env_logger::init();
-    let agent = agent();
 
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let mut database = establish_connection(&database_url).expect("failed to connect to database");
+    let command = args.command.unwrap_or(Command::Run);
+
+    // held until the client exits, only the long running command needs guarding
+    let _pid_file = match (&args.pid_file, &command) {
+        (Some(path), Command::Run) => match pidfile::PidFile::acquire(path) {
+            Ok(pid_file) => Some(pid_file),
+            Err(e) => {
+                error!("refusing to start: {}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+
+    let database_url = match std::env::var("DATABASE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            let url = database::default_database_url().expect("failed to find a database path");
+            debug!("DATABASE_URL is not set, using {}", url);
+            url
+        }
+    };
+    // reports only read the database, so they may run alongside a client that is downloading
+    let _database_lock = match command {
+        Command::Stats { .. } | Command::ExportFailed { .. } => None,
+        _ => match database::lock_database(&database_url) {
+            Ok(lock) => lock,
+            Err(e) => {
+                error!("refusing to start: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let mut database = establish_connection(&database_url)
+        .await
+        .expect("failed to connect to database");
     run_migrations(&mut database).expect("failed to run migrations");
 
-    let config = Config::load(&agent, &mut database)
+    // these commands only operate on the local database, so there is no need to contact the api
+    match command {
+        Command::Maintenance => {
+            match database::run_maintenance(&mut database) {
+                Ok((before, after)) => info!(
+                    "database maintenance complete, {} bytes -> {} bytes ({} bytes reclaimed)",
+                    before,
+                    after,
+                    before - after
+                ),
+                Err(e) => {
+                    error!("database maintenance failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Command::ExportFailed { ref output } => {
+            match report::export_failed(&mut database, output) {
+                Ok(count) => info!("wrote {} failed items to {:?}", count, output),
+                Err(e) => {
+                    error!("failed to export failed items: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Command::Stats {
+            ref after,
+            ref before,
+        } => {
+            match database::media_stats(&mut database, after.as_deref(), before.as_deref()) {
+                Ok(stats) => {
+                    match (after, before) {
+                        (None, None) => println!("all items"),
+                        (after, before) => println!(
+                            "items created from {} until {}",
+                            after.as_deref().unwrap_or("the beginning"),
+                            before.as_deref().unwrap_or("now")
+                        ),
+                    }
+                    println!("  total:      {}", stats.items);
+                    println!("  downloaded: {}", stats.downloaded);
+                    println!("  failed:     {}", stats.failed);
+                    println!("  pending:    {}", stats.pending);
+                    println!("  excluded:   {}", stats.excluded);
+                    println!("  size:       {} bytes", stats.bytes);
+                }
+                Err(e) => {
+                    error!("failed to load stats: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Command::RetryDeadLetter => {
+            match database::retry_dead_letter(&mut database) {
+                Ok(count) => {
+                    info!(
+                        "{} failed items will be downloaded again on the next run",
+                        count
+                    );
+                }
+                Err(e) => {
+                    error!("failed to retry dead letter items: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Command::Reindex { ref dir } => {
+            match reindex::reindex(&mut database, dir) {
+                Ok(summary) => info!(
+                    "reindexed {:?}: {} files recorded as downloaded, {} already recorded, {} skipped",
+                    dir, summary.added, summary.existing, summary.skipped
+                ),
+                Err(e) => {
+                    error!("failed to reindex {:?}: {}", dir, e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Command::Purge {
+            delete_files,
+            full,
+            yes,
+        } => {
+            let options = purge::PurgeOptions {
+                delete_files,
+                full,
+                config_file: args.config.clone(),
+            };
+            if !yes && !purge::confirm(&options).unwrap_or(false) {
+                info!("purge cancelled");
+                return;
+            }
+
+            match purge::purge(&mut database, &options) {
+                Ok(summary) => {
+                    info!("purged {} items and {} files", summary.items, summary.files);
+                    if !full {
+                        info!("run `syncabull rescan` to have the api start this account from the beginning");
+                    }
+                }
+                Err(e) => {
+                    error!("failed to purge local state: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Command::Doctor => {
+            if !doctor::run(&mut database, args.config.as_deref()).await {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Command::CheckApi => {
+            if !doctor::check_api(&mut database, args.config.as_deref()).await {
+                std::process::exit(1);
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    let mut config = Config::load(&mut database, None, args.config.as_deref())
         .await
         .expect("failed to load config");
+    config.skip_metadata |= args.no_metadata;
+    if let Some(limit_rate) = args.limit_rate {
+        config.max_download_speed = limit_rate;
+    }
+    config.start_token = args.start_token.clone();
+    config.max_runtime = args.max_runtime;
+    let agent = agent(&config);
+
+    if let Err(e) = database::apply_pragmas(&mut database, &config) {
+        error!("failed to configure database: {}", e);
+    }
+
+    match command {
+        Command::Run => {
+            if let Err(e) = database::optimize(&mut database) {
+                error!("failed to optimize database: {}", e);
+            }
+
+            // older apis ignore what they don't support, e.g. carrying on from where the scan left
+            // off rather than starting from the token
+            let mut required = Vec::new();
+            if config.start_token.is_some() {
+                required.push(("page_token", "start scanning from a page token"));
+            }
+            if config.include_archived_media {
+                required.push(("include_archived", "include archived media"));
+            }
+            if !required.is_empty() {
+                let features = match media::capabilities(&config, &agent).await {
+                    Ok(capabilities) => capabilities.map(|c| c.features).unwrap_or_default(),
+                    Err(e) => {
+                        error!("unable to check the api's capabilities: {}", e);
+                        std::process::exit(1);
+                    }
+                };
+                for (feature, action) in required {
+                    if !features.iter().any(|f| f == feature) {
+                        error!("the api is too old to {}", action);
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            let mut accounts = Vec::with_capacity(config.additional_accounts.len());
+            for account in config.additional_accounts.iter() {
+                let mut account_config =
+                    Config::load(&mut database, Some(account), config.config_file.as_deref())
+                        .await
+                        .expect("failed to load account config");
+                account_config.skip_metadata |= args.no_metadata;
+                if let Some(limit_rate) = args.limit_rate {
+                    account_config.max_download_speed = limit_rate;
+                }
+                account_config.max_runtime = args.max_runtime;
+                accounts.push(account_config);
+            }
+            let accounts: Vec<&Config> = std::iter::once(&config).chain(accounts.iter()).collect();
+
+            for account in accounts.iter() {
+                check_token_status(account, &agent).await;
+            }
+
+            // every account is scanned at once, taking turns to fetch pages. Each scan blocks
+            // until it is finished, so they are spawned rather than joined
+            let pool =
+                establish_pool(&database_url, &config).expect("failed to create database pool");
+            let scan_turn = Mutex::new(());
+            let limiter = Arc::new(RateLimiter::new(config.max_download_speed));
+            let mut results: Vec<_> = accounts.iter().map(|_| None).collect();
+            tokio_scoped::scope(|scope| {
+                for (account, result) in accounts.iter().zip(results.iter_mut()) {
+                    let (agent, pool, scan_turn) = (&agent, pool.clone(), &scan_turn);
+                    let limiter = limiter.clone();
+                    scope.spawn(async move {
+                        *result =
+                            Some(download_scan(account, agent, pool, scan_turn, limiter).await);
+                    });
+                }
+            });
+
+            let mut failed = false;
+            for (account, result) in accounts.iter().zip(results) {
+                if let Some(Err(e)) = result {
+                    error!(
+                        "scanning the {} account stopped: {}",
+                        account.account_name(),
+                        e
+                    );
+                    failed = true;
+                }
+            }
+            if failed {
+                std::process::exit(1);
+            }
+        }
+        Command::Rescan => {
+            if let Err(e) = rescan(&config, &agent, &mut database).await {
+                error!("failed to trigger rescan: {}", e);
+                std::process::exit(1);
+            }
+            info!("rescan requested, the next run will scan this account from the beginning");
+        }
+        Command::RotatePasscode => {
+            // the next registration would derive the old passcode again
+            if config.registration_seed.is_some() {
+                error!("this client's passcode is derived from its registration seed, so it can't be rotated");
+                std::process::exit(1);
+            }
+            // an override would replace the new passcode on the next run, locking this client out
+            let overridden_by = if std::env::var("LOCAL_PASSCODE").is_ok() {
+                Some(String::from("LOCAL_PASSCODE"))
+            } else if let Some(path) = &config.config_file {
+                match database::config_file_sets(path, "local_passcode") {
+                    Ok(true) => Some(format!("local_passcode in {:?}", path)),
+                    Ok(false) => None,
+                    Err(e) => {
+                        error!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                None
+            };
+            if let Some(overridden_by) = overridden_by {
+                error!(
+                    "{} overrides the saved passcode, remove it before rotating the passcode",
+                    overridden_by
+                );
+                std::process::exit(1);
+            }
+
+            let passcode = match media::rotate_passcode(&config, &agent).await {
+                Ok(passcode) => passcode,
+                Err(e) => {
+                    error!("failed to rotate passcode: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            // the api only returns the new passcode once, so it must not be lost
+            config.local_passcode = Some(passcode.clone());
+            if let Err(e) = config.save(&mut database) {
+                error!("failed to save the new passcode, it is {}: {}", passcode, e);
+                std::process::exit(1);
+            }
+            info!("passcode rotated, the old passcode no longer works");
+        }
+        Command::GrantScopes => {
+            match media::capabilities(&config, &agent).await {
+                Ok(Some(capabilities)) if capabilities.additional_scopes.is_empty() => {
+                    info!("the api has no additional scopes to grant");
+                    return;
+                }
+                Ok(_) => {}
+                Err(e) => debug!("unable to get api capabilities: {}", e),
+            }
+            if let Err(e) = media::authenticate(&config, &agent, true).await {
+                error!("failed to grant additional scopes: {}", e);
+                std::process::exit(1);
+            }
+            match media::token_status(&config, &agent).await {
+                Ok(status) => info!("granted scopes: {}", status.scopes.join(" ")),
+                Err(e) => debug!("unable to get google token status: {}", e),
+            }
+        }
+        Command::Audit { list } => {
+            let report = match audit::audit(&config, &agent, &mut database).await {
+                Ok(report) => report,
+                Err(e) => {
+                    error!("failed to audit the backup: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            println!("  on google:      {}", report.library);
+            println!("  backed up:      {}", report.backed_up);
+            println!("  missing:        {}", report.missing.len());
+            println!("  not downloaded: {}", report.not_downloaded.len());
+            println!("  removed:        {}", report.removed.len());
+            if list {
+                for (heading, ids) in [
+                    ("missing", &report.missing),
+                    ("not downloaded", &report.not_downloaded),
+                    ("removed from google", &report.removed),
+                ] {
+                    if !ids.is_empty() {
+                        println!("{}:", heading);
+                        for id in ids.iter() {
+                            println!("  {}", id);
+                        }
+                    }
+                }
+            }
+        }
+        Command::Maintenance
+        | Command::Stats { .. }
+        | Command::ExportFailed { .. }
+        | Command::RetryDeadLetter
+        | Command::Reindex { .. }
+        | Command::Purge { .. }
+        | Command::Doctor
+        | Command::CheckApi => {
+            unreachable!("handled before loading config")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use warp::Filter;
+
+    use super::{
+        database, expire_queue,
+        media::{self, DownloadOutcome},
+        ratelimit::RateLimiter,
+        recorded_sha256, requeue_stalled, spawn_with_connection,
+        test_utils::{config, connection, item, pool},
+        throughput::Throughput,
+        watch, Lane, ScanState, MAX_DOWNLOAD_ATTEMPTS,
+    };
+
+    #[tokio::test]
+    async fn test_present_file_with_its_recorded_hash_is_skipped() {
+        let pool = pool();
+        let store = tempfile::tempdir().unwrap();
+        let config = config(
+            &mut pool.get().unwrap(),
+            store.path(),
+            &[("skip_if_present", "hash")],
+        );
+
+        // google serves different content of the same size, so a download would replace the file
+        let route = warp::path!("lr" / String).map(|_| "fresh content");
+        let (address, serving) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        let serving = tokio::spawn(serving);
+        let mut stored = item("stored", 0);
+        stored.baseUrl = format!("http://{}/lr/stored", address);
+
+        let path = store.path().join("stored");
+        std::fs::write(&path, "saved content").unwrap();
+        let outcome = DownloadOutcome {
+            path: path.clone(),
+            bytes: 13,
+            duration: Duration::ZERO,
+            sha256: Some(media::hash_file(path.clone()).await.unwrap()),
+        };
+        stored.download_success = true;
+        database::save_media_item(&mut pool.get().unwrap(), &stored, Some(&outcome)).unwrap();
+
+        // forgetting every item, as purge does, leaves the recorded hash to check the file against
+        database::clear_media(&mut pool.get().unwrap()).unwrap();
+        let recorded = recorded_sha256(&pool, "stored").await;
+        assert_eq!(recorded, outcome.sha256);
+
+        let skipped = media::download_item(
+            &config,
+            &reqwest::Client::new(),
+            &Throughput::default(),
+            &RateLimiter::default(),
+            &stored,
+            false,
+            recorded.as_deref(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(skipped.sha256, outcome.sha256);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "saved content");
+        serving.abort();
+    }
 
-    download_scan(&config, &agent, database).await;
+    #[tokio::test]
+    async fn test_present_file_is_skipped_without_fetching_it() {
+        let pool = pool();
+        let store = tempfile::tempdir().unwrap();
+        let config = config(
+            &mut pool.get().unwrap(),
+            store.path(),
+            &[("skip_if_present", "size")],
+        );
+
+        let fetched = Arc::new(AtomicUsize::new(0));
+        let counter = fetched.clone();
+        let route = warp::get()
+            .and(warp::path!("lr" / String))
+            .map(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                "fresh content"
+            })
+            .or(warp::head()
+                .and(warp::path!("lr" / String))
+                .map(|_| "fresh content"));
+        let (address, serving) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        let serving = tokio::spawn(serving);
+        let mut stored = item("stored", 0);
+        stored.baseUrl = format!("http://{}/lr/stored", address);
+        std::fs::write(store.path().join("stored"), "saved content").unwrap();
+
+        let skipped = media::download_item(
+            &config,
+            &reqwest::Client::new(),
+            &Throughput::default(),
+            &RateLimiter::default(),
+            &stored,
+            false,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(skipped.bytes, 13);
+        assert_eq!(fetched.load(Ordering::SeqCst), 0);
+        serving.abort();
+    }
+
+    #[tokio::test]
+    async fn test_large_retried_item_is_deferred_to_the_large_lane() {
+        let pool = pool();
+        let store = tempfile::tempdir().unwrap();
+
+        // the api reports the item's size, any download beyond the first byte would fail
+        let route = warp::path!("media" / String).map(|_| {
+            warp::reply::with_header(
+                warp::reply::with_status("x", warp::http::StatusCode::PARTIAL_CONTENT),
+                "content-range",
+                "bytes 0-0/1000",
+            )
+        });
+        let (address, serving) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        let serving = tokio::spawn(serving);
+        let config = config(
+            &mut pool.get().unwrap(),
+            store.path(),
+            &[
+                ("webserver_address", &format!("http://{}", address)),
+                ("local_id", "id"),
+                ("local_passcode", "passcode"),
+                ("large_file_threshold", "100"),
+            ],
+        );
+
+        let result = media::download_through_api(
+            &config,
+            &reqwest::Client::new(),
+            &Throughput::default(),
+            &RateLimiter::default(),
+            &item("large", 0),
+            true,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(media::MediaError::Deferred { bytes: 1000 })
+        ));
+        serving.abort();
+    }
+
+    #[tokio::test]
+    async fn test_expired_prefetched_pages_are_downloaded_through_the_api() {
+        let store = tempfile::tempdir().unwrap();
+        let config = config(&mut connection(), store.path(), &[]);
+        let agent = reqwest::Client::new();
+        let state = ScanState::default();
+        state.api_serves_media.set(true).unwrap();
+
+        // two prefetched pages are still queued when their base urls expire
+        let pages = [["first", "second"], ["third", "fourth"]];
+        state
+            .queue
+            .lock()
+            .await
+            .extend(pages.iter().flatten().map(|id| item(id, 0)));
+
+        let mut reload = false;
+        assert!(expire_queue(&config, &agent, &state, &mut reload).await);
+        assert!(!reload);
+        assert_eq!(state.queue.lock().await.len(), 4);
+        let retrying = state.retrying.lock().await.clone();
+        assert!(pages.iter().flatten().all(|id| retrying.contains(*id)));
+
+        // they are all going through the api now, so nothing is left to expire
+        assert!(!expire_queue(&config, &agent, &state, &mut reload).await);
+    }
+
+    #[test]
+    fn test_lane_receiving_data_is_not_stalled() {
+        let state = ScanState::default();
+        assert!(state.stalled(Lane::Main, Duration::from_secs(60)));
+
+        state.throughput.record(1);
+        assert!(!state.stalled(Lane::Main, Duration::from_secs(60)));
+        assert!(state.stalled(Lane::Large, Duration::from_secs(60)));
+
+        state.beat(Lane::Large, Duration::from_secs(60));
+        assert!(!state.stalled(Lane::Large, Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn test_lane_waiting_on_the_database_is_stalled() {
+        let pool = pool();
+        let state = ScanState::default();
+
+        // the pool's only connection is taken, so the lane waits on the database
+        let held = pool.get().unwrap();
+        let waiting = async {
+            let _ = spawn_with_connection(&pool, |conn| database::in_database(conn, "item")).await;
+        };
+        assert!(!watch(&state, Lane::Main, Duration::from_secs(1), waiting).await);
+        drop(held);
+    }
+
+    #[tokio::test]
+    async fn test_hanging_download_is_requeued_until_it_runs_out_of_attempts() {
+        let pool = pool();
+        let state = ScanState::default();
+        let timeout = Duration::from_secs(1);
+        let hang = |attempts| {
+            let state = &state;
+            async move {
+                *state.downloading(Lane::Main).lock().await = Some(item("stuck", attempts));
+                std::future::pending::<()>().await
+            }
+        };
+
+        assert!(!watch(&state, Lane::Main, timeout, hang(1)).await);
+        requeue_stalled(&pool, "", &state, Lane::Main, timeout).await;
+        let requeued = state.queue.lock().await.pop_front().unwrap();
+        assert_eq!(requeued.id, "stuck");
+        assert_eq!(requeued.download_attempts, 1);
+        assert!(state.downloading.lock().await.is_none());
+
+        assert!(!watch(&state, Lane::Main, timeout, hang(MAX_DOWNLOAD_ATTEMPTS)).await);
+        requeue_stalled(&pool, "", &state, Lane::Main, timeout).await;
+        assert!(state.queue.lock().await.is_empty());
+        assert_eq!(state.stats.failed.load(Ordering::Relaxed), 1);
+        let failed = database::failed_media_items(&mut pool.get().unwrap()).unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].id, "stuck");
+
+        // a worker that finishes isn't restarted
+        assert!(watch(&state, Lane::Main, timeout, async {}).await);
+    }
 }
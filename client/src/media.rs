@@ -1,14 +1,32 @@
-use std::time::Duration;
+use std::{
+    fmt::Display,
+    path::PathBuf,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use crate::{config::Config, Id, Passcode};
+use crate::{
+    config::{Config, SkipIfPresent},
+    ratelimit::RateLimiter,
+    throughput::Throughput,
+    Id, Passcode,
+};
 use futures_util::TryStreamExt;
-use log::{error, trace};
-use reqwest::Client;
+use log::{debug, error, info, trace, warn};
+use reqwest::{header, Client, RequestBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
-use shared_libs::json_templates::MediaItem;
+use sha2::{Digest, Sha256};
+use shared_libs::{
+    json_templates::{
+        AuthUrlParameters, Capabilities, ContributorInfo, LibraryPage, LibraryParameters,
+        MediaItem, TokenStatus, AUTH_EXPIRES_IN_HEADER, LAST_PAGE_HEADER,
+    },
+    signing,
+};
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc, Mutex},
     time::Instant,
 };
 use tokio_util::io::StreamReader;
@@ -19,49 +37,429 @@ struct Register {
     passcode: Passcode,
 }
 
+/// a login token matching the api's `Token`, used to claim a completed google login for this client
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Token {
+    /// The auth key from the end of our auth url, identifying this client to the api
+    pub id: String,
+    /// The claim code shown to the user once they have logged in with google
+    pub token: String,
+    pub expiry: SystemTime,
+}
+
+#[derive(Debug, Serialize)]
+struct AuthFailureNotification<'a> {
+    event: &'a str,
+    client_id: Option<&'a Id>,
+    consecutive_failures: u32,
+}
+
+/// the result of a successful download
+#[derive(Debug, Clone)]
+pub struct DownloadOutcome {
+    /// Where the file was stored
+    pub path: PathBuf,
+    /// The size of the stored file
+    pub bytes: u64,
+    /// How long the download took
+    pub duration: Duration,
+    /// The sha256 of the stored file, only computed when `Config::records_sha256` is set
+    pub sha256: Option<String>,
+}
+
+/// how many characters of an item's id are shown in logs
+const LOG_ID_LEN: usize = 12;
+
+/// An item as it is shown in logs, its filename and the start of its id, rather than the whole
+/// item or its very long base url
+pub(crate) struct Label<'a>(&'a MediaItem);
+
+/// show an item in logs by its filename and the start of its id
+pub(crate) fn label(item: &MediaItem) -> Label<'_> {
+    Label(item)
+}
+
+impl Display for Label<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let id = self.0.id.get(..LOG_ID_LEN).unwrap_or(&self.0.id);
+        write!(f, "{} [{}]", self.0.filename, id)
+    }
+}
+
+/// a login url from the api
+#[derive(Debug)]
+pub struct AuthUrl {
+    pub url: String,
+    /// How long the api accepts the login for, None if the api is too old to say
+    pub expires_in: Option<Duration>,
+}
+
+impl AuthUrl {
+    /// how long the user has to complete the login, to append to the prompt showing the url
+    pub fn time_limit(&self) -> String {
+        match self.expires_in {
+            Some(expires_in) => format!(" within {} seconds", expires_in.as_secs()),
+            None => String::new(),
+        }
+    }
+}
+
+/// a page of items from the api
+#[derive(Debug)]
+pub struct MediaPage {
+    pub items: Vec<MediaItem>,
+    /// Whether this is the last page of the library, None if the api is too old to say
+    pub last_page: Option<bool>,
+}
+
+impl MediaPage {
+    /// whether there are more pages after this one. Google can return short or even empty pages
+    /// before the end of the library, so only the api saying this is the last page counts. Older
+    /// apis don't say, so an empty page from them is taken to be the last
+    pub fn has_more(&self) -> bool {
+        match self.last_page {
+            Some(last_page) => !last_page,
+            None => !self.items.is_empty(),
+        }
+    }
+}
+
+/// Why a request to the api, or a download, failed
+#[derive(Debug)]
+pub enum MediaError {
+    /// The api rejected our credentials, or our google account can no longer be used
+    Auth(StatusCode),
+    /// The api holds a google login for us that google has since rejected, the login flow must be
+    /// run again to link a new one
+    ReauthRequired,
+    /// The api or google asked us to slow down, for `retry_after` if they said how long
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    /// The server failed the request, or refused it for a reason other than our credentials
+    Server {
+        status: StatusCode,
+        message: String,
+    },
+    /// The server couldn't be reached, or the connection failed part way through
+    Network(reqwest::Error),
+    Parse(ParseError),
+    /// Reading or writing local files failed, or a download timed out
+    Io(std::io::Error),
+    InvalidBaseUrl(InvalidBaseUrl),
+    /// Returned instead of downloading an item over `large_file_threshold` when it is deferred to
+    /// the large file lane
+    Deferred {
+        bytes: u64,
+    },
+}
+
+impl MediaError {
+    /// the error for an unsuccessful response from the api, or from google for a download
+    async fn from_response(res: Response) -> MediaError {
+        let status = res.status();
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => MediaError::Auth(status),
+            // the api's way of saying our google login was rejected for good
+            StatusCode::PRECONDITION_REQUIRED => MediaError::ReauthRequired,
+            StatusCode::TOO_MANY_REQUESTS => MediaError::RateLimited {
+                retry_after: res
+                    .headers()
+                    .get(header::RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse().ok())
+                    .map(Duration::from_secs),
+            },
+            _ => MediaError::Server {
+                status,
+                message: res.text().await.unwrap_or_default(),
+            },
+        }
+    }
+
+    /// a download that took too long, or stalled
+    fn timed_out(message: &str) -> MediaError {
+        MediaError::Io(std::io::Error::new(std::io::ErrorKind::TimedOut, message))
+    }
+}
+
+impl std::fmt::Display for MediaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MediaError::Auth(status) => write!(f, "authentication with the api failed: {}", status),
+            MediaError::ReauthRequired => {
+                write!(f, "the google login has expired and must be linked again")
+            }
+            MediaError::RateLimited {
+                retry_after: Some(retry_after),
+            } => write!(f, "rate limited for {} seconds", retry_after.as_secs()),
+            MediaError::RateLimited { retry_after: None } => write!(f, "rate limited"),
+            MediaError::Server { status, message } if message.is_empty() => {
+                write!(f, "the server responded {}", status)
+            }
+            MediaError::Server { status, message } => {
+                write!(f, "the server responded {}: {}", status, message)
+            }
+            MediaError::Network(e) => write!(f, "{}", e),
+            MediaError::Parse(e) => write!(f, "{}", e),
+            MediaError::Io(e) => write!(f, "{}", e),
+            MediaError::InvalidBaseUrl(e) => write!(f, "{}", e),
+            MediaError::Deferred { bytes } => {
+                write!(
+                    f,
+                    "item is {} bytes, deferred to the large file lane",
+                    bytes
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for MediaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MediaError::Network(e) => Some(e),
+            MediaError::Parse(e) => Some(e),
+            MediaError::Io(e) => Some(e),
+            MediaError::InvalidBaseUrl(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for MediaError {
+    fn from(e: reqwest::Error) -> Self {
+        MediaError::Network(e)
+    }
+}
+
+impl From<std::io::Error> for MediaError {
+    fn from(e: std::io::Error) -> Self {
+        MediaError::Io(e)
+    }
+}
+
+impl From<ParseError> for MediaError {
+    fn from(e: ParseError) -> Self {
+        MediaError::Parse(e)
+    }
+}
+
+impl From<InvalidBaseUrl> for MediaError {
+    fn from(e: InvalidBaseUrl) -> Self {
+        MediaError::InvalidBaseUrl(e)
+    }
+}
+
+/// The api's response could not be parsed, it may be an error page from a proxy or a response
+/// from an incompatible version of the api
+#[derive(Debug)]
+pub struct ParseError {
+    pub error: serde_json::Error,
+    /// The raw response body
+    pub body: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unable to parse the api's response: {}", self.error)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// An item's base url can't be downloaded from, retrying with the same url would never succeed
+#[derive(Debug)]
+pub struct InvalidBaseUrl {
+    pub base_url: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for InvalidBaseUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unusable base url {:?}: {}", self.base_url, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidBaseUrl {}
+
+/// Build the url to fetch a google base url with the given parameter, e.g. `d` for the original
+/// file. Parameters go after an `=` at the end of the url's path, so any already there are
+/// replaced, and a query string is kept after them.
+fn download_url(base_url: &str, param: &str) -> Result<String, InvalidBaseUrl> {
+    let invalid = |reason: &str| InvalidBaseUrl {
+        base_url: base_url.to_string(),
+        reason: reason.to_string(),
+    };
+
+    if base_url.trim().is_empty() {
+        return Err(invalid("it is empty"));
+    }
+    let mut url = reqwest::Url::parse(base_url.trim()).map_err(|e| invalid(&e.to_string()))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid("it is not an http url"));
+    }
+
+    let path = url.path();
+    let segment_start = path.rfind('/').map_or(0, |i| i + 1);
+    let path = match path[segment_start..].find('=') {
+        Some(i) => &path[..segment_start + i],
+        None => path,
+    };
+    if path.len() == segment_start {
+        return Err(invalid("it has no path"));
+    }
+
+    url.set_path(&format!("{}={}", path, param));
+    url.set_fragment(None);
+    Ok(url.to_string())
+}
+
+/// send a request to each of the given servers in turn, failing over to the next server only if
+/// the current one is unreachable. Returns the address of the server that responded.
+async fn send_with_failover<F>(
+    addresses: &[String],
+    build: F,
+) -> Result<(String, Response), MediaError>
+where
+    F: Fn(&str) -> RequestBuilder,
+{
+    let mut last_error = None;
+    for address in addresses {
+        match build(address).send().await {
+            Ok(res) => return Ok((address.clone(), res)),
+            Err(e) if e.is_connect() || e.is_timeout() => {
+                warn!(
+                    "server {} is unreachable, trying next server: {}",
+                    address, e
+                );
+                last_error = Some(e);
+            }
+            Err(e) => return Err(MediaError::Network(e)),
+        }
+    }
+
+    match last_error {
+        Some(e) => Err(MediaError::Network(e)),
+        None => Err(MediaError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no servers configured",
+        ))),
+    }
+}
+
+/// derive an id and passcode from a registration seed, the same seed always gives the same
+/// credentials
+fn seeded_credentials(seed: &str) -> (Id, Passcode) {
+    let hash = |purpose: &str| format!("{:x}", Sha256::digest(format!("{}\n{}", purpose, seed)));
+    let mut id = hash("syncabull id");
+    id.truncate(32);
+    (id, hash("syncabull passcode"))
+}
+
+/// authorise a request to `path` with the preshared key, or sign it when signing is enabled
+fn with_preshared_key(config: &Config, request: RequestBuilder, path: &str) -> RequestBuilder {
+    match &config.request_signing_key {
+        Some(key) => {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            request.header(signing::TIMESTAMP_HEADER, timestamp).header(
+                signing::SIGNATURE_HEADER,
+                signing::sign(key, "GET", path, timestamp),
+            )
+        }
+        None => request.header("x-psk", &config.preshared_key),
+    }
+}
+
+/// check the api accepts our preshared key, or request signature, without registering
+pub(crate) async fn check_psk(
+    config: &Config,
+    agent: &Client,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let _permit = config.api_permits.acquire().await;
+    let (_, res) = send_with_failover(&config.webserver_addresses, |address| {
+        with_preshared_key(
+            config,
+            agent.get(format!("{}/psk_check", address)),
+            "/psk_check",
+        )
+    })
+    .await?;
+
+    if !res.status().is_success() {
+        return Err(format!("the api rejected the key: {}", res.status()).into());
+    }
+
+    Ok(())
+}
+
 /// connect to the webserver and register an account, this will return an id and passcode
-/// that we will need to peform further actions
+/// that we will need to peform further actions, along with the address of the server that
+/// accepted the registration. With a registration seed the credentials are derived from it, and
+/// registering again returns the existing account.
 pub(crate) async fn register(
     config: &Config,
     agent: &Client,
-) -> Result<(Id, Passcode), Box<dyn std::error::Error>> {
-    let url = format!("{}/register", config.webserver_address);
-    trace!("registering with server at address: {}", &url);
-    let res = agent
-        .get(&url)
-        .header("x-psk", &config.preshared_key)
-        .send()
-        .await?;
+) -> Result<(Id, Passcode, String), MediaError> {
+    let _permit = config.api_permits.acquire().await;
+    trace!(
+        "registering with servers: {:?}",
+        &config.webserver_addresses
+    );
+    let seeded = config.registration_seed.as_deref().map(seeded_credentials);
+    let (address, res) = send_with_failover(&config.webserver_addresses, |address| {
+        let mut request = agent.get(format!("{}/register", address));
+        if let Some((id, passcode)) = &seeded {
+            request = request.basic_auth(id, Some(passcode));
+        }
+        with_preshared_key(config, request, "/register")
+    })
+    .await?;
 
     trace!("got registration response");
 
+    if res.status() == StatusCode::CONFLICT {
+        return Err(MediaError::Server {
+            status: res.status(),
+            message: String::from(
+                "the id derived from the registration seed is registered with another passcode",
+            ),
+        });
+    }
+
     if !res.status().is_success() {
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "unable to register with api",
-        )));
+        return Err(MediaError::from_response(res).await);
     }
 
     trace!("parsing registration response");
 
-    let body: Register = res.json().await?;
+    let body = res.text().await?;
+    let body: Register = serde_json::from_str(&body).map_err(|error| ParseError { error, body })?;
 
     trace!("registration response parsed");
 
-    Ok((body.id, body.passcode))
+    Ok((body.id, body.passcode, address))
 }
 
 /// connect to the server and request a url to authenticate to, for the user to connect their google account
+/// this is pinned to the server we registered with, as the login is tracked by that server
+/// an incremental login asks for the api's additional scopes on top of those already granted
 pub(crate) async fn get_auth_url(
     config: &Config,
     agent: &Client,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let url = format!("{}/auth_url", config.webserver_address);
+    incremental: bool,
+) -> Result<AuthUrl, MediaError> {
+    let _permit = config.api_permits.acquire().await;
+    let url = format!("{}/auth_url", config.registered_address());
 
     trace!("getting auth url from {}", &url);
 
     let res = agent
         .get(&url)
+        .query(&AuthUrlParameters { incremental })
         .basic_auth(
             config.local_id.as_ref().unwrap(),
             config.local_passcode.as_ref(),
@@ -73,24 +471,31 @@ pub(crate) async fn get_auth_url(
 
     if !res.status().is_success() {
         error!("unable to get auth url from api: {}", res.status());
-        error!("body: {}", res.text().await?);
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "unable to get auth url",
-        )));
+        return Err(MediaError::from_response(res).await);
     }
 
     trace!("parsing auth url response");
 
-    Ok(res.text().await?)
+    let expires_in = res
+        .headers()
+        .get(AUTH_EXPIRES_IN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs);
+
+    Ok(AuthUrl {
+        url: res.text().await?,
+        expires_in,
+    })
 }
 
-/// connect to the api and await the user completing authentication
+/// connect to the api and await the user completing authentication, this is pinned to the server
+/// we registered with
 pub(crate) async fn await_user_authentication(
     config: &Config,
     agent: &Client,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let url = format!("{}/is_logged_in", config.webserver_address);
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let url = format!("{}/is_logged_in", config.registered_address());
 
     trace!("awaiting user authentication, from url {}", &url);
 
@@ -119,21 +524,41 @@ pub(crate) async fn await_user_authentication(
     Ok(())
 }
 
-pub(crate) async fn get_media_items(
+/// claim a completed google login, binding it to our account on the api
+pub(crate) async fn claim_token(
     config: &Config,
     agent: &Client,
-    reload: bool,
-) -> Result<Vec<MediaItem>, Box<dyn std::error::Error + Send + Sync + 'static>> {
-    let url = format!(
-        "{}/download?reload={}&max_count=25",
-        config.webserver_address, reload
-    );
+    token: &Token,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let _permit = config.api_permits.acquire().await;
+    let url = format!("{}/token_completion", config.registered_address());
 
-    trace!("getting media items");
-    trace!("url: {}", url);
+    trace!("claiming login token at {}", &url);
+
+    let res = agent.post(&url).json(token).send().await?;
+
+    if !res.status().is_success() {
+        error!("unable to claim login token: {}", res.status());
+        error!("body: {}", res.text().await?);
+        return Err("unable to claim login token".into());
+    }
+
+    trace!("login token claimed");
+
+    Ok(())
+}
+
+/// ask the api to claim a completed login on our behalf, returning false if there is nothing to
+/// claim yet
+pub(crate) async fn claim_pending(
+    config: &Config,
+    agent: &Client,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let _permit = config.api_permits.acquire().await;
+    let url = format!("{}/claim_pending", config.registered_address());
 
     let res = agent
-        .get(&url)
+        .post(&url)
         .basic_auth(
             config.local_id.as_ref().unwrap(),
             config.local_passcode.as_ref(),
@@ -141,92 +566,823 @@ pub(crate) async fn get_media_items(
         .send()
         .await?;
 
-    trace!("got media items");
+    match res.status() {
+        StatusCode::NOT_FOUND => Ok(false),
+        status if status.is_success() => Ok(true),
+        status => Err(format!("unable to claim pending login: {}", status).into()),
+    }
+}
+
+/// periodically claim our login server-side, in case the success page is closed before it can
+/// claim the login itself
+async fn recover_pending_login(
+    config: &Config,
+    agent: &Client,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    loop {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        match claim_pending(config, agent).await {
+            Ok(true) => {
+                info!("claimed pending login");
+                return await_user_authentication(config, agent).await;
+            }
+            Ok(false) => trace!("no pending login to claim yet"),
+            Err(e) => trace!("unable to check for a pending login: {}", e),
+        }
+    }
+}
+
+/// The lines typed into the terminal. A blocking read of stdin can't be cancelled, so one thread
+/// reads it for the whole process, rather than each login leaving behind a reader that would
+/// swallow the code pasted for the next
+static TERMINAL_LINES: OnceLock<Mutex<mpsc::UnboundedReceiver<String>>> = OnceLock::new();
+
+/// wait for a claim code pasted into the terminal by the user, ignoring anything typed before now.
+/// Returns None once stdin is closed.
+async fn read_claim_code() -> Option<String> {
+    let lines = TERMINAL_LINES.get_or_init(|| {
+        let (tx, rx) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lines() {
+                match line {
+                    Ok(line) if !line.trim().is_empty() => {
+                        if tx.send(line.trim().to_string()).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => continue,
+                    Err(_) => return,
+                }
+            }
+        });
+        Mutex::new(rx)
+    });
+
+    let mut lines = lines.lock().await;
+    while lines.try_recv().is_ok() {}
+    lines.recv().await
+}
+
+/// wait for the user to complete the login at `auth_url`. Normally the success page claims the
+/// login for us, but if it can't the user may paste the claim code it shows into the terminal.
+pub(crate) async fn complete_authentication(
+    config: &Config,
+    agent: &Client,
+    auth_url: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let auth_key = auth_url.rsplit('/').next().unwrap_or_default().to_string();
+
+    tokio::select! {
+        res = await_user_authentication(config, agent) => res,
+        res = recover_pending_login(config, agent) => res,
+        Some(code) = read_claim_code() => {
+            let token = Token {
+                id: auth_key,
+                token: code,
+                expiry: SystemTime::now(),
+            };
+            claim_token(config, agent, &token).await?;
+            await_user_authentication(config, agent).await
+        }
+    }
+}
+
+/// run the whole login flow, printing the url for the user to visit and waiting for them to link
+/// their google account
+pub(crate) async fn authenticate(
+    config: &Config,
+    agent: &Client,
+    incremental: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let auth_url = get_auth_url(config, agent, incremental).await?;
+
+    info!(
+        "please visit {} and complete authentication{}",
+        auth_url.url,
+        auth_url.time_limit()
+    );
+    info!("if the page shows a claim code, paste it here and press enter");
+
+    complete_authentication(config, agent, &auth_url.url).await
+}
+
+/// ask the api to restart scanning this account from the beginning
+pub(crate) async fn rescan(
+    config: &Config,
+    agent: &Client,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let _permit = config.api_permits.acquire().await;
+    trace!("requesting rescan");
+
+    let (_, res) = send_with_failover(&config.webserver_addresses, |address| {
+        agent.post(format!("{}/rescan", address)).basic_auth(
+            config.local_id.as_ref().unwrap(),
+            config.local_passcode.as_ref(),
+        )
+    })
+    .await?;
 
     if !res.status().is_success() {
-        //print response body
-        error!("unable to download media item: {}", res.status());
+        error!("unable to request rescan: {}", res.status());
         error!("body: {}", res.text().await?);
+        return Err("unable to request rescan".into());
+    }
 
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "unable to get media items",
-        )));
+    trace!("rescan accepted");
+
+    Ok(())
+}
+
+/// ask the api for a new passcode, the old one stops working as soon as this succeeds
+pub(crate) async fn rotate_passcode(
+    config: &Config,
+    agent: &Client,
+) -> Result<Passcode, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let _permit = config.api_permits.acquire().await;
+    trace!("rotating passcode");
+
+    let (_, res) = send_with_failover(&config.webserver_addresses, |address| {
+        agent
+            .post(format!("{}/rotate_passcode", address))
+            .basic_auth(
+                config.local_id.as_ref().unwrap(),
+                config.local_passcode.as_ref(),
+            )
+    })
+    .await?;
+
+    if res.status() == StatusCode::NOT_FOUND {
+        return Err("the api is too old to rotate passcodes".into());
+    }
+    if !res.status().is_success() {
+        return Err(format!("unable to rotate passcode: {}", res.status()).into());
+    }
+
+    let body: Register = res.json().await?;
+    Ok(body.passcode)
+}
+
+/// list a page of the ids in our google library, without moving our scan through it
+pub(crate) async fn library_page(
+    config: &Config,
+    agent: &Client,
+    page_token: Option<String>,
+) -> Result<LibraryPage, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let _permit = config.api_permits.acquire().await;
+    let params = LibraryParameters {
+        page_token,
+        max_count: None,
+        include_archived: config.include_archived_media,
+    };
+    let (_, res) = send_with_failover(&config.webserver_addresses, |address| {
+        agent
+            .get(format!("{}/library", address))
+            .query(&params)
+            .basic_auth(
+                config.local_id.as_ref().unwrap(),
+                config.local_passcode.as_ref(),
+            )
+    })
+    .await?;
+
+    if !res.status().is_success() {
+        return Err(Box::new(MediaError::from_response(res).await));
+    }
+
+    Ok(res.json().await?)
+}
+
+/// ask the api how long the google token linked to our account has left
+pub(crate) async fn token_status(
+    config: &Config,
+    agent: &Client,
+) -> Result<TokenStatus, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let _permit = config.api_permits.acquire().await;
+    let (_, res) = send_with_failover(&config.webserver_addresses, |address| {
+        agent.get(format!("{}/token_status", address)).basic_auth(
+            config.local_id.as_ref().unwrap(),
+            config.local_passcode.as_ref(),
+        )
+    })
+    .await?;
+
+    if !res.status().is_success() {
+        return Err(format!("unable to get token status: {}", res.status()).into());
+    }
+
+    Ok(res.json().await?)
+}
+
+/// what the api supports, older apis without `/capabilities` report None
+pub(crate) async fn capabilities(
+    config: &Config,
+    agent: &Client,
+) -> Result<Option<Capabilities>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let _permit = config.api_permits.acquire().await;
+    let (_, res) = send_with_failover(&config.webserver_addresses, |address| {
+        agent.get(format!("{}/capabilities", address))
+    })
+    .await?;
+
+    if res.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !res.status().is_success() {
+        return Err(format!("unable to get api capabilities: {}", res.status()).into());
+    }
+
+    Ok(Some(res.json().await?))
+}
+
+pub(crate) async fn get_media_items(
+    config: &Config,
+    agent: &Client,
+    reload: bool,
+    max_count: u8,
+    page_token: Option<&str>,
+) -> Result<MediaPage, MediaError> {
+    let _permit = config.api_permits.acquire().await;
+    trace!("getting media items");
+
+    let (address, res) = send_with_failover(&config.webserver_addresses, |address| {
+        let url = format!(
+            "{}/download?reload={}&max_count={}",
+            address, reload, max_count
+        );
+        trace!("url: {}", url);
+        let mut request = agent.get(url).basic_auth(
+            config.local_id.as_ref().unwrap(),
+            config.local_passcode.as_ref(),
+        );
+        if let Some(page_token) = page_token {
+            request = request.query(&[("page_token", page_token)]);
+        }
+        // older apis silently ignore this, `run` checks the api supports it before scanning
+        if config.include_archived_media {
+            request = request.query(&[("include_archived", "true")]);
+        }
+        request
+    })
+    .await?;
+
+    trace!("got media items from {}", address);
+
+    if !res.status().is_success() {
+        error!("unable to get media items: {}", res.status());
+        return Err(MediaError::from_response(res).await);
     }
 
     trace!("parsing media items");
 
-    let body = res.json().await?;
-    Ok(body)
+    let last_page = res
+        .headers()
+        .get(LAST_PAGE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value == "true");
+    let body = res.text().await?;
+    match serde_json::from_str(&body) {
+        Ok(items) => Ok(MediaPage { items, last_page }),
+        Err(error) => Err(MediaError::Parse(ParseError { error, body })),
+    }
+}
+
+/// let the configured webhook know that we have repeatedly failed to authenticate with the api
+pub(crate) async fn notify_auth_failure(
+    config: &Config,
+    agent: &Client,
+    webhook: &str,
+    failures: u32,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    trace!("notifying webhook {} of auth failure", webhook);
+
+    let res = agent
+        .post(webhook)
+        .json(&AuthFailureNotification {
+            event: "auth_failure",
+            client_id: config.local_id.as_ref(),
+            consecutive_failures: failures,
+        })
+        .send()
+        .await?;
+
+    if !res.status().is_success() {
+        return Err(format!("webhook responded with {}", res.status()).into());
+    }
+
+    Ok(())
+}
+
+/// the most bytes read from a download at once
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// the size of each range requested when downloading a large item through the api
+const LARGE_FILE_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// the seconds to allow for downloading `len` bytes, 2 seconds for every second it takes at this
+/// download's share of `max_download_speed`, or at 1000000 bytes a second without a limit
+fn transfer_timeout_secs(limiter: &RateLimiter, len: u64) -> u64 {
+    let speed = limiter.rate_per_download().unwrap_or(1000000).max(1);
+    len / speed * 2 + 5
+}
+
+/// download a very large item through the api in ranged chunks. The partial file is kept between
+/// attempts, so an interrupted download resumes where it left off rather than starting again.
+async fn download_large_item(
+    config: &Config,
+    agent: &Client,
+    throughput: &Throughput,
+    limiter: &RateLimiter,
+    item: &MediaItem,
+    len: u64,
+    start: Instant,
+) -> Result<DownloadOutcome, MediaError> {
+    let file_name = &item.id;
+    let _share = limiter.share();
+
+    tokio::fs::create_dir_all(&config.temp_path).await?;
+    let partial = config.temp_path.join(format!("{}.part", file_name));
+
+    let mut offset = match tokio::fs::metadata(&partial).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+    if offset > len {
+        warn!(
+            "partial download of {} is larger than the item, restarting",
+            file_name
+        );
+        tokio::fs::remove_file(&partial).await?;
+        offset = 0;
+    } else if offset > 0 {
+        info!("resuming download of {} from byte {}", label(item), offset);
+    }
+
+    while offset < len {
+        let end = (offset + LARGE_FILE_CHUNK_SIZE).min(len) - 1;
+        trace!("downloading bytes {}-{} of {}", offset, end, file_name);
+
+        let (_, res) = send_with_failover(&config.webserver_addresses, |address| {
+            agent
+                .get(format!("{}/media/{}", address, file_name))
+                .basic_auth(
+                    config.local_id.as_ref().unwrap(),
+                    config.local_passcode.as_ref(),
+                )
+                .header(header::RANGE, format!("bytes={}-{}", offset, end))
+        })
+        .await?;
+
+        if res.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(MediaError::Server {
+                status: res.status(),
+                message: format!(
+                    "unable to download bytes {}-{} of {}",
+                    offset, end, file_name
+                ),
+            });
+        }
+
+        let dest = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&partial)
+            .await?;
+        let reader = res.bytes_stream().map_err(std::io::Error::other);
+        let reader = StreamReader::new(reader);
+
+        let timeout = transfer_timeout_secs(limiter, end - offset + 1);
+        offset += tokio::time::timeout(
+            Duration::from_secs(timeout),
+            download(config, throughput, limiter, reader, dest, None),
+        )
+        .await
+        .map_err(|_| MediaError::timed_out("download timed out"))??;
+    }
+
+    // the chunks may have been downloaded by earlier attempts, so the hash is taken from the
+    // finished file
+    let sha256 = match config.records_sha256() {
+        true => Some(hash_file(partial.clone()).await?),
+        false => None,
+    };
+    let path = store_file(config, &partial, file_name, sha256.as_deref()).await?;
+
+    Ok(DownloadOutcome {
+        path,
+        bytes: len,
+        duration: start.elapsed(),
+        sha256,
+    })
+}
+
+/// download an item already known to be `len` bytes through the api, without going through its
+/// base url, which may have expired while it waited in the large file lane
+pub(crate) async fn download_deferred(
+    config: &Config,
+    agent: &Client,
+    throughput: &Throughput,
+    limiter: &RateLimiter,
+    item: &MediaItem,
+    len: u64,
+) -> Result<DownloadOutcome, MediaError> {
+    info!(
+        "{} is {} bytes, downloading it in chunks through the api",
+        label(item),
+        len
+    );
+    download_large_item(
+        config,
+        agent,
+        throughput,
+        limiter,
+        item,
+        len,
+        Instant::now(),
+    )
+    .await
+}
+
+/// download an item through the api, which looks up a fresh base url for it. Used for items
+/// retried after google rate limited them, whose base urls have likely expired since. If
+/// `defer_large` is set, items over `large_file_threshold` are deferred as `download_item` does.
+pub(crate) async fn download_through_api(
+    config: &Config,
+    agent: &Client,
+    throughput: &Throughput,
+    limiter: &RateLimiter,
+    item: &MediaItem,
+    defer_large: bool,
+) -> Result<DownloadOutcome, MediaError> {
+    let start = Instant::now();
+
+    // the size of the item is needed to download it in chunks, so request its first byte to
+    // learn it from the content range
+    let (_, res) = send_with_failover(&config.webserver_addresses, |address| {
+        agent
+            .get(format!("{}/media/{}", address, item.id))
+            .basic_auth(
+                config.local_id.as_ref().unwrap(),
+                config.local_passcode.as_ref(),
+            )
+            .header(header::RANGE, "bytes=0-0")
+    })
+    .await?;
+
+    if res.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(MediaError::from_response(res).await);
+    }
+
+    let len = res
+        .headers()
+        .get(header::CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit_once('/'))
+        .and_then(|(_, len)| len.parse::<u64>().ok())
+        .ok_or_else(|| MediaError::Server {
+            status: res.status(),
+            message: format!("the api didn't report the size of {}", item.id),
+        })?;
+
+    if defer_large && config.large_file_threshold > 0 && len > config.large_file_threshold {
+        return Err(MediaError::Deferred { bytes: len });
+    }
+
+    download_large_item(config, agent, throughput, limiter, item, len, start).await
+}
+
+/// the sha256 of a file on disk
+pub(crate) async fn hash_file(path: PathBuf) -> Result<String, MediaError> {
+    let hash = tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+        Ok::<_, std::io::Error>(format!("{:x}", hasher.finalize()))
+    })
+    .await
+    .map_err(std::io::Error::other)??;
+    Ok(hash)
+}
+
+/// where content with this sha256 is stored in the content addressed layout, under a directory
+/// named after the first two characters of the hash so no one directory grows too large
+fn content_path(config: &Config, sha256: &str) -> PathBuf {
+    config.store_path.join(&sha256[..2]).join(sha256)
+}
+
+/// move a downloaded file into the store path, named after the item's id, or after its sha256
+/// when storing in the content addressed layout. Content that is already stored is shared rather
+/// than stored again. Returns where the file was stored.
+async fn store_file(
+    config: &Config,
+    downloaded: &std::path::Path,
+    file_name: &str,
+    sha256: Option<&str>,
+) -> Result<PathBuf, MediaError> {
+    let sha256 = sha256.filter(|_| config.content_addressed);
+    let path = match sha256 {
+        Some(sha256) => content_path(config, sha256),
+        None => config.store_path.join(file_name),
+    };
+
+    if sha256.is_some() && tokio::fs::metadata(&path).await.is_ok() {
+        info!(
+            "{} has the same content as {:?}, not storing it again",
+            file_name, path
+        );
+        tokio::fs::remove_file(downloaded).await?;
+        return Ok(path);
+    }
+
+    trace!("moving to final destination {:?}", path);
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+
+    // Attempt to move the file, fallback to copying if it fails. The copy is swapped in so a half
+    // copied file is never mistaken for stored content
+    if let Err(e) = tokio::fs::rename(downloaded, &path).await {
+        error!("unable to rename file: {}", e);
+        let tmp = path.with_extension("part");
+        tokio::fs::copy(downloaded, &tmp).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        tokio::fs::remove_file(downloaded).await?;
+    }
+
+    Ok(path)
 }
 
 async fn download<R>(
     config: &Config,
+    throughput: &Throughput,
+    limiter: &RateLimiter,
     mut reader: R,
     mut dest: File,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>
+    mut hasher: Option<&mut Sha256>,
+) -> Result<u64, MediaError>
 where
     R: AsyncReadExt + Unpin,
 {
-    // copy in chunks, respecting a rate limit if present
-    // we limit in 100ms timeframes
-    let mut total_bytes = 0;
-    let mut time = Instant::now();
-    let mut buf = vec![0; 1024.min(config.max_download_speed as usize / 10)];
+    // copy in chunks, waiting on the shared rate limiter before each one
+    let mut written = 0;
+    let mut buf = vec![0; DOWNLOAD_CHUNK_SIZE];
     loop {
-        let bytes = reader.read(&mut buf).await?;
+        // fail fast if the transfer stalls, rather than waiting out the whole download timeout
+        let bytes = tokio::time::timeout(
+            Duration::from_secs(config.download_stall_timeout_secs),
+            reader.read(&mut buf),
+        )
+        .await
+        .map_err(|_| MediaError::timed_out("download stalled, no data was received"))??;
         if bytes == 0 {
-            break Ok(());
+            dest.flush().await?;
+            break Ok(written);
         }
+        limiter.acquire(bytes as u64).await;
         dest.write_all(&buf[..bytes]).await?;
-        total_bytes += bytes;
+        if let Some(ref mut hasher) = hasher {
+            hasher.update(&buf[..bytes]);
+        }
+        throughput.record(bytes as u64);
+        written += bytes as u64;
+    }
+}
 
-        if config.max_download_speed > 0
-            && total_bytes / 100 > config.max_download_speed as usize / 100
+/// choose the parameter appended to an item's base url to download it
+///
+/// Photos need `d` to be downloaded at full resolution with their metadata, while videos need
+/// `dv`, as `d` only returns a still frame, though both can be configured. Items google reports
+/// without a mime type are still recognised as videos by their video metadata. The configured
+/// rules are checked first, as some items, such as motion photos, are reported as photos but need
+/// `dv` for their video.
+fn download_param<'a>(config: &'a Config, item: &MediaItem) -> &'a str {
+    if let Some(rule) = config
+        .download_param_rules
+        .iter()
+        .find(|rule| rule.matches(&item.filename))
+    {
+        return &rule.param;
+    }
+
+    let is_video = match item.mimeType {
+        Some(ref mime_type) => mime_type.starts_with("video"),
+        None => item
+            .mediaMetadata
+            .as_ref()
+            .is_some_and(|metadata| metadata.video.is_some()),
+    };
+    if is_video {
+        &config.video_download_param
+    } else {
+        &config.photo_download_param
+    }
+}
+
+/// the size profile pictures are requested at
+const AVATAR_PARAM: &str = "s512";
+
+/// the file a contributor's profile picture is stored in, named after them with any characters
+/// that aren't safe in a filename replaced
+fn avatar_path(config: &Config, contributor: &ContributorInfo) -> PathBuf {
+    let name: String = contributor
+        .displayName
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = match name.trim_start_matches('.') {
+        "" => "unknown",
+        name => name,
+    };
+    config
+        .store_path
+        .join("contributors")
+        .join(format!("{}.jpg", name))
+}
+
+/// download a shared album contributor's profile picture, unless it is already stored
+pub(crate) async fn download_avatar(
+    config: &Config,
+    agent: &Client,
+    contributor: &ContributorInfo,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let path = avatar_path(config, contributor);
+    if tokio::fs::metadata(&path).await.is_ok() {
+        return Ok(());
+    }
+
+    let url = download_url(&contributor.profilePictureBaseUrl, AVATAR_PARAM)?;
+    let res = agent.get(&url).send().await?;
+    if !res.status().is_success() {
+        return Err(format!("unable to download profile picture: {}", res.status()).into());
+    }
+    let body = res.bytes().await?;
+
+    // written alongside and swapped in, so a half written picture is never mistaken for a stored one
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+    let tmp = path.with_extension("part");
+    tokio::fs::write(&tmp, &body).await?;
+    tokio::fs::rename(&tmp, &path).await?;
+
+    trace!("saved profile picture of {}", contributor.displayName);
+    Ok(())
+}
+
+/// move the partial file of a failed download into `failed/` under the store path, replacing any
+/// kept from an earlier attempt
+async fn keep_failed_temp(config: &Config, partial: &std::path::Path, file_name: &str) {
+    let failed = config.store_path.join("failed");
+    let result = async {
+        tokio::fs::create_dir_all(&failed).await?;
+        // the temp path may be on another filesystem, so fall back to copying
+        if tokio::fs::rename(partial, failed.join(file_name))
+            .await
+            .is_err()
         {
-            // if we are under a second, sleep for the remaining time
-            if time.elapsed().as_millis() < 100 {
-                let remaining = 100_000 - time.elapsed().as_micros();
-                tokio::time::sleep(Duration::from_micros(
-                    remaining.try_into().unwrap_or(u64::MAX),
-                ))
-                .await;
-            }
-            time = Instant::now();
-            total_bytes = 0;
+            tokio::fs::copy(partial, failed.join(file_name)).await?;
+        }
+        Ok::<_, std::io::Error>(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => info!("kept the partial download of {} in {:?}", file_name, failed),
+        Err(e) => warn!(
+            "unable to keep the partial download of {}: {}",
+            file_name, e
+        ),
+    }
+}
+
+/// whether a file already in the store path with the expected size can be kept, in hash mode
+/// checking it still has the sha256 recorded when it was downloaded
+async fn verify_present(
+    config: &Config,
+    path: &std::path::Path,
+    recorded_sha256: Option<&str>,
+) -> bool {
+    if config.skip_if_present != SkipIfPresent::Hash {
+        return true;
+    }
+
+    let recorded = match recorded_sha256 {
+        Some(recorded) => recorded,
+        None => {
+            debug!("no hash is recorded for {:?}, downloading it again", path);
+            return false;
+        }
+    };
+    match hash_file(path.to_path_buf()).await {
+        Ok(sha256) if sha256 == recorded => true,
+        Ok(_) => {
+            warn!(
+                "{:?} doesn't match the hash recorded when it was downloaded, downloading it again",
+                path
+            );
+            false
+        }
+        Err(e) => {
+            warn!("unable to hash {:?}, downloading it again: {}", path, e);
+            false
         }
     }
 }
 
+/// the size of an item's file already in the store path, if it can be kept rather than downloaded
+/// again. Only the headers are asked for to learn the size google reports, files that aren't on
+/// disk are downloaded without asking
+async fn present_len(
+    config: &Config,
+    agent: &Client,
+    url: &str,
+    path: &std::path::Path,
+    recorded_sha256: Option<&str>,
+) -> Option<u64> {
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+
+    // the body of a HEAD response is empty, so the length is read from the header
+    let res = agent.head(url).send().await.ok()?;
+    let len = res
+        .headers()
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse::<u64>()
+        .ok()?;
+    if !res.status().is_success() || len != metadata.len() {
+        return None;
+    }
+
+    verify_present(config, path, recorded_sha256)
+        .await
+        .then_some(len)
+}
+
+/// download an item into the store path. If `defer_large` is set, items over
+/// `large_file_threshold` are not downloaded, a `Deferred` error is returned instead.
+/// `recorded_sha256` is the hash recorded by an earlier download of the item, which a file already
+/// in the store path must match to be kept in hash mode.
 pub(crate) async fn download_item(
     config: &Config,
     agent: &Client,
+    throughput: &Throughput,
+    limiter: &RateLimiter,
     item: &MediaItem,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-    trace!("downloading item: {:?}", item);
+    defer_large: bool,
+    recorded_sha256: Option<&str>,
+) -> Result<DownloadOutcome, MediaError> {
+    let start = Instant::now();
     let file_name = &item.id;
 
-    let param = match item.mimeType {
-        Some(ref mime_type) if mime_type.contains("video") => "dv",
-        _ => "d",
-    };
+    let param = download_param(config, item);
 
-    let url = format!("{}={}", &item.baseUrl, param);
+    let url = download_url(&item.baseUrl, param)?;
 
-    trace!("downloading item: {} with param: {}", item.id, param);
+    trace!("downloading {} with param: {}", label(item), param);
     trace!("url: {}", &url);
 
+    // if the file is already on disk with the size google reports, skip the download. Content
+    // addressed files are named after their hash, which isn't known until the body has been
+    // transferred
+    if config.skip_if_present != SkipIfPresent::Off && !config.content_addressed {
+        let path = config.store_path.join(file_name);
+        if let Some(len) = present_len(config, agent, &url, &path, recorded_sha256).await {
+            info!(
+                "{} is already present on disk, skipping download",
+                label(item)
+            );
+            return Ok(DownloadOutcome {
+                path,
+                bytes: len,
+                duration: start.elapsed(),
+                sha256: recorded_sha256.map(str::to_string),
+            });
+        }
+    }
+
     let res = agent.get(&url).send().await?;
 
     if !res.status().is_success() {
-        //print response body
-        error!("unable to download media item: {}", res.status());
-        error!("body: {}", res.text().await?);
+        error!("unable to download {}: {}", label(item), res.status());
+        return Err(MediaError::from_response(res).await);
+    }
 
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "unable to download item",
-        )));
+    if let Some(len) = res.content_length() {
+        if config.large_file_threshold > 0 && len > config.large_file_threshold {
+            if defer_large {
+                return Err(MediaError::Deferred { bytes: len });
+            }
+            info!(
+                "{} is {} bytes, downloading it in chunks through the api",
+                label(item),
+                len
+            );
+            return download_large_item(config, agent, throughput, limiter, item, len, start).await;
+        }
     }
 
     // if config.temp_path doesn't exist - create it
@@ -251,14 +1407,19 @@ pub(crate) async fn download_item(
     );
 
     let length = res.content_length();
+    let _share = limiter.share();
 
     let timeout = {
-        if let Some(len) = length {
-            // for every 1000000 bytes (or max download rate), add 2 seconds
-            (len / (1000000.max(config.max_download_speed)) * 2) + 5
-        } else {
-            // give it 10 minutes to download
-            60 * 10
+        let cap = match param {
+            "dv" => config.video_download_timeout_secs,
+            param if param == config.video_download_param => config.video_download_timeout_secs,
+            _ => config.photo_download_timeout_secs,
+        };
+
+        // a huge file is never cut off by the cap for its kind
+        match length {
+            Some(len) => cap.max(transfer_timeout_secs(limiter, len)),
+            None => cap,
         }
     };
 
@@ -267,31 +1428,105 @@ pub(crate) async fn download_item(
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
     let reader = StreamReader::new(reader);
 
-    tokio::time::timeout(Duration::from_secs(timeout), download(config, reader, dest)).await??;
+    let mut hasher = config.records_sha256().then(Sha256::new);
+    let result = match tokio::time::timeout(
+        Duration::from_secs(timeout),
+        download(config, throughput, limiter, reader, dest, hasher.as_mut()),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(_) => Err(MediaError::timed_out(&format!(
+            "download timed out after {} seconds",
+            timeout
+        ))),
+    };
+    if result.is_err() && config.keep_failed_temp {
+        keep_failed_temp(config, &tmp_dir.path().join(file_name), file_name).await;
+    }
+    let bytes = result?;
+
+    let sha256 = hasher.map(|hasher| format!("{:x}", hasher.finalize()));
+    let path = store_file(
+        config,
+        &tmp_dir.path().join(file_name),
+        file_name,
+        sha256.as_deref(),
+    )
+    .await?;
 
-    trace!("moving to final destination");
+    trace!("removing temp dir");
+    tmp_dir.close()?;
 
-    // if dest does not exist, create it
-    if !config.store_path.exists() {
-        trace!("creating store path: {:?}", &config.store_path);
-        std::fs::create_dir_all(&config.store_path)?;
+    Ok(DownloadOutcome {
+        path,
+        bytes,
+        duration: start.elapsed(),
+        sha256,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{download_url, MediaPage};
+
+    const BASE_URL: &str = "https://lh3.googleusercontent.com/lr/AFBm1_abc";
+
+    #[test]
+    fn test_download_url_appends_param() {
+        assert_eq!(
+            download_url(BASE_URL, "d").unwrap(),
+            format!("{}=d", BASE_URL)
+        );
     }
 
-    // Attempt to move the file, fallback to copying if it fails
-    if let Err(e) = std::fs::rename(
-        tmp_dir.path().join(&file_name),
-        config.store_path.join(&file_name),
-    ) {
-        error!("unable to rename file: {}", e);
-        std::fs::copy(
-            tmp_dir.path().join(&file_name),
-            config.store_path.join(&file_name),
-        )?;
-        std::fs::remove_file(tmp_dir.path().join(&file_name))?;
+    #[test]
+    fn test_download_url_replaces_existing_params() {
+        assert_eq!(
+            download_url(&format!("{}=w2048-h1024", BASE_URL), "dv").unwrap(),
+            format!("{}=dv", BASE_URL)
+        );
     }
 
-    trace!("removing temp dir");
-    tmp_dir.close()?;
+    #[test]
+    fn test_download_url_keeps_query() {
+        assert_eq!(
+            download_url(&format!("{}?authuser=0", BASE_URL), "d").unwrap(),
+            format!("{}=d?authuser=0", BASE_URL)
+        );
+        assert_eq!(
+            download_url(&format!("{}=s512?authuser=0#top", BASE_URL), "d").unwrap(),
+            format!("{}=d?authuser=0", BASE_URL)
+        );
+    }
 
-    Ok(())
+    #[test]
+    fn test_download_url_rejects_malformed() {
+        for base_url in [
+            "",
+            "   ",
+            "lr/AFBm1_abc",
+            "ftp://lh3.googleusercontent.com/lr/AFBm1_abc",
+            "https://lh3.googleusercontent.com",
+            "https://lh3.googleusercontent.com/lr/",
+            "https://lh3.googleusercontent.com/=d",
+        ] {
+            assert!(
+                download_url(base_url, "d").is_err(),
+                "{:?} should be rejected",
+                base_url
+            );
+        }
+    }
+
+    #[test]
+    fn test_empty_page_only_ends_the_scan_when_the_api_says_so() {
+        let page = |last_page| MediaPage {
+            items: Vec::new(),
+            last_page,
+        };
+        assert!(page(Some(false)).has_more());
+        assert!(!page(Some(true)).has_more());
+        assert!(!page(None).has_more());
+    }
 }
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use log::debug;
+
+/// The number of items requested per page until the client has measured how it is doing
+pub const DEFAULT_PAGE_SIZE: u8 = 25;
+
+/// The most items the api will return in a page
+pub const MAX_PAGE_SIZE: u8 = 100;
+
+/// Pages that take longer than this to fetch are made smaller
+const SLOW_PAGE: Duration = Duration::from_secs(20);
+
+/// A page that downloads in less than this many times as long as it took to fetch leaves the
+/// downloader idle for too much of the time, so the next page is made larger
+const MIN_DRAIN_RATIO: u32 = 10;
+
+/// The number of items requested from the api per page. Unless fixed by the user, this grows
+/// while pages download faster than they can be fetched, and shrinks when fetching is slow, times
+/// out, or a page can't be downloaded before its base urls expire.
+#[derive(Debug)]
+pub struct PageSize {
+    size: u8,
+    fixed: bool,
+}
+
+impl PageSize {
+    /// use `fixed` for every page if set, otherwise adapt from the default
+    pub fn new(fixed: Option<u8>) -> Self {
+        PageSize {
+            size: fixed.unwrap_or(DEFAULT_PAGE_SIZE),
+            fixed: fixed.is_some(),
+        }
+    }
+
+    pub fn get(&self) -> u8 {
+        self.size
+    }
+
+    fn set(&mut self, size: u8, reason: &str) {
+        let size = size.clamp(1, MAX_PAGE_SIZE);
+        if !self.fixed && size != self.size {
+            debug!("page size {} -> {}, {}", self.size, size, reason);
+            self.size = size;
+        }
+    }
+
+    /// a page was fetched in `latency`
+    pub fn fetched(&mut self, latency: Duration) {
+        if latency > SLOW_PAGE {
+            self.set(self.size - self.size / 4, "fetching is slow");
+        }
+    }
+
+    /// a page that took `latency` to fetch was downloaded in `drain`
+    pub fn drained(&mut self, latency: Duration, drain: Duration) {
+        if latency <= SLOW_PAGE && drain < latency * MIN_DRAIN_RATIO {
+            let grow = (self.size / 4).max(1);
+            self.set(
+                self.size.saturating_add(grow),
+                "pages download faster than they load",
+            );
+        }
+    }
+
+    /// fetching a page timed out
+    pub fn timed_out(&mut self) {
+        self.set(self.size / 2, "fetching timed out");
+    }
+
+    /// a page's base urls expired before all of it was downloaded
+    pub fn expired(&mut self) {
+        self.set(
+            self.size / 2,
+            "base urls expired before the page was downloaded",
+        );
+    }
+}
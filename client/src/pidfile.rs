@@ -0,0 +1,78 @@
+use std::{
+    error::Error,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+
+/// A file holding the id of this process, so process managers can track it and a second instance
+/// can't start while it exists. It is removed when dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+/// whether a process with this id is running
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // signal 0 only checks that the process exists and may be signalled
+    std::process::Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// whether a process with this id is running, which can't be checked here so is assumed
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+impl PidFile {
+    /// Write our process id to `path`, failing if it already holds the id of a running process.
+    /// A file left behind by a process that has since exited is replaced.
+    pub fn acquire(path: &Path) -> Result<PidFile, Box<dyn Error + Send + Sync + 'static>> {
+        if let Ok(contents) = std::fs::read_to_string(path) {
+            match contents.trim().parse::<u32>() {
+                // in a container a restarted client is often given the same id as the last one
+                Ok(pid) if pid != std::process::id() && is_running(pid) => {
+                    return Err(format!(
+                        "another instance is already running with pid {}, according to {:?}",
+                        pid, path
+                    )
+                    .into());
+                }
+                Ok(pid) => info!("removing stale pid file {:?} left by pid {}", path, pid),
+                Err(_) => warn!("removing unreadable pid file {:?}", path),
+            }
+            std::fs::remove_file(path)?;
+        }
+
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // created exclusively, so of two instances starting at once only one succeeds
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| format!("unable to create pid file {:?}: {}", path, e))?;
+        writeln!(file, "{}", std::process::id())?;
+
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("failed to remove pid file {:?}: {}", self.path, e);
+        }
+    }
+}
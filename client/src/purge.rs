@@ -0,0 +1,114 @@
+use std::{
+    error::Error,
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    database::{self, DbConnection},
+    reindex::is_media_id,
+};
+
+/// What to remove when purging
+#[derive(Debug, Default)]
+pub struct PurgeOptions {
+    /// Also delete downloaded media from the store path
+    pub delete_files: bool,
+    /// Also forget this client's registration, so the next run registers again
+    pub full: bool,
+    /// The TOML file settings are read from, which may set the store path
+    pub config_file: Option<PathBuf>,
+}
+
+/// The result of a purge
+#[derive(Debug, Default)]
+pub struct PurgeSummary {
+    /// Rows removed from the media table
+    pub items: usize,
+    /// Files deleted from the store path
+    pub files: usize,
+}
+
+/// ask the user to confirm the purge on the terminal, returning whether they did
+pub fn confirm(options: &PurgeOptions) -> std::io::Result<bool> {
+    print!("this will forget every downloaded item");
+    if options.delete_files {
+        print!(", delete the downloaded files");
+    }
+    if options.full {
+        print!(", and forget this client's registration");
+    }
+    print!(". type 'yes' to continue: ");
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim() == "yes")
+}
+
+/// whether a directory name is a prefix directory of the content addressed layout
+fn is_content_prefix(name: &str) -> bool {
+    name.len() == 2 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// delete every file in `dir` that is named after a media item, or stored in the content
+/// addressed layout, leaving anything else alone
+fn delete_media_files(dir: &Path) -> Result<usize, Box<dyn Error + Send + Sync + 'static>> {
+    let mut deleted = 0;
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        if entry.metadata()?.is_dir() && entry.file_name().to_str().is_some_and(is_content_prefix) {
+            deleted += delete_media_files(&entry.path())?;
+            continue;
+        }
+
+        let is_media =
+            entry.metadata()?.is_file() && entry.file_name().to_str().is_some_and(is_media_id);
+        if !is_media {
+            continue;
+        }
+
+        match std::fs::remove_file(entry.path()) {
+            Ok(()) => {
+                debug!("deleted {:?}", entry.path());
+                deleted += 1;
+            }
+            Err(e) => warn!("unable to delete {:?}: {}", entry.path(), e),
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Reset the local state of this client: forget every item, mark the initial scan as incomplete
+/// and optionally delete the downloaded files and the client's registration.
+pub fn purge(
+    connection: &mut DbConnection,
+    options: &PurgeOptions,
+) -> Result<PurgeSummary, Box<dyn Error + Send + Sync + 'static>> {
+    let mut summary = PurgeSummary::default();
+
+    // the config is only needed to find the store path
+    if options.delete_files {
+        let config = database::load_config(connection, options.config_file.as_deref())?;
+        summary.files = delete_media_files(&config.store_path)?;
+        // additional accounts store their media in a subfolder named after them
+        for account in config.additional_accounts.iter() {
+            summary.files += delete_media_files(&config.store_path.join(account))?;
+        }
+        database::clear_hashes(connection)?;
+    }
+
+    summary.items = database::clear_media(connection)?;
+    database::reset_local_state(connection, options.full)?;
+
+    Ok(summary)
+}
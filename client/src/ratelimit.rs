@@ -0,0 +1,156 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tokio::{sync::Mutex, time::Instant};
+
+/// A token bucket shared by every download, so `max_download_speed` caps the combined speed of
+/// all downloads rather than each one
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Bytes per second, 0 for no limit
+    rate: u64,
+    bucket: Mutex<Bucket>,
+    /// The downloads currently sharing the limit
+    downloads: AtomicU64,
+}
+
+/// A running download's place among those sharing the limit, given up when dropped
+pub struct Share<'a>(&'a RateLimiter);
+
+impl Drop for Share<'_> {
+    fn drop(&mut self) {
+        self.0.downloads.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that may be read without waiting, negative while downloads are waiting on bytes
+    /// they have already taken
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(0)
+    }
+}
+
+impl RateLimiter {
+    /// limit downloads to `rate` bytes per second in total, 0 for no limit
+    pub fn new(rate: u64) -> Self {
+        RateLimiter {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+            downloads: AtomicU64::new(0),
+        }
+    }
+
+    /// count a download as sharing the limit until the returned share is dropped
+    pub fn share(&self) -> Share<'_> {
+        self.downloads.fetch_add(1, Ordering::Relaxed);
+        Share(self)
+    }
+
+    /// the bytes per second each running download can expect, or None if there is no limit
+    pub fn rate_per_download(&self) -> Option<u64> {
+        (self.rate > 0).then(|| self.rate / self.downloads.load(Ordering::Relaxed).max(1))
+    }
+
+    /// wait until `bytes` more bytes may be downloaded without exceeding the limit
+    pub async fn acquire(&self, bytes: u64) {
+        if self.rate == 0 {
+            return;
+        }
+        let rate = self.rate as f64;
+
+        // the lock is held while waiting, so downloads are let through in the order they asked
+        let mut bucket = self.bucket.lock().await;
+        let now = Instant::now();
+        let refilled = now.duration_since(bucket.last_refill).as_secs_f64() * rate;
+        // allow bursts of up to 100ms worth of data
+        bucket.tokens = (bucket.tokens + refilled).min(rate / 10.0);
+        bucket.last_refill = now;
+
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-bucket.tokens / rate)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::time::Instant;
+
+    use super::RateLimiter;
+
+    #[tokio::test]
+    async fn test_rate_limit_is_shared_between_downloads() {
+        const RATE: u64 = 1_000_000;
+        const WORKERS: u64 = 4;
+        const CHUNK: u64 = 10_000;
+        const CHUNKS_PER_WORKER: u64 = 25;
+
+        let limiter = Arc::new(RateLimiter::new(RATE));
+        let start = Instant::now();
+
+        let workers: Vec<_> = (0..WORKERS)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move {
+                    for _ in 0..CHUNKS_PER_WORKER {
+                        limiter.acquire(CHUNK).await;
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.await.unwrap();
+        }
+
+        // 1MB at 1MB/s in total, if each worker were limited separately this would take 250ms
+        let total = WORKERS * CHUNKS_PER_WORKER * CHUNK;
+        let expected = Duration::from_secs_f64(total as f64 / RATE as f64);
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= expected.mul_f64(0.9),
+            "{} bytes took {:?}, expected at least {:?}",
+            total,
+            elapsed,
+            expected
+        );
+        assert!(elapsed < expected * 2, "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn test_rate_is_split_between_running_downloads() {
+        let limiter = RateLimiter::new(1_000_000);
+        let first = limiter.share();
+        assert_eq!(limiter.rate_per_download(), Some(1_000_000));
+        let second = limiter.share();
+        assert_eq!(limiter.rate_per_download(), Some(500_000));
+        drop((first, second));
+        assert_eq!(limiter.rate_per_download(), Some(1_000_000));
+
+        assert_eq!(RateLimiter::default().rate_per_download(), None);
+    }
+
+    #[tokio::test]
+    async fn test_unlimited_never_waits() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+        for _ in 0..1000 {
+            limiter.acquire(u64::MAX).await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,90 @@
+use std::{error::Error, path::Path, time::Duration};
+
+use log::{debug, trace};
+use shared_libs::json_templates::MediaItem;
+
+use crate::{
+    database::{self, DbConnection},
+    media::DownloadOutcome,
+};
+
+/// The shortest file name that is treated as a media item id, google's ids are far longer than
+/// this so shorter names are assumed to be unrelated files
+const MIN_ID_LEN: usize = 32;
+
+/// The result of reindexing a directory
+#[derive(Debug, Default)]
+pub struct ReindexSummary {
+    /// Files that were recorded as downloaded
+    pub added: usize,
+    /// Files that were already recorded as downloaded
+    pub existing: usize,
+    /// Files that don't look like downloaded media
+    pub skipped: usize,
+}
+
+/// whether a file name looks like a google media item id, which is what items are stored as
+pub(crate) fn is_media_id(name: &str) -> bool {
+    name.len() >= MIN_ID_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// record every file in `dir` that is named after a media item id as downloaded, so that a
+/// backup whose database was lost is not downloaded again. Items already recorded as downloaded are
+/// left untouched, failed and dead lettered items are marked downloaded.
+pub fn reindex(
+    connection: &mut DbConnection,
+    dir: &Path,
+) -> Result<ReindexSummary, Box<dyn Error + Send + Sync + 'static>> {
+    let mut summary = ReindexSummary::default();
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let name = entry.file_name();
+
+        let id = match name.to_str() {
+            Some(id) if metadata.is_file() && is_media_id(id) => id,
+            _ => {
+                trace!("skipping {:?}", entry.path());
+                summary.skipped += 1;
+                continue;
+            }
+        };
+
+        if database::backed_up(connection, id)? {
+            summary.existing += 1;
+            continue;
+        }
+
+        // the rest of the metadata is only known to google, the next rescan will not fill it in
+        // as the item is already recorded
+        let item = MediaItem {
+            id: id.to_string(),
+            description: None,
+            productUrl: String::new(),
+            baseUrl: String::new(),
+            mimeType: None,
+            mediaMetadata: None,
+            contributorInfo: None,
+            filename: id.to_string(),
+            download_attempts: 1,
+            download_success: true,
+            last_error: None,
+        };
+        let outcome = DownloadOutcome {
+            path: entry.path(),
+            bytes: metadata.len(),
+            duration: Duration::ZERO,
+            sha256: None,
+        };
+
+        database::record_found_file(connection, &item, &outcome)?;
+        debug!("recorded {} as downloaded", id);
+        summary.added += 1;
+    }
+
+    Ok(summary)
+}
@@ -0,0 +1,54 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use flate2::{write::GzEncoder, Compression};
+
+use crate::database::{self, DbConnection, FailedItem};
+
+/// quote a field for a csv file, escaping any quotes within it
+fn csv_field(field: &str) -> String {
+    format!("\"{}\"", field.replace('"', "\"\""))
+}
+
+/// whether a report should be gzip compressed, based on a `.gz` extension on its path
+fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "gz")
+}
+
+fn write_failed<W: Write>(mut writer: W, items: &[FailedItem]) -> std::io::Result<W> {
+    writeln!(writer, "id,filename,product_url,last_error")?;
+    for item in items.iter() {
+        writeln!(
+            writer,
+            "{},{},{},{}",
+            csv_field(&item.id),
+            csv_field(&item.filename),
+            csv_field(&item.product_url),
+            csv_field(item.last_error.as_deref().unwrap_or_default())
+        )?;
+    }
+    Ok(writer)
+}
+
+/// write a csv report of every item in the dead letter table to `path`, returning the number of
+/// items written. The report is gzip compressed if `path` ends in `.gz`.
+pub fn export_failed(
+    connection: &mut DbConnection,
+    path: &Path,
+) -> Result<usize, Box<dyn Error + Send + Sync + 'static>> {
+    let items = database::failed_media_items(connection)?;
+
+    let file = BufWriter::new(File::create(path)?);
+    if is_gzip(path) {
+        let encoder = write_failed(GzEncoder::new(file, Compression::default()), &items)?;
+        encoder.finish()?.flush()?;
+    } else {
+        write_failed(file, &items)?.flush()?;
+    }
+
+    Ok(items.len())
+}
@@ -1,5 +1,13 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    attempts (id) {
+        id -> Text,
+        download_attempts -> Integer,
+        last_attempt_at -> BigInt,
+    }
+}
+
 diesel::table! {
     config (key) {
         key -> Text,
@@ -35,7 +43,66 @@ diesel::table! {
         processing_status -> Nullable<Text>,
         profile_picture_url -> Nullable<Text>,
         display_name -> Nullable<Text>,
+
+        latitude -> Nullable<Double>,
+        longitude -> Nullable<Double>,
+
+        file_path -> Nullable<Text>,
+        file_size -> Nullable<BigInt>,
+        download_duration_ms -> Nullable<BigInt>,
+
+        last_error -> Nullable<Text>,
+        excluded -> Bool,
+        sha256 -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    dead_letter (id) {
+        id -> Text,
+        filename -> Text,
+        product_url -> Text,
+        creation_time -> Nullable<Text>,
+        download_attempts -> Integer,
+        reason -> Nullable<Text>,
+        failed_at -> Text,
+        account -> Text,
+    }
+}
+
+diesel::table! {
+    file_hashes (id) {
+        id -> Text,
+        sha256 -> Text,
+    }
+}
+
+diesel::table! {
+    download_queue (account, id) {
+        id -> Text,
+        position -> Integer,
+        item -> Text,
+        download_attempts -> Integer,
+        loaded_at -> BigInt,
+        account -> Text,
+    }
+}
+
+diesel::table! {
+    retry_queue (id) {
+        id -> Text,
+        item -> Text,
+        retry_at -> BigInt,
+        account -> Text,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(config, media,);
+diesel::allow_tables_to_appear_in_same_query!(
+    attempts,
+    config,
+    dead_letter,
+    download_queue,
+    file_hashes,
+    media,
+    retry_queue,
+);
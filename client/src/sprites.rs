@@ -0,0 +1,124 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use image::{imageops::FilterType, ImageFormat, ImageReader, RgbImage};
+use log::debug;
+use serde::Serialize;
+
+use crate::{
+    config::Config,
+    database::{self, DbPool},
+};
+
+/// The width and height of each thumbnail in the sprite sheet, in pixels
+pub const THUMBNAIL_SIZE: u32 = 64;
+
+/// The number of thumbnails in each row of the sprite sheet
+pub const SPRITE_COLUMNS: u32 = 20;
+
+/// The most thumbnails a sprite sheet can hold, webp images can be at most 16383 pixels high
+pub const MAX_SPRITE_ITEMS: u32 = 16383 / THUMBNAIL_SIZE * SPRITE_COLUMNS;
+
+/// Where a thumbnail sits in the sprite sheet
+#[derive(Debug, Serialize)]
+struct SpritePosition {
+    id: String,
+    x: u32,
+    y: u32,
+}
+
+/// Written alongside the sprite sheet, mapping positions in it to item ids
+#[derive(Debug, Serialize)]
+struct SpriteIndex {
+    image: &'static str,
+    thumbnail_size: u32,
+    columns: u32,
+    /// Most recently downloaded first
+    items: Vec<SpritePosition>,
+}
+
+/// load the cached thumbnail for an item, creating it from the downloaded file if needed
+fn thumbnail(
+    thumbnails: &Path,
+    id: &str,
+    file_path: &Path,
+) -> Result<RgbImage, Box<dyn Error + Send + Sync + 'static>> {
+    let path = thumbnails.join(format!("{}.jpg", id));
+    if let Ok(thumbnail) = image::open(&path) {
+        return Ok(thumbnail.to_rgb8());
+    }
+
+    // downloads are named after their id, so the format has to be guessed from the contents.
+    // They are cropped to a square so the sprite sheet is a regular grid
+    let thumbnail = ImageReader::open(file_path)?
+        .with_guessed_format()?
+        .decode()?
+        .resize_to_fill(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Triangle)
+        .to_rgb8();
+    thumbnail.save_with_format(&path, ImageFormat::Jpeg)?;
+    Ok(thumbnail)
+}
+
+/// write a file through a temporary path and swap it in, so readers never see it half written
+fn write_atomic(
+    path: &Path,
+    write: impl FnOnce(&Path) -> Result<(), Box<dyn Error + Send + Sync + 'static>>,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    let tmp = path.with_extension("tmp");
+    write(&tmp)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Build a webp sprite sheet of the most recently downloaded images into `sprites/recent.webp`
+/// under the store path, with `sprites/recent.json` mapping positions in it to item ids.
+/// Thumbnails are cached in `thumbnails/`, so each image is only decoded once. Returns the number
+/// of items in the sheet. The pool's connection is given back before any image is decoded.
+pub fn generate(
+    config: &Config,
+    database: &DbPool,
+) -> Result<usize, Box<dyn Error + Send + Sync + 'static>> {
+    let thumbnails = config.store_path.join("thumbnails");
+    let sprites = config.store_path.join("sprites");
+    std::fs::create_dir_all(&thumbnails)?;
+    std::fs::create_dir_all(&sprites)?;
+
+    let recent = database::with_connection(database, |conn| {
+        database::recent_images(conn, config.sprite_sheet_items)
+    })?;
+    let mut cells = Vec::with_capacity(recent.len());
+    for (id, file_path) in recent {
+        // videos google reports as images, deleted files and formats that can't be decoded are
+        // left out rather than failing the whole sheet
+        match thumbnail(&thumbnails, &id, &PathBuf::from(file_path)) {
+            Ok(thumbnail) => cells.push((id, thumbnail)),
+            Err(e) => debug!("unable to create a thumbnail for {}: {}", id, e),
+        }
+    }
+
+    let rows = (cells.len() as u32).div_ceil(SPRITE_COLUMNS).max(1);
+    let mut sheet = RgbImage::new(SPRITE_COLUMNS * THUMBNAIL_SIZE, rows * THUMBNAIL_SIZE);
+    let mut index = SpriteIndex {
+        image: "recent.webp",
+        thumbnail_size: THUMBNAIL_SIZE,
+        columns: SPRITE_COLUMNS,
+        items: Vec::with_capacity(cells.len()),
+    };
+    for (i, (id, thumbnail)) in cells.into_iter().enumerate() {
+        let x = (i as u32 % SPRITE_COLUMNS) * THUMBNAIL_SIZE;
+        let y = (i as u32 / SPRITE_COLUMNS) * THUMBNAIL_SIZE;
+        image::imageops::replace(&mut sheet, &thumbnail, x as i64, y as i64);
+        index.items.push(SpritePosition { id, x, y });
+    }
+
+    write_atomic(&sprites.join("recent.webp"), |path| {
+        Ok(sheet.save_with_format(path, ImageFormat::WebP)?)
+    })?;
+    write_atomic(&sprites.join("recent.json"), |path| {
+        Ok(std::fs::write(path, serde_json::to_vec(&index)?)?)
+    })?;
+
+    Ok(index.items.len())
+}
@@ -0,0 +1,195 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+};
+
+use log::{error, info};
+use serde::Serialize;
+use warp::{http::StatusCode, Filter, Reply};
+
+use crate::{
+    database::{media_row, with_connection, DbPool},
+    unix_time, ScanState,
+};
+
+/// The most queued items listed by `/queue`, so a huge queue doesn't produce a huge response
+const MAX_QUEUE_ITEMS: usize = 100;
+
+#[derive(Debug, Serialize)]
+struct QueuedItem {
+    id: String,
+    filename: String,
+    download_attempts: u32,
+    last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct QueueStatus {
+    /// The total number of queued items, which may be more than are listed
+    length: usize,
+    /// The item currently being downloaded, which has been taken off the queue
+    downloading: Option<String>,
+    /// The first `MAX_QUEUE_ITEMS` queued items, in the order they will be downloaded
+    items: Vec<QueuedItem>,
+    /// The number of items waiting for the large file lane
+    large_length: usize,
+    /// The item the large file lane is downloading
+    downloading_large: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ApiStatus {
+    /// Whether the last page was fetched from the api, false while backing off from failures
+    reachable: bool,
+    /// When a page was last fetched, in seconds since the unix epoch
+    last_success_at: Option<u64>,
+    consecutive_failures: u64,
+    /// How long until the next attempt, while backing off
+    retry_in_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct SessionStatus {
+    downloaded: u64,
+    failed: u64,
+    bytes: u64,
+    /// Averaged over the last 30 seconds
+    bytes_per_sec: u64,
+    queue_length: usize,
+    downloading: Option<String>,
+    api: ApiStatus,
+}
+
+fn with<T: Clone + Send>(data: T) -> impl Filter<Extract = (T,), Error = Infallible> + Clone {
+    warp::any().map(move || data.clone())
+}
+
+fn with_state(
+    state: Arc<ScanState>,
+) -> impl Filter<Extract = (Arc<ScanState>,), Error = Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
+
+async fn queue(state: Arc<ScanState>) -> Result<impl warp::Reply, Infallible> {
+    let downloading = state
+        .downloading
+        .lock()
+        .await
+        .as_ref()
+        .map(|item| item.id.clone());
+    let downloading_large = state
+        .downloading_large
+        .lock()
+        .await
+        .as_ref()
+        .map(|item| item.id.clone());
+    let large_length = state.large_queue.lock().await.len();
+    let queue = state.queue.lock().await;
+
+    let status = QueueStatus {
+        length: queue.len(),
+        downloading,
+        items: queue
+            .iter()
+            .take(MAX_QUEUE_ITEMS)
+            .map(|item| QueuedItem {
+                id: item.id.clone(),
+                filename: item.filename.clone(),
+                download_attempts: item.download_attempts,
+                last_error: item.last_error.clone(),
+            })
+            .collect(),
+        large_length,
+        downloading_large,
+    };
+
+    Ok(warp::reply::json(&status))
+}
+
+async fn status(state: Arc<ScanState>) -> Result<impl warp::Reply, Infallible> {
+    let health = &state.api_health;
+    let consecutive_failures = health.consecutive_failures.load(Ordering::Relaxed);
+    let api = ApiStatus {
+        reachable: consecutive_failures == 0,
+        last_success_at: Some(health.last_success_at.load(Ordering::Relaxed)).filter(|at| *at > 0),
+        consecutive_failures,
+        retry_in_secs: Some(health.retry_at.load(Ordering::Relaxed))
+            .filter(|at| *at > 0)
+            .map(|at| at.saturating_sub(unix_time())),
+    };
+
+    let status = SessionStatus {
+        downloaded: state.stats.downloaded.load(Ordering::Relaxed),
+        failed: state.stats.failed.load(Ordering::Relaxed),
+        bytes: state.stats.bytes.load(Ordering::Relaxed),
+        bytes_per_sec: state.bytes_per_sec(),
+        queue_length: state.queue.lock().await.len(),
+        downloading: state
+            .downloading
+            .lock()
+            .await
+            .as_ref()
+            .map(|item| item.id.clone()),
+        api,
+    };
+
+    Ok(warp::reply::json(&status))
+}
+
+async fn item(id: String, pool: DbPool) -> Result<warp::reply::Response, Infallible> {
+    let result =
+        tokio::task::spawn_blocking(move || with_connection(&pool, |conn| media_row(conn, &id)))
+            .await;
+
+    Ok(match result {
+        Ok(Ok(Some(row))) => warp::reply::json(&row).into_response(),
+        Ok(Ok(None)) => {
+            warp::reply::with_status("no such item", StatusCode::NOT_FOUND).into_response()
+        }
+        Ok(Err(e)) => {
+            error!("failed to load item for the status server: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(e) => {
+            error!("failed to load item for the status server: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    })
+}
+
+/// serve the status endpoints on `address` until the client shuts down
+pub async fn serve(address: SocketAddr, state: Arc<ScanState>, pool: DbPool) {
+    // inspect the items waiting to be downloaded
+    let queue = warp::get()
+        .and(warp::path("queue"))
+        .and(warp::path::end())
+        .and(with_state(state.clone()))
+        .and_then(queue);
+
+    // a summary of this session, including the current download speed
+    let status = warp::get()
+        .and(warp::path("status"))
+        .and(warp::path::end())
+        .and(with_state(state.clone()))
+        .and_then(status);
+
+    // the stored metadata of a single item
+    let item = warp::get()
+        .and(warp::path("item"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(with(pool))
+        .and_then(item);
+
+    let shutdown = state.shutdown.clone();
+    match warp::serve(queue.or(status).or(item))
+        .try_bind_with_graceful_shutdown(address, async move { shutdown.cancelled().await })
+    {
+        Ok((address, server)) => {
+            info!("status server listening on {}", address);
+            server.await
+        }
+        Err(e) => error!("unable to start status server on {}: {}", address, e),
+    }
+}
@@ -0,0 +1,74 @@
+//! Fixtures shared by the client's tests: in-memory databases, media items and configs loaded the
+//! same way a real run loads them.
+
+use std::{collections::BTreeMap, path::Path};
+
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    Connection, ExpressionMethods, RunQueryDsl,
+};
+use shared_libs::json_templates::MediaItem;
+
+use crate::{
+    config::Config,
+    database::{self, DbConnection, DbPool},
+};
+
+/// an in-memory database with every migration applied
+pub fn connection() -> DbConnection {
+    let mut connection = DbConnection::establish(":memory:").unwrap();
+    database::run_migrations(&mut connection).unwrap();
+    connection
+}
+
+/// a pool over a single in-memory database with every migration applied
+pub fn pool() -> DbPool {
+    let pool = Pool::builder()
+        .max_size(1)
+        .build(ConnectionManager::new(":memory:"))
+        .unwrap();
+    database::run_migrations(&mut *pool.get().unwrap()).unwrap();
+    pool
+}
+
+/// an image that has been attempted `download_attempts` times
+pub fn item(id: &str, download_attempts: u32) -> MediaItem {
+    MediaItem {
+        id: id.to_string(),
+        description: None,
+        productUrl: format!("https://photos.google.com/lr/photo/{}", id),
+        baseUrl: format!("https://lh3.googleusercontent.com/lr/{}", id),
+        mimeType: Some(String::from("image/jpeg")),
+        mediaMetadata: None,
+        contributorInfo: None,
+        filename: format!("{}.jpg", id),
+        download_attempts,
+        download_success: false,
+        last_error: None,
+    }
+}
+
+/// save `settings` to the config table and load the primary account's config from it. Files are
+/// stored and downloaded under `store`, and an unreachable api is used unless `settings` gives one
+pub fn config(connection: &mut DbConnection, store: &Path, settings: &[(&str, &str)]) -> Config {
+    use crate::schema::config::dsl::*;
+
+    let store = store.to_str().unwrap();
+    let mut rows = BTreeMap::from([
+        ("store_path", store),
+        ("temp_path", store),
+        ("webserver_address", "http://127.0.0.1:1"),
+        ("preshared_key", "psk"),
+    ]);
+    rows.extend(settings.iter().copied());
+
+    let rows: Vec<_> = rows
+        .into_iter()
+        .map(|(k, v)| (key.eq(k), value.eq(v)))
+        .collect();
+    diesel::insert_into(config)
+        .values(&rows)
+        .execute(connection)
+        .unwrap();
+    database::load_config(connection, None).unwrap()
+}
@@ -0,0 +1,76 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// The window that throughput is averaged over
+const WINDOW: Duration = Duration::from_secs(30);
+
+/// A moving average of the bytes downloaded per second, bytes are counted into one second buckets
+/// so recording stays cheap when called for every chunk read
+#[derive(Debug)]
+pub struct Throughput {
+    start: Instant,
+    /// (seconds since `start`, bytes received in that second), oldest first
+    buckets: Mutex<VecDeque<(u64, u64)>>,
+    /// One more than the second since `start` that bytes were last received in, 0 if none have
+    /// been, as that may be longer ago than the window
+    last: AtomicU64,
+}
+
+impl Default for Throughput {
+    fn default() -> Self {
+        Throughput {
+            start: Instant::now(),
+            buckets: Mutex::new(VecDeque::with_capacity(WINDOW.as_secs() as usize + 1)),
+            last: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Throughput {
+    /// drop buckets that have fallen out of the window
+    fn expire(buckets: &mut VecDeque<(u64, u64)>, now: u64) {
+        while let Some((second, _)) = buckets.front() {
+            if now - second < WINDOW.as_secs() {
+                break;
+            }
+            buckets.pop_front();
+        }
+    }
+
+    pub fn record(&self, bytes: u64) {
+        let now = self.start.elapsed().as_secs();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        match buckets.back_mut() {
+            Some((second, total)) if *second == now => *total += bytes,
+            _ => buckets.push_back((now, bytes)),
+        }
+        Throughput::expire(&mut buckets, now);
+        self.last.store(now + 1, Ordering::Relaxed);
+    }
+
+    /// whether any bytes have been received within the last `duration`
+    pub fn received_within(&self, duration: Duration) -> bool {
+        match self.last.load(Ordering::Relaxed) {
+            0 => false,
+            last => self.start.elapsed().as_secs() + 1 - last <= duration.as_secs(),
+        }
+    }
+
+    /// the average bytes per second received over the window
+    pub fn bytes_per_sec(&self) -> u64 {
+        let now = self.start.elapsed().as_secs();
+        let mut buckets = self.buckets.lock().unwrap();
+        Throughput::expire(&mut buckets, now);
+
+        // until the client has been running for a whole window, average over the time so far
+        let window = WINDOW.as_secs().min(now + 1);
+        buckets.iter().map(|(_, bytes)| bytes).sum::<u64>() / window
+    }
+}
@@ -13,6 +13,132 @@ pub struct QueryData {
 pub struct RequestParameters {
     pub reload: bool,
     pub max_count: u8,
+    /// Fetch the page at this google page token rather than where the user's scan left off, the
+    /// scan carries on from it
+    #[serde(default)]
+    pub page_token: Option<String>,
+    /// Include media the user has archived. Changing this restarts the user's scan, as the page
+    /// tokens of the two kinds of scan can't be mixed
+    #[serde(default)]
+    pub include_archived: bool,
+}
+
+/// Set by `/download` to `true` when the page is the last in the library, and `false` otherwise
+pub const LAST_PAGE_HEADER: &str = "x-last-page";
+
+/// Set by `/auth_url` to the number of seconds the returned url can be used to log in for
+pub const AUTH_EXPIRES_IN_HEADER: &str = "x-auth-expires-in";
+
+/// Query parameters for `/auth_url`
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct AuthUrlParameters {
+    /// Request the api's additional scopes on top of those already granted, rather than only the
+    /// initial scopes
+    #[serde(default)]
+    pub incremental: bool,
+}
+
+/// A client registered against the same google account, returned by `/clients`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LinkedClient {
+    pub id: String,
+    /// Whether this is the client that made the request
+    pub current: bool,
+    pub initial_scan_complete: bool,
+}
+
+/// What the api supports, returned by `/capabilities`, so clients can adapt to older servers
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Capabilities {
+    /// The version of the api
+    pub version: String,
+    /// The optional features and endpoints this api supports
+    pub features: Vec<String>,
+    /// The google scopes requested when a client first logs in
+    pub scopes: Vec<String>,
+    /// The google scopes a client can request on top of those, through an incremental login
+    pub additional_scopes: Vec<String>,
+    /// The most items returned in a page by `/download`
+    pub max_page_size: u8,
+}
+
+/// Query parameters for `/stored`
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct StoredItemsParameters {
+    /// Only list items with ids after this one, the `next` of the previous page
+    #[serde(default)]
+    pub after: Option<String>,
+    /// The most items to list, capped by the api
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+/// Whether the api managed to store an item
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StoredStatus {
+    Stored,
+    /// The last attempt failed, it is tried again on the next pass over the library
+    Failed,
+}
+
+/// An item the api has stored, or tried to, for a user in storage mode
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredItem {
+    pub id: String,
+    pub status: StoredStatus,
+    /// The size of the stored file
+    pub bytes: Option<u64>,
+    /// When the item was last stored or attempted, in seconds since the unix epoch
+    pub updated_at: u64,
+    pub last_error: Option<String>,
+}
+
+/// A page of the items the api has stored for a user, ordered by id, returned by `/stored`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoredItemsPage {
+    pub items: Vec<StoredItem>,
+    /// Pass as `after` to get the next page, None on the last page
+    pub next: Option<String>,
+}
+
+/// Query parameters for `/library`
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LibraryParameters {
+    /// The `next` of the previous page, None to start from the beginning of the library
+    #[serde(default)]
+    pub page_token: Option<String>,
+    /// The most items to list, capped at the api's max page size
+    #[serde(default)]
+    pub max_count: Option<u8>,
+    /// Include media the user has archived, `page_token` must come from a listing that did the same
+    #[serde(default)]
+    pub include_archived: bool,
+}
+
+/// A page of the ids in a user's google library, returned by `/library`. Listing the library
+/// doesn't move the user's scan through it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LibraryPage {
+    pub ids: Vec<String>,
+    /// Pass as `page_token` to get the next page, None on the last page
+    pub next: Option<String>,
+}
+
+/// The state of the google login the api holds for a user, returned by `/token_status`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TokenStatus {
+    /// Whether a google account has been linked
+    pub linked: bool,
+    /// Seconds until the current access token expires, 0 if it already has. The api refreshes
+    /// expired tokens when they are next used, so this only matters if that refresh fails
+    pub expires_in_secs: Option<u64>,
+    /// Whether google rejected the stored login, so the client must log in again
+    #[serde(default)]
+    pub needs_reauth: bool,
+    /// The google scopes granted to the current login
+    #[serde(default)]
+    pub scopes: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -22,6 +148,19 @@ pub struct MediaMetadata {
     pub height: String,
     pub photo: Option<Photo>,
     pub video: Option<Video>,
+    pub location: Option<Location>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Location {
+    pub locationName: Option<String>,
+    pub latlng: Option<LatLng>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LatLng {
+    pub latitude: f64,
+    pub longitude: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -83,6 +222,9 @@ pub struct MediaItem {
 
     #[serde(default)]
     pub download_success: bool,
+
+    #[serde(default)]
+    pub last_error: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -92,12 +234,25 @@ pub struct GetMediaItems {
     pub nextPageToken: Option<String>,
 }
 
-#[derive(Deserialize)]
+/// The standard error body returned by Google apis, e.g. `{ "error": { "code": 403, ... } }`
+#[derive(Deserialize, Debug)]
+pub struct GoogleErrorResponse {
+    pub error: GoogleError,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GoogleError {
+    pub code: Option<u16>,
+    pub message: Option<String>,
+    pub status: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
 pub struct GoogleProfile {
     /// Google ID for user
     pub sub: String,
-    /// Url to profile picture of user
-    pub picture: String,
+    /// Url to profile picture of user, only present with the profile scope
+    pub picture: Option<String>,
     /// Email address of user
     pub email: String,
     /// Whether the email of this user has been verified
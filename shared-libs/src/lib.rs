@@ -1 +1,2 @@
 pub mod json_templates;
+pub mod signing;